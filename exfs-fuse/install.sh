@@ -0,0 +1,14 @@
+#!/usr/bin/env bash
+# 构建并安装 exfs 工具
+# cargo 的二进制名不能包含 `.`,安装时改为 mkfs -t exfs、fsck -t exfs 查找的 mkfs.exfs、fsck.exfs
+# 用法: PREFIX=/usr/local DESTDIR= ./install.sh
+set -e
+PREFIX="${PREFIX:-/usr/local}"
+cd "$(dirname "$0")"
+cargo build --release
+install -d "$DESTDIR$PREFIX/bin" "$DESTDIR$PREFIX/sbin"
+install -m 755 target/release/exfs-fuse target/release/exfs-debug "$DESTDIR$PREFIX/bin"
+install -m 755 target/release/dump-exfs "$DESTDIR$PREFIX/bin/dump.exfs"
+for tool in mkfs fsck; do
+    install -m 755 "target/release/$tool-exfs" "$DESTDIR$PREFIX/sbin/$tool.exfs"
+done
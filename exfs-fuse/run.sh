@@ -5,4 +5,4 @@ umount "$FS"
 rm -rf "$FS"
 mkdir "$FS"
 cd "$WORK_DIR" || exit
-RUST_BACKTRACE=1 RUST_LOG=debug ~/.cargo/bin/cargo run --package exfs-fuse --bin exfs-fuse -- fs
\ No newline at end of file
+RUST_BACKTRACE=1 RUST_LOG=debug ~/.cargo/bin/cargo run --package exfs-fuse --bin exfs-fuse -- fs --mkfs
\ No newline at end of file
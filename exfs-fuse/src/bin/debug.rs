@@ -0,0 +1,319 @@
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io::{BufRead, IsTerminal, stdin, stdout, Write};
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+
+use clap::Parser;
+use exfs::block_device::file_device::FileDevice;
+use exfs::config::BLOCK_SIZE;
+use exfs::layout::data_block::{dir_block_ok, DIR_ENTRIES_PER_BLOCK, DIR_ENTRY_SIZE, DirEntry};
+use exfs::layout::index_node::{INDEX_NODE_SIZE, IndexNode};
+use exfs::layout::super_block::SuperBlock;
+use exfs::manager::block_cache_manager::BlockCacheDevice;
+
+const HELP: &str = "\
+stats                 打印超级块与各区域
+inode <ino>           打印 inode
+ls <ino>              列出目录的全部目录项,包括无效项
+index <ino>           逐级打印文件的索引树
+block <blk>           以十六进制打印物理块
+data <id>             以十六进制打印数据块(逻辑 id)
+testb <id>            数据块是否在位图中标记
+testi <ino>           inode 是否在位图中标记
+help                  打印本帮助
+quit                  退出";
+
+/// 以只读方式打开 exfs 镜像并检查其内部结构
+#[derive(Parser, Debug)]
+#[command(name = "exfs-debug", version)]
+struct Args {
+    /// 镜像文件路径
+    image: PathBuf,
+
+    /// 执行一条命令后退出,如 -R "inode 1"
+    #[arg(short = 'R', long, value_name = "COMMAND")]
+    request: Option<String>,
+}
+
+fn main() {
+    let args = Args::parse();
+    let mut debugger = Debugger::open(&args.image).unwrap_or_else(|e| {
+        eprintln!("exfs-debug: {}", e);
+        exit(1)
+    });
+    if let Some(request) = &args.request {
+        if let Err(e) = debugger.run(request) {
+            eprintln!("{}", e);
+            exit(1)
+        }
+        return;
+    }
+    let interactive = stdin().is_terminal();
+    loop {
+        if interactive {
+            print!("exfs-debug: ");
+            stdout().flush().unwrap();
+        }
+        let mut line = String::new();
+        if stdin().lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        match debugger.run(&line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+struct Debugger {
+    fs: BlockCacheDevice,
+    sb: SuperBlock,
+}
+
+impl Debugger {
+    fn open(image: &PathBuf) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(image)
+            .map_err(|e| format!("can not open {}: {}", image.display(), e))?;
+        let fs = BlockCacheDevice::open_read_only(Arc::new(FileDevice::new(file)))
+            .map_err(|e| e.to_string())?;
+        let sb = fs.super_block();
+        Ok(Self { fs, sb })
+    }
+
+    /// 执行一条命令,返回 false 表示退出
+    fn run(&mut self, line: &str) -> Result<bool, String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else { return Ok(true) };
+        let arg = words.next().map(parse_num).transpose()?;
+        let need = || arg.ok_or(format!("{}: missing argument", command));
+        match command {
+            "stats" | "sb" => self.stats(),
+            "inode" | "stat" => self.inode(need()?)?,
+            "ls" => self.ls(need()?)?,
+            "index" => self.index(need()?)?,
+            "block" => self.block(need()?)?,
+            "data" => self.block(self.check_data(need()?).map(|id| self.sb.data_block(id))?)?,
+            "testb" => {
+                let id = self.check_data(need()?)?;
+                println!("data block {} is {}", id, marked(self.fs.used(id, false).map_err(errno)?));
+            }
+            "testi" => {
+                let ino = self.check_inode(need()?)?;
+                println!("inode {} is {}", ino, marked(self.fs.used(ino - 1, true).map_err(errno)?));
+            }
+            "help" | "?" => println!("{}", HELP),
+            "quit" | "q" | "exit" => return Ok(false),
+            _ => return Err(format!("{}: unknown command, try help", command)),
+        }
+        Ok(true)
+    }
+
+    fn stats(&self) {
+        let sb = &self.sb;
+        println!("magic:               {}", if sb.is_valid() { "ok" } else { "bad" });
+        println!("label:               {}", sb.label());
+        println!("uuid:                {}", sb.uuid());
+        println!("state:               {}", sb.state());
+        println!("mount count:         {}", sb.mount_count);
+        println!("last mount time:     {}", sb.mount_time);
+        println!("last write time:     {}", sb.write_time);
+        println!("last fsck time:      {}", sb.fsck_time);
+        println!("version:             {}", sb.version);
+        println!("features:            compat {:#x}, ro_compat {:#x}, incompat {:#x}",
+                 sb.feature_compat, sb.feature_ro_compat, sb.feature_incompat);
+        println!("checksum:            {}", checksum(sb.metadata_csum(), sb.checksum_ok()));
+        println!("backups:             {:?}", sb.backups());
+        println!("blocks:              {}", sb.blocks());
+        println!("inodes:              {}", sb.inode_size());
+        println!("inode bitmap blocks: {}", sb.inode_bitmap_blocks);
+        println!("data bitmap blocks:  {}", sb.bitmap_blocks);
+        println!("inode blocks:        {}", sb.inode_blocks);
+        println!("data blocks:         {}", sb.data_blocks);
+        println!("reserved blocks:     {}", sb.reserved_blocks);
+        if sb.grouped() {
+            println!("block groups:        {} x {} blocks, {} inodes per group", sb.groups(), sb.blocks_per_group, sb.inodes_per_group);
+        }
+        for (name, range) in sb.regions() {
+            println!("{:<16} {:>10} - {:<10} ({} blocks)", name, range.start, range.end, range.len());
+        }
+    }
+
+    fn inode(&mut self, ino: usize) -> Result<(), String> {
+        let ino = self.check_inode(ino)?;
+        let (blk, offset) = self.sb.inode_block(ino);
+        let inode = self.fs.inode_unchecked(ino).map_err(errno)?;
+        println!("inode {} (block {}, offset {})", ino, blk, offset);
+        println!("type:        {:?}", inode.file_type());
+        println!("mode:        {:o}", inode.mode);
+        println!("links:       {}", inode.link_count);
+        println!("uid / gid:   {} / {}", inode.uid, inode.gid);
+        println!("size:        {}", inode.size);
+        println!("created:     {}", inode.created);
+        println!("modified:    {}", inode.modified);
+        println!("index_level: {}", inode.index_level);
+        println!("index_node:  {:?}", inode.index_node.range());
+        println!("checksum:    {}", checksum(self.sb.metadata_csum(), inode.checksum_ok(ino)));
+        Ok(())
+    }
+
+    fn ls(&mut self, ino: usize) -> Result<(), String> {
+        let ino = self.check_inode(ino)?;
+        let inode = self.fs.inode_unchecked(ino).map_err(errno)?;
+        if !inode.is_dir() {
+            return Err(format!("inode {} is not a directory", ino));
+        }
+        let mut blocks = Vec::new();
+        self.walk(inode.index_node, inode.index_level, 0, &mut blocks, false)?;
+        for id in blocks {
+            let blk = self.sb.data_block(id);
+            println!("data block {} (block {}):", id, blk);
+            let mut slots = [DirEntry { name: [0u8; 56].into(), inode: 0 }; BLOCK_SIZE / DIR_ENTRY_SIZE];
+            self.fs.data(id, 0, |dirs: &[DirEntry; BLOCK_SIZE / DIR_ENTRY_SIZE]| slots = *dirs)
+                .map_err(errno)?;
+            let mut empty = 0;
+            for (slot, entry) in slots.iter().enumerate() {
+                let no_name = entry.name.iter().all(|&b| b == 0);
+                if slot == DIR_ENTRIES_PER_BLOCK && no_name && entry.inode != 0 {
+                    println!("  [{:>2}] checksum {:#x}, {}", slot, entry.inode,
+                             checksum(self.sb.metadata_csum(), dir_block_ok(&slots)));
+                    continue;
+                }
+                if no_name && entry.inode == 0 {
+                    empty += 1;
+                    continue;
+                }
+                println!(
+                    "  [{:>2}] {:>8} {}{}",
+                    slot,
+                    entry.inode,
+                    OsString::from(entry.name).to_string_lossy(),
+                    if no_name || entry.inode == 0 { "  (invalid)" } else { "" }
+                );
+            }
+            println!("  {} empty slots", empty);
+        }
+        Ok(())
+    }
+
+    fn index(&mut self, ino: usize) -> Result<(), String> {
+        let ino = self.check_inode(ino)?;
+        let inode = self.fs.inode_unchecked(ino).map_err(errno)?;
+        println!("inode {}: index_level {}", ino, inode.index_level);
+        let mut blocks = Vec::new();
+        self.walk(inode.index_node, inode.index_level, 1, &mut blocks, true)?;
+        println!("{} data blocks", blocks.len());
+        Ok(())
+    }
+
+    /// 遍历索引树收集数据块,print 时逐级打印
+    /// 越界的节点不会继续读取
+    fn walk(&mut self, node: IndexNode, level: u8, depth: usize, blocks: &mut Vec<usize>, print: bool) -> Result<(), String> {
+        if level == 0 || !node.is_valid() {
+            return Ok(());
+        }
+        let range = node.range();
+        let bad = range.end > self.sb.data_blocks;
+        if print {
+            println!("{:indent$}L{} {:?}{}", "", level, range, if bad { "  (out of range)" } else { "" }, indent = depth * 2);
+        }
+        if bad {
+            return Ok(());
+        }
+        for id in range {
+            if level == 1 {
+                blocks.push(id);
+                continue;
+            }
+            if print {
+                println!("{:indent$}index block {} (block {})", "", id, self.sb.data_block(id), indent = depth * 2 + 2);
+            }
+            let mut nodes = [IndexNode::default(); BLOCK_SIZE / INDEX_NODE_SIZE];
+            self.fs.data(id, 0, |data: &[IndexNode; BLOCK_SIZE / INDEX_NODE_SIZE]| nodes = *data)
+                .map_err(errno)?;
+            for v in nodes {
+                self.walk(v, level - 1, depth + 2, blocks, print)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, blk: usize) -> Result<(), String> {
+        if blk >= self.sb.blocks() {
+            return Err(format!("block {} out of range 0..{}", blk, self.sb.blocks()));
+        }
+        let mut data = [0u8; BLOCK_SIZE];
+        self.fs.block_cache(blk).map_err(errno)?
+            .lock()
+            .unwrap()
+            .read(0, |v: &[u8; BLOCK_SIZE]| data = *v);
+        hexdump(&data);
+        Ok(())
+    }
+
+    fn check_inode(&self, ino: usize) -> Result<usize, String> {
+        if ino == 0 || ino > self.sb.inode_size() {
+            return Err(format!("inode {} out of range 1..={}", ino, self.sb.inode_size()));
+        }
+        Ok(ino)
+    }
+
+    fn check_data(&self, id: usize) -> Result<usize, String> {
+        if id >= self.sb.data_blocks {
+            return Err(format!("data block {} out of range 0..{}", id, self.sb.data_blocks));
+        }
+        Ok(id)
+    }
+}
+
+/// 与 hexdump -C 相同的格式,重复行以 * 省略
+fn hexdump(data: &[u8]) {
+    let mut last: Option<&[u8]> = None;
+    let mut skipped = false;
+    for (i, line) in data.chunks(16).enumerate() {
+        if last == Some(line) {
+            if !skipped {
+                println!("*");
+                skipped = true;
+            }
+            continue;
+        }
+        last = Some(line);
+        skipped = false;
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = line.iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        println!("{:08x}  {:<23}  {:<23}  |{}|", i * 16, hex[..8].join(" "), hex[8..].join(" "), text);
+    }
+    println!("{:08x}", data.len());
+}
+
+fn marked(used: bool) -> &'static str {
+    if used { "marked in use" } else { "not marked" }
+}
+
+fn errno(e: i32) -> String {
+    format!("errno {}", e)
+}
+
+fn checksum(enabled: bool, ok: bool) -> &'static str {
+    match (enabled, ok) {
+        (false, _) => "disabled",
+        (true, true) => "ok",
+        (true, false) => "bad",
+    }
+}
+
+/// 支持十进制与 0x 开头的十六进制
+fn parse_num(s: &str) -> Result<usize, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+        .map_err(|_| format!("invalid number: {}", s))
+}
@@ -0,0 +1,43 @@
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+
+use clap::Parser;
+use exfs::block_device::file_device::FileDevice;
+use exfs::manager::block_cache_manager::BlockCacheDevice;
+
+/// 以 JSON 格式输出 exfs 镜像的布局与使用情况
+#[derive(Parser, Debug)]
+#[command(name = "dump.exfs", version)]
+struct Args {
+    /// 镜像文件路径
+    image: PathBuf,
+
+    /// 不输出每个文件的区间列表
+    #[arg(long)]
+    no_files: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = dump(&args) {
+        eprintln!("dump.exfs: {}", e);
+        exit(1)
+    }
+}
+
+fn dump(args: &Args) -> Result<(), String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(&args.image)
+        .map_err(|e| format!("can not open {}: {}", args.image.display(), e))?;
+    let mut fs = BlockCacheDevice::open_read_only(Arc::new(FileDevice::new(file)))
+        .map_err(|e| e.to_string())?;
+    let mut dump = fs.dump().map_err(|e| format!("dump failed, errno {}", e))?;
+    if args.no_files {
+        dump.files.clear();
+    }
+    println!("{}", dump.to_json());
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+
+use clap::Parser;
+use exfs::block_device::file_device::FileDevice;
+use exfs::manager::block_cache_manager::BlockCacheDevice;
+
+/// 退出码与 e2fsck 一致
+const EXIT_OK: i32 = 0;
+const EXIT_CORRECTED: i32 = 1;
+const EXIT_UNCORRECTED: i32 = 4;
+const EXIT_ERROR: i32 = 8;
+
+/// 检查 exfs 镜像的一致性
+#[derive(Parser, Debug)]
+#[command(name = "fsck.exfs", version)]
+struct Args {
+    /// 镜像文件路径
+    image: PathBuf,
+
+    /// 修复位图、link count 与孤儿文件,默认只检查不写入
+    #[arg(short, long)]
+    repair: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+    exit(fsck(&args).unwrap_or_else(|e| {
+        eprintln!("fsck.exfs: {}", e);
+        EXIT_ERROR
+    }))
+}
+
+fn fsck(args: &Args) -> Result<i32, String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(args.repair)
+        .open(&args.image)
+        .map_err(|e| format!("can not open {}: {}", args.image.display(), e))?;
+    let device = Arc::new(FileDevice::new(file));
+    // 只检查时日志只在内存中重放,不修改镜像
+    let fs = if args.repair {
+        BlockCacheDevice::open(device)
+    } else {
+        BlockCacheDevice::open_read_only(device)
+    };
+    let mut fs = fs.map_err(|e| e.to_string())?;
+    let report = fs.fsck(args.repair)
+        .map_err(|e| format!("check failed, errno {}", e))?;
+
+    for problem in report.problems.iter() {
+        let fixed = args.repair && !report.remaining.contains(problem);
+        println!("{}{}", problem, if fixed { " [fixed]" } else { "" });
+    }
+    if report.is_clean() {
+        println!("{}: clean", args.image.display());
+        Ok(EXIT_OK)
+    } else if report.remaining.is_empty() {
+        println!("{}: {} problems fixed", args.image.display(), report.problems.len());
+        Ok(EXIT_CORRECTED)
+    } else {
+        println!("{}: {} problems, {} left", args.image.display(), report.problems.len(), report.remaining.len());
+        Ok(EXIT_UNCORRECTED)
+    }
+}
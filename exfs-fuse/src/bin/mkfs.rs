@@ -0,0 +1,116 @@
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+
+use clap::Parser;
+use exfs::block_device::file_device::FileDevice;
+use exfs::config::BLOCK_SIZE;
+use exfs::layout::journal::JournalMode;
+use exfs::manager::block_cache_manager::BlockCacheDevice;
+use exfs::manager::mkfs::MkfsOptions;
+use exfs_fuse::parse_size;
+
+/// 在镜像文件上创建 exfs 文件系统
+#[derive(Parser, Debug)]
+#[command(name = "mkfs.exfs", version)]
+struct Args {
+    /// 镜像文件路径,不存在时自动创建
+    image: PathBuf,
+
+    /// 镜像大小(如 64M、1G),缺省时使用镜像当前大小
+    #[arg(short, long)]
+    size: Option<String>,
+
+    /// 每多少字节的空间分配一个 inode
+    #[arg(short = 'i', long, default_value_t = BLOCK_SIZE)]
+    bytes_per_inode: usize,
+
+    /// 卷标,最长 16 字节
+    #[arg(short = 'L', long, default_value = "")]
+    label: String,
+
+    /// 为 root 保留的数据块百分比
+    #[arg(short = 'm', long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=50))]
+    reserved_percent: u8,
+
+    /// 快速格式化,只初始化元数据,不清零数据块
+    #[arg(short, long)]
+    fast: bool,
+
+    /// 日志模式: none、metadata 或 data
+    #[arg(short = 'J', long, default_value_t = JournalMode::Metadata)]
+    journal: JournalMode,
+
+    /// 日志区域块数,至少 32,缺省时按镜像大小计算
+    #[arg(long, value_name = "BLOCKS")]
+    journal_blocks: Option<usize>,
+
+    /// 不为元数据记录校验和
+    #[arg(long)]
+    no_checksums: bool,
+
+    /// 按块组划分,每组的块数,最多 32768;每组有自己的位图与 inode 表
+    #[arg(short = 'g', long, value_name = "BLOCKS")]
+    blocks_per_group: Option<usize>,
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = mkfs(&args) {
+        eprintln!("mkfs.exfs: {}", e);
+        exit(1)
+    }
+}
+
+fn mkfs(args: &Args) -> Result<(), String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&args.image)
+        .map_err(|e| format!("can not open {}: {}", args.image.display(), e))?;
+    if let Some(size) = &args.size {
+        file.set_len(parse_size(size)?).map_err(|e| e.to_string())?;
+    }
+    let mut fs = BlockCacheDevice::new(Arc::new(FileDevice::new(file)))
+        .map_err(|e| e.to_string())?;
+    let options = MkfsOptions::new()
+        .bytes_per_inode(args.bytes_per_inode)
+        .label(&args.label)
+        .reserved_percent(args.reserved_percent)
+        .fast(args.fast)
+        .journal(args.journal)
+        .checksums(!args.no_checksums);
+    let options = match args.journal_blocks {
+        Some(blocks) => options.journal_blocks(blocks),
+        None => options,
+    };
+    let options = match args.blocks_per_group {
+        Some(blocks) => options.block_groups(blocks),
+        None => options,
+    };
+    let sb = fs.mkfs_with(&options)
+        .map_err(|e| format!("failed to make file system, errno {}", e))?;
+
+    println!("Label:           {}", sb.label());
+    println!("UUID:            {}", sb.uuid());
+    println!("Version:         {}", sb.version);
+    println!("Features:        compat {:#x}, ro_compat {:#x}, incompat {:#x}",
+             sb.feature_compat, sb.feature_ro_compat, sb.feature_incompat);
+    println!("Block size:      {}", BLOCK_SIZE);
+    println!("Blocks:          {} ({} MiB)", sb.blocks(), (sb.blocks() * BLOCK_SIZE) >> 20);
+    println!("Inodes:          {}", sb.inode_size());
+    println!("Data blocks:     {}", sb.data_blocks);
+    println!("Reserved blocks: {}", sb.reserved_blocks);
+    println!("Journal:         {} ({} blocks)", JournalMode::from(sb.journal_mode), sb.journal_blocks);
+    if sb.grouped() {
+        println!("Block groups:    {} x {} blocks, {} inodes per group", sb.groups(), sb.blocks_per_group, sb.inodes_per_group);
+    }
+    for (name, range) in sb.regions() {
+        println!("{:<16} {:>10} - {:<10} ({} blocks)", name, range.start, range.end, range.len());
+    }
+    println!("Super block backups: {:?}", sb.backups());
+    Ok(())
+}
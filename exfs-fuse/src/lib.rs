@@ -0,0 +1,23 @@
+// exfs-fuse 各个命令行工具共用的辅助函数
+
+/// 解析带单位的大小,如 4096、64K、16M、1G
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (num, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("invalid size unit: {}", unit)),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|v| v.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size: {}", size))
+}
+
@@ -1,50 +1,392 @@
-use std::env;
 use std::fs::OpenOptions;
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
+use clap::Parser;
+use exfs::block_device::block_device::BlockDevice;
+use exfs::block_device::checksum_device::ChecksumDevice;
 use exfs::block_device::file_device::FileDevice;
+use exfs::cache::options::CacheOptions;
+use exfs::cache::policy::ReplacePolicy;
+use exfs::manager::allocator::AllocPolicy;
+use exfs::cache::write_back::WritePolicy;
+use exfs::config::BLOCK_SIZE;
+use exfs::layout::journal::JournalMode;
+use exfs::layout::super_block::FsState;
 use exfs::manager::block_cache_manager::BlockCacheDevice;
+use exfs::manager::stats::StatsHandle;
+use exfs_fuse::parse_size;
 use fuser::MountOption;
+use signal_hook::consts::SIGUSR1;
+use signal_hook::iterator::Signals;
+
+const DEFAULT_IMAGE_SIZE: u64 = 1024 * 4096 * 2;
+
+/// 将 exfs 镜像通过 FUSE 挂载到指定目录
+#[derive(Parser, Debug)]
+#[command(name = "exfs-fuse", version)]
+struct Args {
+    /// 挂载点
+    mountpoint: PathBuf,
+
+    /// 镜像文件路径
+    #[arg(short, long, default_value = "fs.img")]
+    image: PathBuf,
+
+    /// 挂载前格式化镜像,可指定镜像大小(如 64M、1G),缺省时使用镜像当前大小
+    #[arg(long, value_name = "SIZE", num_args = 0..=1, default_missing_value = "")]
+    mkfs: Option<String>,
+
+    /// 只读挂载
+    #[arg(short, long, conflicts_with = "mkfs")]
+    read_only: bool,
+
+    /// 允许其他用户访问
+    #[arg(long, conflicts_with = "allow_root")]
+    allow_other: bool,
+
+    /// 允许 root 访问
+    #[arg(long)]
+    allow_root: bool,
+
+    /// 上次未正常卸载且没有日志时仍然挂载,默认要求先运行 fsck.exfs --repair
+    #[arg(long)]
+    force: bool,
+
+    /// 挂载后转入后台运行,默认在前台运行
+    #[arg(long)]
+    daemon: bool,
+
+    /// 文件系统名称,显示在 mount 输出中
+    #[arg(long, default_value = "exfs")]
+    fsname: String,
+
+    /// 额外的挂载选项,以逗号分隔,如 -o noatime,nosuid;覆盖默认选项,-o ro 等同 --read-only
+    #[arg(short = 'o', value_name = "OPTIONS", value_delimiter = ',')]
+    options: Vec<String>,
+
+    /// 本次挂载使用的日志模式(none、metadata 或 data),缺省时使用镜像中记录的模式
+    #[arg(short = 'J', long)]
+    journal: Option<JournalMode>,
+
+    /// 块缓存大小(如 64M),也可用 -o cache=64M 指定
+    #[arg(long, value_name = "SIZE")]
+    cache: Option<String>,
+
+    /// inode、位图与索引块单独使用的缓存大小,缺省时与数据块共用,也可用 -o metadata_cache=8M 指定
+    #[arg(long, value_name = "SIZE")]
+    metadata_cache: Option<String>,
+
+    /// 缓存替换策略: lru 或 2q,2q 下只读过一次的块(如顺序扫描)不会挤出反复访问的块
+    #[arg(long, default_value_t = ReplacePolicy::Lru)]
+    cache_policy: ReplacePolicy,
+
+    /// 写回策略: write-back 或 write-through,启用日志时每次操作后都会提交
+    #[arg(long, default_value_t = WritePolicy::WriteBack)]
+    write_policy: WritePolicy,
+
+    /// 空闲区间的选择策略: best-fit 或 next-fit,文件追加时总是优先接在最后一块之后
+    #[arg(long, default_value_t = AllocPolicy::BestFit)]
+    alloc_policy: AllocPolicy,
+
+    /// 后台写回脏块的间隔秒数,0 表示只在缓存淘汰、达到阈值或 fsync 时写回
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    flush_interval: u64,
+
+    /// 脏块占缓存的百分比超过该值时立即写回
+    #[arg(long, value_name = "PERCENT", default_value_t = 20, value_parser = clap::value_parser!(u8).range(0..=100))]
+    dirty_ratio: u8,
+
+    /// 顺序读预读窗口的上限(如 128K),0 表示关闭预读
+    #[arg(long, value_name = "SIZE")]
+    readahead: Option<String>,
+
+    /// 每隔多少秒将缓存与 I/O 统计打印到标准错误,也可随时发送 SIGUSR1 打印
+    #[arg(long, value_name = "SECONDS")]
+    stats_interval: Option<u64>,
+
+    /// 在该文件中记录每个块的校验和,读取时校验以发现数据损坏;文件不存在时按镜像当前内容创建
+    #[arg(long, value_name = "PATH")]
+    checksum_table: Option<PathBuf>,
+}
+
+impl Args {
+    /// -o 中的 ro/rw 与 allow_other/allow_root 覆盖对应的命令行选项
+    fn apply_options(&mut self) {
+        for option in self.options.iter() {
+            match option.as_str() {
+                "ro" => self.read_only = true,
+                "rw" => self.read_only = false,
+                "allow_other" => (self.allow_other, self.allow_root) = (true, false),
+                "allow_root" => (self.allow_other, self.allow_root) = (false, true),
+                _ => {}
+            }
+        }
+    }
+}
 
 fn main() {
-    println!("Hello, world!");
+    let mut args = Args::parse();
+    args.apply_options();
+    env_logger::init();
+    if args.read_only && args.mkfs.is_some() {
+        eprintln!("Can not use --mkfs with a read-only mount");
+        exit(1)
+    }
 
+    let mut fs = open_fs(&args).unwrap_or_else(|e| {
+        eprintln!("Can not open {}: {}", args.image.display(), e);
+        exit(1)
+    });
+    if let Some(mode) = args.journal {
+        if let Err(e) = fs.set_journal_mode(mode) {
+            eprintln!("Can not use journal mode {}: errno {}", mode, e);
+            exit(1)
+        }
+    }
+    fs.set_read_only(args.read_only);
+    fs.set_alloc_policy(args.alloc_policy);
+    let unknown = fs.super_block().unknown_ro_compat();
+    if unknown != 0 && !args.read_only {
+        eprintln!("{} has unsupported features {:#x}, it can only be mounted with --read-only", args.image.display(), unknown);
+        exit(1)
+    }
+    check_state(&fs, &args);
+    fs.print().unwrap();
+
+    let mountpoint = args.mountpoint.canonicalize().unwrap_or_else(|e| {
+        eprintln!("Invalid mount point {}: {}", args.mountpoint.display(), e);
+        exit(1)
+    });
+    println!("mount point: {:?}", mountpoint);
+    let options = mount_options(&args);
+    if args.daemon && unsafe { libc::daemon(0, 0) } != 0 {
+        eprintln!("Failed to daemonize: {}", std::io::Error::last_os_error());
+        exit(1)
+    }
+    // daemon 会 fork,线程需在其后创建
+    report_stats(fs.stats_handle(), args.stats_interval);
+    if let Err(e) = fuser::mount2(fs, &mountpoint, &options) {
+        eprintln!("Failed to mount: {}", e);
+        exit(1)
+    }
+}
+
+/// 上次没有正常卸载时,有日志则只警告(挂载时已重放),否则除非 --force 或只读挂载,拒绝挂载
+fn check_state(fs: &BlockCacheDevice, args: &Args) {
+    let sb = fs.super_block();
+    if sb.state() == FsState::Clean {
+        return;
+    }
+    let image = args.image.display();
+    if fs.journal_mode() != JournalMode::None || args.read_only || args.force {
+        eprintln!("Warning: {} was not cleanly unmounted, run fsck.exfs to check it", image);
+    } else {
+        eprintln!("{} was not cleanly unmounted, run fsck.exfs --repair {} first or mount with --force", image, image);
+        exit(1)
+    }
+}
+
+/// 收到 SIGUSR1 或每隔 interval 秒打印统计
+fn report_stats(stats: StatsHandle, interval: Option<u64>) {
+    match Signals::new([SIGUSR1]) {
+        Ok(mut signals) => {
+            let stats = stats.clone();
+            thread::spawn(move || {
+                for _ in signals.forever() {
+                    eprintln!("{}", stats.get());
+                }
+            });
+        }
+        Err(e) => eprintln!("Can not handle SIGUSR1: {}", e),
+    }
+    if let Some(interval) = interval.filter(|v| *v > 0) {
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(interval));
+            eprintln!("{}", stats.get());
+        });
+    }
+}
+
+/// 只有显式指定 --mkfs 时才格式化镜像
+fn open_fs(args: &Args) -> Result<BlockCacheDevice, String> {
     let file = OpenOptions::new()
         .read(true)
-        .write(true)
-        // .create(true)
-        // .truncate(true)
-        .open("fs.img")
-        .unwrap();
-    file.set_len(1024 * 4096 * 2).unwrap();
-    let mut fs = BlockCacheDevice::new(Arc::new(FileDevice { file: Arc::new(Mutex::new(file)) }));
-    fs.mkfs(1024);
-    fs.print();
-    env_logger::init();
-    let mountpoint = env::args_os().nth(1).unwrap();
-    println!("mount point: {:?}", mountpoint);
-    // let options = ["-o", "fsname=exfs"]
-    //     .iter()
-    //     .map(|o| o.as_ref())
-    //     .collect::<Vec<&OsStr>>();
-    let mut options = vec![MountOption::RW, MountOption::FSName("hello".to_string())];
-    options.push(MountOption::AutoUnmount);
-    // options.push(MountOption::AllowRoot);
-    fuser::mount2(fs, &mountpoint, &options).unwrap();
+        .write(!args.read_only)
+        .create(args.mkfs.is_some())
+        .open(&args.image)
+        .map_err(|e| e.to_string())?;
+    let device = Arc::new(FileDevice::new(file));
+    if let Some(size) = &args.mkfs {
+        let len = if size.is_empty() {
+            // 空镜像使用默认大小
+            match device.file.metadata().map_err(|e| e.to_string())?.len() {
+                0 => DEFAULT_IMAGE_SIZE,
+                len => len,
+            }
+        } else {
+            parse_size(size)?
+        };
+        device.file.set_len(len).map_err(|e| e.to_string())?;
+    }
+    let device = checksum_device(device, args)?;
+    let cache = cache_options(args)?;
+    match &args.mkfs {
+        None if args.read_only => BlockCacheDevice::open_read_only_with_cache(device, cache)
+            .map_err(|e| e.to_string()),
+        None => BlockCacheDevice::open_with_cache(device, cache)
+            .map_err(|e| format!("{}, run with --mkfs to format it", e)),
+        Some(_) => {
+            let mut fs = BlockCacheDevice::with_cache(device, cache).map_err(|e| e.to_string())?;
+            fs.mkfs(None).map_err(|e| format!("failed to make file system, errno {}", e))?;
+            Ok(fs)
+        }
+    }
+}
+
+/// 指定 --checksum-table 时包装为 ChecksumDevice,表不足时扩展
+/// 新建的表按镜像当前内容计算,格式化时会写入每个块,不需要计算
+fn checksum_device(device: Arc<FileDevice>, args: &Args) -> Result<Arc<dyn BlockDevice>, String> {
+    let Some(path) = &args.checksum_table else { return Ok(device) };
+    let open = |e: std::io::Error| format!("can not open checksum table {}: {}", path.display(), e);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!args.read_only)
+        .create(!args.read_only)
+        .truncate(false)
+        .open(path)
+        .map_err(open)?;
+    let len = file.metadata().map_err(open)?.len();
+    let need = (ChecksumDevice::table_blocks(device.blocks().map_err(|e| e.to_string())?) * BLOCK_SIZE) as u64;
+    if len < need && !args.read_only {
+        file.set_len(need).map_err(open)?;
+    }
+    let device = ChecksumDevice::new(device, Arc::new(FileDevice::new(file))).map_err(open)?;
+    if len == 0 && args.mkfs.is_none() {
+        device.rebuild().map_err(|e| format!("can not build checksum table: {}", e))?;
+    }
+    Ok(Arc::new(device))
+}
+
+/// -o 中的缓存选项优先于 --cache 与 --metadata-cache
+fn cache_options(args: &Args) -> Result<CacheOptions, String> {
+    let mut cache = args.cache.as_deref();
+    let mut metadata_cache = args.metadata_cache.as_deref();
+    for option in args.options.iter() {
+        match option.split_once('=') {
+            Some(("cache", v)) => cache = Some(v),
+            Some(("metadata_cache", v)) => metadata_cache = Some(v),
+            _ => {}
+        }
+    }
+    let mut options = CacheOptions::new()
+        .policy(args.cache_policy)
+        .write_policy(args.write_policy)
+        .flush_interval(Duration::from_secs(args.flush_interval))
+        .dirty_ratio(args.dirty_ratio);
+    if let Some(size) = cache {
+        options = options.size(parse_size(size)?);
+    }
+    if let Some(size) = metadata_cache {
+        options = options.metadata_size(parse_size(size)?);
+    }
+    if let Some(size) = &args.readahead {
+        options = options.readahead_blocks(parse_size(size)? as usize / BLOCK_SIZE);
+    }
+    Ok(options)
+}
+
+fn mount_options(args: &Args) -> Vec<MountOption> {
+    let mut options = vec![
+        if args.read_only { MountOption::RO } else { MountOption::RW },
+        MountOption::FSName(args.fsname.clone()),
+        MountOption::Subtype("exfs".to_string()),
+        MountOption::AutoUnmount,
+    ];
+    if args.allow_other {
+        options.push(MountOption::AllowOther);
+    }
+    if args.allow_root {
+        options.push(MountOption::AllowRoot);
+    }
+    for option in args.options.iter().filter(|o| !o.is_empty() && !is_cache_option(o)) {
+        let option = mount_option(option);
+        // fuser 拒绝互相冲突的选项,-o 中后出现的覆盖默认值与先出现的
+        options.retain(|o| !overrides(&option, o));
+        options.push(option);
+    }
+    options
+}
+
+/// option 与 old 同类(如 fsname)或互相冲突(如 ro 与 rw)
+fn overrides(option: &MountOption, old: &MountOption) -> bool {
+    use MountOption::*;
+    match (option, old) {
+        (CUSTOM(a), CUSTOM(b)) => a == b,
+        (RO, RW) | (RW, RO) | (AllowOther, AllowRoot) | (AllowRoot, AllowOther) | (Dev, NoDev) | (NoDev, Dev)
+        | (Suid, NoSuid) | (NoSuid, Suid) | (Exec, NoExec) | (NoExec, Exec) | (Atime, NoAtime)
+        | (NoAtime, Atime) | (Sync, Async) | (Async, Sync) => true,
+        _ => std::mem::discriminant(option) == std::mem::discriminant(old),
+    }
+}
+
+fn is_cache_option(option: &str) -> bool {
+    matches!(option.split_once('='), Some(("cache" | "metadata_cache", _)))
+}
+
+/// 将 -o 中的字符串转换为 fuser 的挂载选项,无法识别的原样传给内核
+fn mount_option(option: &str) -> MountOption {
+    match option {
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "auto_unmount" => MountOption::AutoUnmount,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        _ => match option.split_once('=') {
+            Some(("fsname", v)) => MountOption::FSName(v.to_string()),
+            Some(("subtype", v)) => MountOption::Subtype(v.to_string()),
+            _ => MountOption::CUSTOM(option.to_string()),
+        },
+    }
 }
 
 
+#[test]
+fn override_mount_options() {
+    let mut args = Args::parse_from(["exfs-fuse", "--allow-other", "-o", "ro,sync,async,allow_root,fsname=img", "./mnt"]);
+    args.apply_options();
+    assert!(args.read_only && args.allow_root && !args.allow_other);
+    let options = mount_options(&args);
+    for option in [MountOption::RW, MountOption::Sync, MountOption::AllowOther, MountOption::FSName("exfs".into())] {
+        assert!(!options.contains(&option), "{:?}", options);
+    }
+    let count = |option: MountOption| options.iter().filter(|v| **v == option).count();
+    assert_eq!(count(MountOption::RO), 1);
+    assert_eq!(count(MountOption::AllowRoot), 1);
+    assert_eq!(count(MountOption::Async), 1);
+    assert_eq!(count(MountOption::FSName("img".into())), 1);
+}
+
 #[test]
 fn mkfs() {
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open("fs.img")
-        .unwrap();
-    file.set_len(1024 * 4096 * 2).unwrap();
-    let mut fs = BlockCacheDevice::new(Arc::new(FileDevice { file: Arc::new(Mutex::new(file)) }));
-    fs.mkfs(1024);
-    fs.print();
-}
\ No newline at end of file
+    use exfs::block_device::ram_device::RamDevice;
+
+    let mut fs = BlockCacheDevice::new(Arc::new(RamDevice::new(1024))).unwrap();
+    fs.mkfs(None).unwrap();
+    fs.print().unwrap();
+}
@@ -1,7 +1,31 @@
+use std::io;
 
-pub trait BlockDevice {
+use crate::config::BLOCK_SIZE;
+
+/// 需要可在后台写回线程中使用
+pub trait BlockDevice: Send + Sync {
     fn id(&self) -> usize;
-    fn read(&self, block: usize, buf: &mut [u8]);
-    fn write(&self, block: usize, data: &[u8]);
-    fn sync(&self);
+    fn read(&self, block: usize, buf: &mut [u8]) -> io::Result<()>;
+    fn write(&self, block: usize, data: &[u8]) -> io::Result<()>;
+    /// 从 block 开始连续读取 buf.len() / BLOCK_SIZE 块,默认逐块读取
+    fn read_blocks(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+        for (i, chunk) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+            self.read(block + i, chunk)?;
+        }
+        Ok(())
+    }
+    /// 从 block 开始连续写入 data.len() / BLOCK_SIZE 块,默认逐块写入
+    fn write_blocks(&self, block: usize, data: &[u8]) -> io::Result<()> {
+        for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+            self.write(block + i, chunk)?;
+        }
+        Ok(())
+    }
+    fn sync(&self) -> io::Result<()>;
+    /// 设备可容纳的完整块数量
+    fn blocks(&self) -> io::Result<usize>;
+    /// 读取时发现的数据校验错误数,不做校验的设备为 0
+    fn checksum_errors(&self) -> u64 {
+        0
+    }
 }
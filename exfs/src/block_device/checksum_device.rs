@@ -0,0 +1,295 @@
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+
+use log::error;
+
+use crate::block_device::block_device::BlockDevice;
+use crate::config::BLOCK_SIZE;
+use crate::utils::crc32c::crc32c_append;
+
+/// 每个表块保存的校验和数量
+pub const CRCS_PER_BLOCK: usize = BLOCK_SIZE / 4;
+
+/// 校验失败的块
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChecksumError {
+    pub block: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl Display for ChecksumError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "checksum mismatch in block {}: expected {:#x}, got {:#x}", self.block, self.expected, self.actual)
+    }
+}
+
+/// 数据块校验设备
+/// 在旁路表中为内层设备的每个块记录 CRC32C,写入时更新,读取时校验
+/// 表项为 0 表示没有记录,不做校验,因此可以直接包装已有的镜像
+/// 写入数据前先把表设备上的表项清零并落盘,新的表项在 sync 时数据落盘后写回
+/// 两者之间崩溃时该块只是没有记录,不会被当作损坏
+pub struct ChecksumDevice {
+    inner: Arc<dyn BlockDevice>,
+    table: Arc<dyn BlockDevice>,
+    // 表的内存副本,写入时持有锁直到表项更新,sync 时持有锁直到表写回
+    crcs: Mutex<Vec<u32>>,
+    // 表设备上已清零、新表项还没写回的块,锁在 crcs 之后获取
+    pending: Mutex<BTreeSet<usize>>,
+    errors: AtomicU64,
+    reporter: Mutex<Option<Sender<ChecksumError>>>,
+}
+
+impl ChecksumDevice {
+    /// 记录 blocks 个块需要的表块数
+    pub fn table_blocks(blocks: usize) -> usize {
+        blocks.div_ceil(CRCS_PER_BLOCK)
+    }
+
+    /// table 可以是单独的文件,也可以是其他设备上的一段区域,块数不能少于 table_blocks
+    pub fn new(inner: Arc<dyn BlockDevice>, table: Arc<dyn BlockDevice>) -> io::Result<Self> {
+        let blocks = inner.blocks()?;
+        let need = Self::table_blocks(blocks);
+        if table.blocks()? < need {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("checksum table has {} blocks, {} blocks needed", table.blocks()?, need),
+            ));
+        }
+        let mut buf = vec![0u8; need * BLOCK_SIZE];
+        table.read_blocks(0, &mut buf)?;
+        let mut crcs: Vec<u32> = buf.chunks(4).map(|v| u32::from_le_bytes(v.try_into().unwrap())).collect();
+        crcs.truncate(blocks);
+        Ok(Self {
+            inner,
+            table,
+            crcs: Mutex::new(crcs),
+            pending: Mutex::new(BTreeSet::new()),
+            errors: AtomicU64::new(0),
+            reporter: Mutex::new(None),
+        })
+    }
+
+    /// 校验失败时同时发送到 sender,接收端关闭后不再发送
+    pub fn report_to(self, sender: Sender<ChecksumError>) -> Self {
+        *self.reporter.lock().unwrap() = Some(sender);
+        self
+    }
+
+    /// 按内层设备当前的内容重新计算整张表
+    pub fn rebuild(&self) -> io::Result<()> {
+        let blocks = self.inner.blocks()?;
+        let mut buf = vec![0u8; CRCS_PER_BLOCK * BLOCK_SIZE];
+        for start in (0..blocks).step_by(CRCS_PER_BLOCK) {
+            let len = CRCS_PER_BLOCK.min(blocks - start) * BLOCK_SIZE;
+            self.inner.read_blocks(start, &mut buf[..len])?;
+            Self::record(&mut self.crcs.lock().unwrap(), start, &buf[..len]);
+        }
+        let crcs = self.crcs.lock().unwrap();
+        let mut pending = self.pending.lock().unwrap();
+        pending.clear();
+        if blocks > 0 {
+            self.write_table(&crcs, &pending, 0, (blocks - 1) / CRCS_PER_BLOCK)?;
+        }
+        self.table.sync()
+    }
+
+    /// 块内容与块号一起计算,可以发现写错位置的块,结果为 0 时记为 1
+    fn crc(block: usize, data: &[u8]) -> u32 {
+        crc32c_append(block as u32, data).max(1)
+    }
+
+    fn verify(&self, block: usize, buf: &[u8]) -> io::Result<()> {
+        let crcs = self.crcs.lock().unwrap();
+        for (i, chunk) in buf.chunks(BLOCK_SIZE).enumerate() {
+            let expected = crcs.get(block + i).copied().unwrap_or(0);
+            if expected == 0 {
+                continue;
+            }
+            let actual = Self::crc(block + i, chunk);
+            if actual != expected {
+                return Err(self.mismatch(ChecksumError { block: block + i, expected, actual }));
+            }
+        }
+        Ok(())
+    }
+
+    fn mismatch(&self, e: ChecksumError) -> Error {
+        error!("Data corruption detected, {}", e);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        let mut reporter = self.reporter.lock().unwrap();
+        if reporter.as_ref().is_some_and(|sender| sender.send(e.clone()).is_err()) {
+            *reporter = None;
+        }
+        Error::new(ErrorKind::InvalidData, e.to_string())
+    }
+
+    /// 持有 crcs 锁依次清零表项、写入数据并更新内存中的表项,sync 不会在其间写回表项
+    fn write_with(&self, block: usize, data: &[u8], write: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+        let mut crcs = self.crcs.lock().unwrap();
+        self.clear(&crcs, block, data.len() / BLOCK_SIZE)?;
+        write()?;
+        Self::record(&mut crcs, block, data);
+        Ok(())
+    }
+
+    /// 写入数据前调用,把表设备上涉及的表项清零并落盘
+    /// 已经清零的块不再重复写表
+    fn clear(&self, crcs: &[u32], block: usize, blocks: usize) -> io::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        let end = (block + blocks).min(crcs.len());
+        let mut range: Option<(usize, usize)> = None;
+        for (id, crc) in crcs.iter().enumerate().take(end).skip(block) {
+            if pending.insert(id) && *crc != 0 {
+                range = Some((range.map_or(id, |r| r.0), id));
+            }
+        }
+        match range {
+            Some((first, last)) => {
+                self.write_table(crcs, &pending, first / CRCS_PER_BLOCK, last / CRCS_PER_BLOCK)?;
+                self.table.sync()
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// 更新内存中的表项,表设备在 sync 时写回
+    fn record(crcs: &mut [u32], block: usize, data: &[u8]) {
+        let end = (block + data.len() / BLOCK_SIZE).min(crcs.len());
+        for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate().take(end.saturating_sub(block)) {
+            crcs[block + i] = Self::crc(block + i, chunk);
+        }
+    }
+
+    /// 写回第 first 到 last 个表块,pending 中的块写 0
+    fn write_table(&self, crcs: &[u32], pending: &BTreeSet<usize>, first: usize, last: usize) -> io::Result<()> {
+        let mut buf = vec![0u8; (last + 1 - first) * BLOCK_SIZE];
+        let start = first * CRCS_PER_BLOCK;
+        let entries = &crcs[start..crcs.len().min((last + 1) * CRCS_PER_BLOCK)];
+        for (i, (v, crc)) in buf.chunks_mut(4).zip(entries).enumerate() {
+            if !pending.contains(&(start + i)) {
+                v.copy_from_slice(&crc.to_le_bytes());
+            }
+        }
+        self.table.write_blocks(first, &buf)
+    }
+}
+
+impl BlockDevice for ChecksumDevice {
+    fn id(&self) -> usize {
+        self.inner.id()
+    }
+
+    fn read(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read(block, buf)?;
+        self.verify(block, buf)
+    }
+
+    fn write(&self, block: usize, data: &[u8]) -> io::Result<()> {
+        self.write_with(block, data, || self.inner.write(block, data))
+    }
+
+    fn read_blocks(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_blocks(block, buf)?;
+        self.verify(block, buf)
+    }
+
+    fn write_blocks(&self, block: usize, data: &[u8]) -> io::Result<()> {
+        self.write_with(block, data, || self.inner.write_blocks(block, data))
+    }
+
+    /// 数据落盘后才写回新的表项,持有 crcs 锁,写入不会落在两者之间
+    fn sync(&self) -> io::Result<()> {
+        let crcs = self.crcs.lock().unwrap();
+        self.inner.sync()?;
+        let mut pending = self.pending.lock().unwrap();
+        let tables: BTreeSet<usize> = pending.iter().map(|id| id / CRCS_PER_BLOCK).collect();
+        pending.clear();
+        for id in tables {
+            self.write_table(&crcs, &pending, id, id)?;
+        }
+        self.table.sync()
+    }
+
+    fn blocks(&self) -> io::Result<usize> {
+        self.inner.blocks()
+    }
+
+    fn checksum_errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
+
+#[test]
+fn detect_bit_rot() {
+    use std::sync::mpsc::channel;
+
+    use crate::block_device::ram_device::RamDevice;
+    use crate::manager::block_cache_manager::BlockCacheDevice;
+
+    let blocks = CRCS_PER_BLOCK + 64;
+    let ram = Arc::new(RamDevice::new(blocks));
+    let table = Arc::new(RamDevice::new(ChecksumDevice::table_blocks(blocks)));
+    ram.write(3, &[7u8; BLOCK_SIZE]).unwrap();
+    let (sender, receiver) = channel();
+    let device = Arc::new(ChecksumDevice::new(ram.clone(), table.clone()).unwrap().report_to(sender));
+    // 包装之前写入的块没有记录,不校验
+    let mut buf = [0u8; BLOCK_SIZE];
+    device.read(3, &mut buf).unwrap();
+    // 跨越两个表块的写入
+    let data = vec![1u8; 4 * BLOCK_SIZE];
+    device.write_blocks(CRCS_PER_BLOCK - 2, &data).unwrap();
+    let mut buf = vec![0u8; 4 * BLOCK_SIZE];
+    device.read_blocks(CRCS_PER_BLOCK - 2, &mut buf).unwrap();
+
+    let mut image = ram.snapshot();
+    image[(CRCS_PER_BLOCK + 1) * BLOCK_SIZE + 100] ^= 0x10;
+    ram.restore(&image).unwrap();
+    let err = device.read_blocks(CRCS_PER_BLOCK - 2, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(receiver.try_recv().unwrap().block, CRCS_PER_BLOCK + 1);
+    assert_eq!(device.checksum_errors(), 1);
+
+    // 表在 sync 时持久化,重新打开后仍能发现损坏
+    device.sync().unwrap();
+    let device = ChecksumDevice::new(ram.clone(), table.clone()).unwrap();
+    let mut block = [0u8; BLOCK_SIZE];
+    assert!(device.read(CRCS_PER_BLOCK + 1, &mut block).is_err());
+    device.read(CRCS_PER_BLOCK, &mut block).unwrap();
+    device.rebuild().unwrap();
+    device.read(CRCS_PER_BLOCK + 1, &mut block).unwrap();
+
+    // 写入数据后、sync 之前崩溃,表项已清零,重新打开后该块不校验,不会被当作损坏
+    device.write(CRCS_PER_BLOCK, &[5u8; BLOCK_SIZE]).unwrap();
+    let torn = ChecksumDevice::new(ram.clone(), table.clone()).unwrap();
+    torn.read(CRCS_PER_BLOCK, &mut block).unwrap();
+    torn.read(CRCS_PER_BLOCK + 1, &mut block).unwrap();
+    assert_eq!(torn.checksum_errors(), 0);
+    // 数据也没有落盘时同样不报告
+    ram.write(CRCS_PER_BLOCK, &[1u8; BLOCK_SIZE]).unwrap();
+    torn.read(CRCS_PER_BLOCK, &mut block).unwrap();
+    device.sync().unwrap();
+    let device = ChecksumDevice::new(ram.clone(), table.clone()).unwrap();
+    assert!(device.read(CRCS_PER_BLOCK, &mut block).is_err());
+
+    // 经过缓存层时表现为 EIO,并计入统计
+    let device = Arc::new(ChecksumDevice::new(Arc::new(RamDevice::new(1024)), Arc::new(RamDevice::new(1))).unwrap());
+    let mut fs = BlockCacheDevice::new(device.clone()).unwrap();
+    fs.mkfs(None).unwrap();
+    let file = fs.create_file("file", &[1u8; BLOCK_SIZE]).unwrap();
+    fs.sync().unwrap();
+    let inode = fs.inode(file).unwrap();
+    let id = fs.inode_data_blk_list(&inode).unwrap()[0];
+    let blk = fs.data_block(id);
+    drop(fs);
+    device.inner.write(blk, &[2u8; BLOCK_SIZE]).unwrap();
+    let mut fs = BlockCacheDevice::open(device).unwrap();
+    let mut v = 0;
+    assert_eq!(fs.data(id, 0, |data: &u8| v = *data).err(), Some(crate::manager::error_code::EIO));
+    assert_eq!(fs.stats().device_checksum_errors, 1);
+}
@@ -1,13 +1,21 @@
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
-use std::io::Read;
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
 
 use crate::block_device::block_device::BlockDevice;
 use crate::config::BLOCK_SIZE;
 
+/// 镜像文件设备
+/// 使用 pread / pwrite 按偏移读写,不需要 seek,也不需要加锁
 pub struct FileDevice {
-    pub file: Arc<Mutex<File>>,
+    pub file: Arc<File>,
+}
+
+impl FileDevice {
+    pub fn new(file: File) -> Self {
+        Self { file: Arc::new(file) }
+    }
 }
 
 impl BlockDevice for FileDevice {
@@ -15,21 +23,51 @@ impl BlockDevice for FileDevice {
         return 0x92101221;
     }
 
-    fn read(&self, block: usize, buf: &mut [u8]) {
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start((block * BLOCK_SIZE) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SIZE, "Not a complete block!");
+    /// 镜像被截断时 read_exact_at 返回 UnexpectedEof,不再直接 panic
+    fn read(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.file.read_exact_at(buf, (block * BLOCK_SIZE) as u64)
+    }
+
+    fn write(&self, block: usize, buf: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(buf, (block * BLOCK_SIZE) as u64)
+    }
+
+    fn read_blocks(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.read(block, buf)
+    }
+
+    fn write_blocks(&self, block: usize, data: &[u8]) -> io::Result<()> {
+        self.write(block, data)
     }
 
-    fn write(&self, block: usize, buf: &[u8]) {
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start((block * BLOCK_SIZE) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SIZE, "Not a complete block!");
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
     }
 
-    fn sync(&self) {
-        let _ = self.file.lock().unwrap().sync_all();
+    /// 由文件长度计算,不足一块的尾部不计入
+    fn blocks(&self) -> io::Result<usize> {
+        let len = self.file.metadata()?.len();
+        Ok(len as usize / BLOCK_SIZE)
     }
 }
+
+#[test]
+fn positional_blocks() {
+    let path = std::env::temp_dir().join(format!("exfs-file-device-{}", std::process::id()));
+    let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+    file.set_len(16 * BLOCK_SIZE as u64).unwrap();
+    let device = FileDevice::new(file);
+    std::fs::remove_file(&path).unwrap();
+
+    let data: Vec<u8> = (0..4 * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE + 1) as u8).collect();
+    device.write_blocks(3, &data).unwrap();
+    let mut block = [0u8; BLOCK_SIZE];
+    device.read(5, &mut block).unwrap();
+    assert_eq!(block, [3u8; BLOCK_SIZE]);
+    let mut buf = vec![0u8; 6 * BLOCK_SIZE];
+    device.read_blocks(2, &mut buf).unwrap();
+    assert_eq!(&buf[BLOCK_SIZE..5 * BLOCK_SIZE], &data[..]);
+    assert_eq!(buf[0], 0);
+    assert_eq!(buf[5 * BLOCK_SIZE], 0);
+    assert_eq!(device.read_blocks(14, &mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
@@ -1,4 +1,6 @@
 pub mod block_device;
+pub mod checksum_device;
 pub mod file_device;
-
+pub mod overlay_device;
+pub mod ram_device;
 
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::block_device::block_device::BlockDevice;
+use crate::config::BLOCK_SIZE;
+
+/// 只读设备上的内存覆盖层
+/// 写入只保存在内存中,读取时优先返回,内层设备不会被修改
+/// 只读打开镜像时在其上重放日志,检查工具看到的是恢复后的状态
+pub struct OverlayDevice {
+    inner: Arc<dyn BlockDevice>,
+    blocks: Mutex<BTreeMap<usize, Box<[u8; BLOCK_SIZE]>>>,
+}
+
+impl OverlayDevice {
+    pub fn new(inner: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            inner,
+            blocks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// 被覆盖的块数
+    pub fn len(&self) -> usize {
+        self.blocks.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl BlockDevice for OverlayDevice {
+    fn id(&self) -> usize {
+        self.inner.id()
+    }
+
+    fn read(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+        let blocks = self.blocks.lock().unwrap();
+        for (i, chunk) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+            match blocks.get(&(block + i)) {
+                Some(data) => chunk.copy_from_slice(&data[..chunk.len()]),
+                None => self.inner.read(block + i, chunk)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, block: usize, data: &[u8]) -> io::Result<()> {
+        let mut blocks = self.blocks.lock().unwrap();
+        for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+            let mut buf = Box::new([0u8; BLOCK_SIZE]);
+            if chunk.len() < BLOCK_SIZE {
+                match blocks.get(&(block + i)) {
+                    Some(data) => buf.copy_from_slice(&data[..]),
+                    None => self.inner.read(block + i, &mut buf[..])?,
+                }
+            }
+            buf[..chunk.len()].copy_from_slice(chunk);
+            blocks.insert(block + i, buf);
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn blocks(&self) -> io::Result<usize> {
+        self.inner.blocks()
+    }
+
+    fn checksum_errors(&self) -> u64 {
+        self.inner.checksum_errors()
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+
+use crate::block_device::block_device::BlockDevice;
+use crate::config::BLOCK_SIZE;
+
+/// 内存块设备
+/// 所有块保存在一段连续内存中,可用于测试或 tmpfs 式的临时文件系统
+pub struct RamDevice {
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDevice {
+    /// 创建 blocks 个全零块
+    pub fn new(blocks: usize) -> Self {
+        Self {
+            data: Mutex::new(vec![0u8; blocks * BLOCK_SIZE]),
+        }
+    }
+
+    /// 从快照恢复,快照长度必须是 BLOCK_SIZE 的整数倍
+    pub fn from_snapshot(snapshot: Vec<u8>) -> io::Result<Self> {
+        check_len(snapshot.len())?;
+        Ok(Self {
+            data: Mutex::new(snapshot),
+        })
+    }
+
+    /// 导出所有块的完整拷贝
+    /// 注意上层缓存中的脏块需要先 sync 才会出现在快照中
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+
+    /// 用快照覆盖当前设备内容
+    pub fn restore(&self, snapshot: &[u8]) -> io::Result<()> {
+        check_len(snapshot.len())?;
+        let mut data = self.data.lock().unwrap();
+        data.clear();
+        data.extend_from_slice(snapshot);
+        Ok(())
+    }
+
+    fn range(&self, block: usize, len: usize, total: usize) -> io::Result<std::ops::Range<usize>> {
+        let start = block * BLOCK_SIZE;
+        if start + len > total {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("block {} is out of ram device({} blocks)", block, total / BLOCK_SIZE),
+            ));
+        }
+        Ok(start..start + len)
+    }
+}
+
+fn check_len(len: usize) -> io::Result<()> {
+    if !len.is_multiple_of(BLOCK_SIZE) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("snapshot size {} is not a multiple of block size", len),
+        ));
+    }
+    Ok(())
+}
+
+impl BlockDevice for RamDevice {
+    fn id(&self) -> usize {
+        0x52414d44
+    }
+
+    fn read(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.data.lock().unwrap();
+        let range = self.range(block, buf.len(), data.len())?;
+        buf.copy_from_slice(&data[range]);
+        Ok(())
+    }
+
+    fn write(&self, block: usize, buf: &[u8]) -> io::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let range = self.range(block, buf.len(), data.len())?;
+        data[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_blocks(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.read(block, buf)
+    }
+
+    fn write_blocks(&self, block: usize, data: &[u8]) -> io::Result<()> {
+        self.write(block, data)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn blocks(&self) -> io::Result<usize> {
+        Ok(self.data.lock().unwrap().len() / BLOCK_SIZE)
+    }
+}
+
+#[test]
+fn snapshot_restore() {
+    use std::sync::Arc;
+    use crate::manager::block_cache_manager::BlockCacheDevice;
+
+    let ram = Arc::new(RamDevice::new(1024));
+    let mut fs = BlockCacheDevice::new(ram.clone()).unwrap();
+    fs.mkfs(None).unwrap();
+    let root = fs.inode(1).unwrap().with_id(1);
+    fs.make_node_internal("hello.txt", &root, 0o100644, 0, 0).unwrap();
+    fs.sync().unwrap();
+
+    let restored = Arc::new(RamDevice::from_snapshot(ram.snapshot()).unwrap());
+    let mut fs = BlockCacheDevice::new(restored).unwrap();
+    let root = fs.inode(1).unwrap();
+    let entries = fs.ls_internal(&root).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(String::from(entries[1].name), "hello.txt");
+}
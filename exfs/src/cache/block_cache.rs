@@ -1,11 +1,13 @@
 // 物理块缓存层
 
 use std::fmt::Debug;
+use std::io;
 use std::mem::size_of;
-use std::sync::Arc;
-use log::debug;
+use std::sync::{Arc, Mutex, Weak};
+use log::{debug, error};
 
 use crate::block_device::block_device::BlockDevice;
+use crate::cache::write_back::WriteBack;
 use crate::config::BLOCK_SIZE;
 use crate::utils::slice::SliceExt;
 
@@ -14,24 +16,83 @@ pub struct CacheBlock {
     data: [u8; BLOCK_SIZE],
     device: Arc<dyn BlockDevice>,
     dirty: bool,
+    // 普通文件的数据,metadata 日志模式下不写入日志
+    file_data: bool,
+    // inode、位图与索引块,使用单独的缓存容量
+    metadata: bool,
+    // 读入或修改后已通过校验和检查
+    verified: bool,
+    // 变脏时登记到脏块列表
+    tracker: Option<(Arc<WriteBack>, Weak<Mutex<CacheBlock>>)>,
 }
 
 impl CacheBlock {
-    pub fn new(device: Arc<dyn BlockDevice>, block: usize) -> Self {
+    pub fn new(device: Arc<dyn BlockDevice>, block: usize) -> io::Result<Self> {
         let mut buf = [0u8; BLOCK_SIZE];
         // let trim = trim_zero(buf.to_vec());
         // if !trim.is_empty() {
         //     debug!("NEW:{},buf:{:?}", block, &trim);
         // }
-        device.read(block, &mut buf);
+        device.read(block, &mut buf)?;
+        Ok(Self::from_data(device, block, buf))
+    }
+
+    /// 使用已读取的内容,供批量读取使用
+    pub fn from_data(device: Arc<dyn BlockDevice>, block: usize, data: [u8; BLOCK_SIZE]) -> Self {
         Self {
             block,
-            data: buf,
+            data,
             device,
             dirty: false,
+            file_data: false,
+            metadata: false,
+            verified: false,
+            tracker: None,
         }
     }
 
+    pub(crate) fn tracked(mut self, write_back: Arc<WriteBack>, this: Weak<Mutex<CacheBlock>>) -> Self {
+        self.tracker = Some((write_back, this));
+        self
+    }
+
+    pub fn block(&self) -> usize {
+        self.block
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn data(&self) -> &[u8; BLOCK_SIZE] {
+        &self.data
+    }
+
+    pub fn is_file_data(&self) -> bool {
+        self.file_data
+    }
+
+    /// 标记为普通文件的数据,再次 modify 后失效
+    pub fn set_file_data(&mut self) {
+        self.file_data = true;
+    }
+
+    pub fn is_metadata(&self) -> bool {
+        self.metadata
+    }
+
+    pub(crate) fn set_metadata(&mut self) {
+        self.metadata = true;
+    }
+
+    pub(crate) fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    pub(crate) fn set_verified(&mut self) {
+        self.verified = true;
+    }
+
     fn addr_of_offset(&self, offset: usize) -> usize {
         &self.data[offset] as *const _ as usize
     }
@@ -48,6 +109,11 @@ impl CacheBlock {
     pub fn get_mut<T: Sized>(&mut self, offset: usize) -> &mut T {
         let type_size = core::mem::size_of::<T>();
         assert!(offset + type_size <= BLOCK_SIZE);
+        if !self.dirty {
+            if let Some((write_back, this)) = &self.tracker {
+                write_back.mark(self.block, this.clone());
+            }
+        }
         self.dirty = true;
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
@@ -65,6 +131,8 @@ impl CacheBlock {
         f: impl FnOnce(&mut T) -> V,
     ) -> V {
         let blk = self.block;
+        self.file_data = false;
+        self.verified = false;
         let data: &mut T = self.get_mut(offset);
         let data_slice =
             unsafe { std::slice::from_raw_parts(data as *const T as *const u8, size_of::<T>()) };
@@ -81,9 +149,14 @@ impl CacheBlock {
         } else if data_mm.len() > 0 {
             debug!("({}->{})【After】{:?}", blk, offset, String::from_utf8_lossy(data_mm));
         }
-        // self.sync(); // 关缓存
+        if self.tracker.as_ref().is_some_and(|(write_back, _)| write_back.is_write_through()) {
+            if let Err(e) = self.sync() {
+                error!("Write through block {} failed: {}", blk, e);
+            }
+        }
         v
     }
+    /// 清零,随 sync 或日志提交写回
     pub fn free(&mut self) {
         self.modify(0, |data: &mut [u8; BLOCK_SIZE]| {
             for byte in data.iter_mut() {
@@ -94,19 +167,41 @@ impl CacheBlock {
                 debug!("After free: {:?}", trim)
             }
         });
-        self.sync();
     }
 
-    pub fn sync(&mut self) {
+    /// 写回失败时保留 dirty 标记,下次 sync 会重试
+    pub fn sync(&mut self) -> io::Result<()> {
         if self.dirty {
-            self.dirty = false;
-            self.device.write(self.block, &self.data);
+            self.device.write(self.block, &self.data)?;
+            self.mark_clean();
+            if let Some((write_back, _)) = &self.tracker {
+                write_back.written(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// 内容已由批量写入落盘
+    pub(crate) fn mark_clean(&mut self) {
+        self.dirty = false;
+        self.file_data = false;
+        if let Some((write_back, _)) = &self.tracker {
+            write_back.clean(self.block);
         }
     }
+
+    /// 替换为已经直接写入设备的内容
+    pub(crate) fn load(&mut self, data: &[u8]) {
+        self.data.copy_from_slice(data);
+        self.verified = false;
+        self.mark_clean();
+    }
 }
 
 impl Drop for CacheBlock {
     fn drop(&mut self) {
-        self.sync()
+        if let Err(e) = self.sync() {
+            error!("Write back block {} failed on drop: {}", self.block, e);
+        }
     }
 }
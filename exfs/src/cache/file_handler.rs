@@ -1,21 +1,28 @@
 use std::io::SeekFrom;
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
 
-use libc::{c_int, ENOSPC, O_APPEND, O_TRUNC};
+use libc::{c_int, O_APPEND, O_TRUNC};
+use log::debug;
 
 use crate::cache::block_cache::CacheBlock;
+use crate::cache::readahead::Readahead;
 use crate::config::BLOCK_SIZE;
 use crate::layout::inode::{Inode, InodeWithId};
 use crate::manager::block_cache_manager::BlockCacheDevice;
 use crate::manager::error_code::{ENOENT, ErrorCode};
+use crate::typ::file_type::FileType;
 
 
 pub struct FileHandler {
     inode_id: usize,
+    // 打开期间 inode 所在块常驻缓存,与 BlockCacheDevice 共享同一份
     inode_block: Arc<Mutex<CacheBlock>>,
     inode_offset: usize,
     offset: usize,
     flags: i32,
+    // 句柄在每次读写时被复制,预读状态需要共享
+    readahead: Arc<Mutex<Readahead>>,
 }
 
 impl Clone for FileHandler {
@@ -26,6 +33,7 @@ impl Clone for FileHandler {
             inode_offset: self.inode_offset,
             offset: self.offset,
             flags: self.flags,
+            readahead: self.readahead.clone(),
         }
     }
 }
@@ -38,8 +46,8 @@ impl FileHandler {
         flags: i32,
     ) -> Result<Self, ErrorCode> {
         let (blk_id, inode_offset) = device.inode_block(inode_id);
-        let blk = device.block_cache(blk_id);
-        let ino = device.inode(inode_id);
+        let blk = device.block_cache(blk_id)?;
+        let ino = device.inode(inode_id)?;
         if !ino.exist() {
             return Err(ENOENT);
         }
@@ -49,11 +57,10 @@ impl FileHandler {
             inode_offset,
             offset,
             flags,
+            readahead: Arc::new(Mutex::new(Readahead::default())),
         };
         if (flags & O_TRUNC) > 0 {
-            if let Err(e) = device.write_system(0, &fh.inode_with_id(), &mut vec![], true) {
-                return Err(e);
-            }
+            device.write_system(0, &fh.inode_with_id(), &mut vec![], true)?;
         }
         Ok(fh)
     }
@@ -82,25 +89,25 @@ impl FileHandler {
         }
     }
 
-    pub fn read(&mut self, device: &mut BlockCacheDevice, buf: &mut [u8]) -> usize {
+    pub fn read(&mut self, device: &mut BlockCacheDevice, buf: &mut [u8]) -> Result<usize, ErrorCode> {
         device.read_internal(self, buf)
     }
 
-    pub fn read_block<T, V>(&mut self, device: &mut BlockCacheDevice, blk_id: usize, offset: usize, f: impl FnOnce(&T) -> V) -> Option<V> {
-        let data = device.inode_data_blk_list(&self.inode_with_id().data);
-        println!("read block {}, but {:?}", blk_id, data);
-        if blk_id >= data.len() { return None }
-        Some(device.block_cache(device.data_block(data[blk_id]))
+    pub fn read_block<T, V>(&mut self, device: &mut BlockCacheDevice, blk_id: usize, offset: usize, f: impl FnOnce(&T) -> V) -> Result<Option<V>, ErrorCode> {
+        let data = device.inode_data_blk_list(&self.inode_with_id().data)?;
+        debug!("read block {}, data blocks {:?}", blk_id, data);
+        if blk_id >= data.len() { return Ok(None) }
+        Ok(Some(device.block_cache(device.data_block(data[blk_id]))?
             .lock()
             .unwrap()
-            .read(offset, f))
+            .read(offset, f)))
     }
 
     fn is_append(&self) -> bool {
         (self.flags & O_APPEND) > 0
     }
 
-    pub fn flush(&self, device: &mut BlockCacheDevice) {
+    pub fn flush(&self, device: &mut BlockCacheDevice) -> Result<(), ErrorCode> {
         device.flush_internal(&self.inode_with_id())
     }
 }
@@ -124,21 +131,31 @@ impl BlockCacheDevice {
         buf: &[u8],
         truncate: bool,
     ) -> Result<usize, ErrorCode> {
-        let mut data = self.inode_data_blk_list(inode_with_id.inode());
+        if inode_with_id.data.is_dir() {
+            self.dentries.remove_dir(inode_with_id.inode);
+        }
+        let mut data = self.inode_data_blk_list(inode_with_id.inode())?;
         let blk = offset / BLOCK_SIZE;
         let len = buf.len();
         let need_blk = (offset + len + BLOCK_SIZE - 1) / BLOCK_SIZE;
         if need_blk > data.len() {
             // 申请空间
-            for _ in data.len()..need_blk {
-                match self.alloc_block(false) {
-                    None => return Err(ENOSPC),
-                    Some(data_id) => data.push(data_id),
-                }
+            // 按区间分配,尽量接在文件最后一块之后,目录很少增长,不为其保留空间
+            while data.len() < need_blk {
+                let n = need_blk - data.len();
+                let extent = if inode_with_id.data.is_dir() {
+                    let hint = data.last().map(|id| id + 1).or_else(|| self.group_data_hint(inode_with_id.inode));
+                    self.alloc_extent(n, hint)?
+                } else {
+                    self.alloc_file_extent(inode_with_id.inode, n, data.last().copied())?
+                };
+                data.extend(extent);
             }
         } else if need_blk < data.len() && truncate {
+            // 截断后不再为文件保留其后的空间
+            self.allocator.forget(inode_with_id.inode);
             for _ in need_blk..data.len() {
-                self.free_block(data.pop().unwrap(), false, true);
+                self.free_block(data.pop().unwrap(), false, true)?;
             }
         }
         let offset_part = offset % BLOCK_SIZE;
@@ -153,7 +170,10 @@ impl BlockCacheDevice {
             self.modify_data(data[blk + i], |data: &mut [u8; BLOCK_SIZE]| {
                 data[offset..end]
                     .copy_from_slice(&buf[i * BLOCK_SIZE..len.min((i + 1) * BLOCK_SIZE)]);
-            });
+            })?;
+            if inode_with_id.data.file_type() == FileType::File {
+                self.mark_file_data(data[blk + i])?;
+            }
             offset_mut += length
         }
         self.modify_inode(inode_with_id.inode, |ino| {
@@ -162,16 +182,20 @@ impl BlockCacheDevice {
             } else {
                 (offset_mut as u64).max(ino.size)
             }
-        });
+        })?;
         self.make_index_part(inode_with_id, data, 0).map(|_| offset_mut)
     }
 
-    pub fn read_internal(&mut self, fh: &mut FileHandler, buf: &mut [u8]) -> usize {
-        let data = self.inode_data_blk_list(&fh.inode_with_id().inode());
+    pub fn read_internal(&mut self, fh: &mut FileHandler, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+        let data = self.inode_data_blk_list(&fh.inode_with_id().inode())?;
         let start_offset = fh.offset;
         let blk = start_offset / BLOCK_SIZE;
         let off = start_offset % BLOCK_SIZE;
         let len = buf.len();
+        let ahead = fh.readahead.lock().unwrap().access(start_offset, len, self.readahead_blocks());
+        if let Some(range) = ahead {
+            self.readahead(&data, range)?;
+        }
         let read_times = (data.len() - blk).min((off + len + BLOCK_SIZE - 1) / BLOCK_SIZE);
         for i in 0..read_times {
             let offset = fh.offset % BLOCK_SIZE;
@@ -180,11 +204,25 @@ impl BlockCacheDevice {
             self.data(data[blk + i], 0, |data: &[u8; BLOCK_SIZE]| {
                 buf[i * BLOCK_SIZE..len.min((i + 1) * BLOCK_SIZE)]
                     .copy_from_slice(&data[offset..end]);
-            });
+            })?;
             let length = end - offset;
             fh.offset += length
         }
-        fh.offset - start_offset
+        Ok(fh.offset - start_offset)
+    }
+
+    /// 预读 range 内从起点开始的第一段物理地址连续的数据块
+    fn readahead(&mut self, data: &[usize], range: Range<usize>) -> Result<(), ErrorCode> {
+        let end = range.end.min(data.len());
+        if range.start >= end {
+            return Ok(());
+        }
+        let first = data[range.start];
+        let n = data[range.start..end].iter().enumerate()
+            .take_while(|(i, id)| **id == first + i)
+            .count();
+        let n = self.super_block().contiguous(first, n);
+        self.prefetch(first, n).map(|_| ())
     }
 
 }
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+
+use crate::layout::inode::Inode;
+use crate::typ::file_name::FileName;
+
+/// inode 缓存,按 inode 号保存 inode 副本
+/// 所有修改经过 modify_inode 同步更新,释放 inode 时移除
+pub struct InodeCache {
+    inodes: LruCache<usize, Inode>,
+}
+
+impl InodeCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { inodes: LruCache::new(NonZeroUsize::new(capacity.max(1)).unwrap()) }
+    }
+
+    pub fn get(&mut self, id: usize) -> Option<Inode> {
+        self.inodes.get(&id).copied()
+    }
+
+    pub fn put(&mut self, id: usize, inode: Inode) {
+        self.inodes.put(id, inode);
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        self.inodes.pop(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.inodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inodes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.inodes.clear();
+    }
+}
+
+/// 目录项缓存,(父目录, 文件名) -> inode 号,None 表示确认不存在
+/// 按目录分组,写入目录内容时整个目录失效,容量超出时淘汰最久未访问的目录
+pub struct DentryCache {
+    dirs: LruCache<usize, HashMap<FileName, Option<usize>>>,
+    // 所有目录中的目录项总数
+    len: usize,
+    capacity: usize,
+}
+
+impl DentryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { dirs: LruCache::unbounded(), len: 0, capacity: capacity.max(1) }
+    }
+
+    /// 未缓存时返回 None
+    pub fn get(&mut self, parent: usize, name: &FileName) -> Option<Option<usize>> {
+        self.dirs.get(&parent).and_then(|dir| dir.get(name).copied())
+    }
+
+    pub fn insert(&mut self, parent: usize, name: FileName, inode: Option<usize>) {
+        let dir = self.dirs.get_or_insert_mut(parent, HashMap::new);
+        if dir.insert(name, inode).is_none() {
+            self.len += 1;
+        }
+        // 当前目录最后淘汰
+        while self.len > self.capacity && self.dirs.len() > 1 {
+            let (_, dir) = self.dirs.pop_lru().unwrap();
+            self.len -= dir.len();
+        }
+    }
+
+    /// 目录内容改变或目录被删除
+    pub fn remove_dir(&mut self, parent: usize) {
+        if let Some(dir) = self.dirs.pop(&parent) {
+            self.len -= dir.len();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.dirs.clear();
+        self.len = 0;
+    }
+}
+
+#[test]
+fn lookup_uses_dentry_cache() {
+    use crate::manager::block_cache_manager::BlockCacheDevice;
+    use crate::manager::error_code::ENOENT;
+    use crate::manager::mkfs::MkfsOptions;
+    use crate::typ::file_type::FileType;
+
+    let (_, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let file = fs.create_file("a", &[]).unwrap();
+    let root = fs.inode(1).unwrap().with_id(1);
+    assert_eq!(fs.lookup_internal(&root, "a".into()).unwrap().inode, file);
+    assert_eq!(fs.lookup_internal(&root, "b".into()).err(), Some(ENOENT));
+
+    // 再次查找不再读取目录与 inode 块
+    let accesses = |fs: &BlockCacheDevice| {
+        let stats = fs.stats();
+        stats.cache_hits + stats.cache_misses
+    };
+    let before = accesses(&fs);
+    assert_eq!(fs.lookup_internal(&root, "a".into()).unwrap().inode, file);
+    assert_eq!(fs.lookup_internal(&root, "b".into()).err(), Some(ENOENT));
+    assert_eq!(accesses(&fs), before);
+
+    // 创建、改名、链接与删除后目录项保持一致
+    let b = fs.create_file("b", &[]).unwrap();
+    let root = fs.inode(1).unwrap().with_id(1);
+    assert_eq!(fs.lookup_internal(&root, "b".into()).unwrap().inode, b);
+    fs.rename_internal(&root, "b".into(), &root, "c".into()).unwrap();
+    let root = fs.inode(1).unwrap().with_id(1);
+    assert_eq!(fs.lookup_internal(&root, "b".into()).err(), Some(ENOENT));
+    assert_eq!(fs.lookup_internal(&root, "c".into()).unwrap().inode, b);
+    fs.unlink_internal(&root, "c".into()).unwrap();
+    let root = fs.inode(1).unwrap().with_id(1);
+    assert_eq!(fs.lookup_internal(&root, "c".into()).err(), Some(ENOENT));
+    assert_eq!(fs.lookup_internal(&root, "a".into()).unwrap().inode().link_count, 1);
+
+    // 释放后重新分配的 inode 号不会沿用旧目录的目录项
+    let dir = fs.make_node_internal("d", &root, FileType::Dir << 12 | 0o755, 0, 0).unwrap();
+    let d = fs.inode(dir).unwrap().with_id(dir);
+    fs.make_node_internal("x", &d, FileType::File << 12 | 0o644, 0, 0).unwrap();
+    let d = fs.inode(dir).unwrap().with_id(dir);
+    assert!(fs.lookup_internal(&d, "x".into()).is_ok());
+    fs.free_block(dir, true, true).unwrap();
+    fs.alloc_block(true).unwrap();
+    fs.modify_inode(dir, |inode| *inode = Inode::new(FileType::Dir << 12 | 0o755, 0, 0)).unwrap();
+    let d = fs.inode(dir).unwrap().with_id(dir);
+    assert_eq!(fs.lookup_internal(&d, "x".into()).err(), Some(ENOENT));
+}
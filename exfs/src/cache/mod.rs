@@ -1,2 +1,7 @@
 pub mod block_cache;
-pub mod file_handler;
\ No newline at end of file
+pub mod file_handler;
+pub mod inode_cache;
+pub mod options;
+pub mod policy;
+pub mod readahead;
+pub mod write_back;
\ No newline at end of file
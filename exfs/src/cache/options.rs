@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use crate::cache::policy::ReplacePolicy;
+use crate::cache::write_back::WritePolicy;
+use crate::config::{BLOCK_SIZE, CACHE_BLOCKS, DENTRY_CACHE, INODE_CACHE, READAHEAD_BLOCKS};
+
+/// 块缓存参数
+/// 未设置的项使用默认值: 128 块,元数据(inode、位图与索引块)与数据块共用容量,
+/// LRU 替换,write-back,每 5 秒写回一次,脏块超过容量的 20% 时提前写回,预读窗口最大 32 块,
+/// 缓存 1024 个 inode 与 4096 个目录项
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+    pub(crate) blocks: usize,
+    pub(crate) metadata_blocks: Option<usize>,
+    pub(crate) write_policy: WritePolicy,
+    pub(crate) flush_interval: Duration,
+    pub(crate) dirty_ratio: u8,
+    pub(crate) readahead_blocks: usize,
+    pub(crate) policy: ReplacePolicy,
+    pub(crate) inodes: usize,
+    pub(crate) dentries: usize,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            blocks: CACHE_BLOCKS,
+            metadata_blocks: None,
+            write_policy: WritePolicy::WriteBack,
+            flush_interval: Duration::from_secs(5),
+            dirty_ratio: 20,
+            readahead_blocks: READAHEAD_BLOCKS,
+            policy: ReplacePolicy::Lru,
+            inodes: INODE_CACHE,
+            dentries: DENTRY_CACHE,
+        }
+    }
+}
+
+impl CacheOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 缓存的块数,至少为 1
+    pub fn blocks(mut self, blocks: usize) -> Self {
+        self.blocks = blocks.max(1);
+        self
+    }
+
+    /// 缓存的字节数,向下取整到整块
+    pub fn size(self, bytes: u64) -> Self {
+        self.blocks(bytes as usize / BLOCK_SIZE)
+    }
+
+    /// 元数据单独使用的块数,设置后读写文件数据不会淘汰元数据
+    pub fn metadata_blocks(mut self, blocks: usize) -> Self {
+        self.metadata_blocks = Some(blocks.max(1));
+        self
+    }
+
+    /// 元数据单独使用的字节数,向下取整到整块
+    pub fn metadata_size(self, bytes: u64) -> Self {
+        self.metadata_blocks(bytes as usize / BLOCK_SIZE)
+    }
+
+    pub fn write_policy(mut self, policy: WritePolicy) -> Self {
+        self.write_policy = policy;
+        self
+    }
+
+    /// 后台线程写回脏块的间隔,为 0 时不启动后台线程
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// 脏块占缓存容量的百分比超过该值时立即写回
+    pub fn dirty_ratio(mut self, percent: u8) -> Self {
+        self.dirty_ratio = percent.min(100);
+        self
+    }
+
+    /// 顺序读预读窗口的上限块数,为 0 时关闭预读,实际上限不超过缓存容量的 1/4
+    pub fn readahead_blocks(mut self, blocks: usize) -> Self {
+        self.readahead_blocks = blocks;
+        self
+    }
+
+    /// 缓存替换策略,大文件顺序读写较多时可用 2Q 避免元数据被挤出
+    pub fn policy(mut self, policy: ReplacePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// inode 缓存的条目数,至少为 1
+    pub fn inode_cache(mut self, inodes: usize) -> Self {
+        self.inodes = inodes.max(1);
+        self
+    }
+
+    /// 目录项缓存的条目数,包括不存在的文件名,至少为 1
+    pub fn dentry_cache(mut self, dentries: usize) -> Self {
+        self.dentries = dentries.max(1);
+        self
+    }
+
+    /// 缓存的总块数,包括元数据单独使用的部分
+    pub(crate) fn capacity(&self) -> usize {
+        self.blocks + self.metadata_blocks.unwrap_or(0)
+    }
+}
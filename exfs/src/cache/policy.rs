@@ -0,0 +1,213 @@
+use std::fmt::{Display, Formatter};
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+
+use crate::cache::block_cache::CacheBlock;
+
+pub type CacheEntry = Arc<Mutex<CacheBlock>>;
+
+/// 块缓存的替换策略
+/// 只负责记录访问顺序并给出淘汰顺序,容量、被引用的块与脏块由 BlockCacheDevice 处理
+pub trait CachePolicy: Send {
+    /// 访问已缓存的块
+    fn get(&mut self, block: &usize) -> Option<&CacheEntry>;
+    /// 不计为一次访问
+    fn peek(&self, block: &usize) -> Option<&CacheEntry>;
+    fn contains(&self, block: &usize) -> bool {
+        self.peek(block).is_some()
+    }
+    fn put(&mut self, block: usize, cache: CacheEntry);
+    /// 淘汰一个块
+    fn pop(&mut self, block: &usize) -> Option<CacheEntry>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// 按淘汰的先后顺序遍历
+    fn victims(&self) -> Box<dyn Iterator<Item = (&usize, &CacheEntry)> + '_>;
+    fn clear(&mut self);
+}
+
+/// 替换策略
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ReplacePolicy {
+    #[default]
+    Lru,
+    /// 2Q,只访问过一次的块不会挤出反复访问的块,适合大文件顺序读与元数据混合的场景
+    TwoQueue,
+}
+
+impl ReplacePolicy {
+    /// capacity: 缓存容量,2Q 用于计算各队列长度
+    pub fn build(&self, capacity: usize) -> Box<dyn CachePolicy> {
+        match self {
+            ReplacePolicy::Lru => Box::new(Lru(LruCache::unbounded())),
+            ReplacePolicy::TwoQueue => Box::new(TwoQueue::new(capacity)),
+        }
+    }
+}
+
+impl FromStr for ReplacePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(ReplacePolicy::Lru),
+            "2q" => Ok(ReplacePolicy::TwoQueue),
+            _ => Err(format!("unknown cache policy '{}', expected lru or 2q", s)),
+        }
+    }
+}
+
+impl Display for ReplacePolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ReplacePolicy::Lru => "lru",
+            ReplacePolicy::TwoQueue => "2q",
+        })
+    }
+}
+
+pub struct Lru(LruCache<usize, CacheEntry>);
+
+impl CachePolicy for Lru {
+    fn get(&mut self, block: &usize) -> Option<&CacheEntry> {
+        self.0.get(block)
+    }
+
+    fn peek(&self, block: &usize) -> Option<&CacheEntry> {
+        self.0.peek(block)
+    }
+
+    fn put(&mut self, block: usize, cache: CacheEntry) {
+        self.0.put(block, cache);
+    }
+
+    fn pop(&mut self, block: &usize) -> Option<CacheEntry> {
+        self.0.pop(block)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn victims(&self) -> Box<dyn Iterator<Item = (&usize, &CacheEntry)> + '_> {
+        Box::new(self.0.iter().rev())
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// 2Q (Johnson & Shasha)
+/// 新块进入 FIFO 的 a1in,在其中再次访问不会提升,可以吸收一次顺序扫描和紧邻的重复访问;
+/// 从 a1in 淘汰的块号记入 a1out,之后再次读入时才进入 LRU 的 am
+pub struct TwoQueue {
+    a1in: LruCache<usize, CacheEntry>,
+    am: LruCache<usize, CacheEntry>,
+    a1out: LruCache<usize, ()>,
+    // a1in 超过该长度时优先从 a1in 淘汰
+    kin: usize,
+}
+
+impl TwoQueue {
+    /// a1in 占容量的 1/4,a1out 记录容量 1/2 的块号
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            a1in: LruCache::unbounded(),
+            am: LruCache::unbounded(),
+            a1out: LruCache::new(NonZeroUsize::new((capacity / 2).max(1)).unwrap()),
+            kin: (capacity / 4).max(1),
+        }
+    }
+}
+
+impl CachePolicy for TwoQueue {
+    fn get(&mut self, block: &usize) -> Option<&CacheEntry> {
+        match self.am.get(block) {
+            Some(cache) => Some(cache),
+            None => self.a1in.peek(block),
+        }
+    }
+
+    fn peek(&self, block: &usize) -> Option<&CacheEntry> {
+        self.am.peek(block).or_else(|| self.a1in.peek(block))
+    }
+
+    fn put(&mut self, block: usize, cache: CacheEntry) {
+        if self.am.contains(&block) || self.a1out.pop(&block).is_some() {
+            self.am.put(block, cache);
+        } else {
+            self.a1in.put(block, cache);
+        }
+    }
+
+    fn pop(&mut self, block: &usize) -> Option<CacheEntry> {
+        if let Some(cache) = self.a1in.pop(block) {
+            self.a1out.put(*block, ());
+            return Some(cache);
+        }
+        self.am.pop(block)
+    }
+
+    fn len(&self) -> usize {
+        self.a1in.len() + self.am.len()
+    }
+
+    fn victims(&self) -> Box<dyn Iterator<Item = (&usize, &CacheEntry)> + '_> {
+        if self.a1in.len() > self.kin {
+            Box::new(self.a1in.iter().rev().chain(self.am.iter().rev()))
+        } else {
+            Box::new(self.am.iter().rev().chain(self.a1in.iter().rev()))
+        }
+    }
+
+    fn clear(&mut self) {
+        self.a1in.clear();
+        self.am.clear();
+        self.a1out.clear();
+    }
+}
+
+#[test]
+fn metadata_survives_scan() {
+    use crate::cache::options::CacheOptions;
+    use crate::layout::journal::JournalMode;
+    use crate::manager::block_cache_manager::BlockCacheDevice;
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new().blocks(4096).journal(JournalMode::None));
+    fs.sync().unwrap();
+    drop(fs);
+
+    // 反复访问 16 个 inode 表块,其间顺序读取 512 个数据块,返回最后一轮访问的未命中数
+    let run = |policy: ReplacePolicy| {
+        let options = CacheOptions::new().blocks(64).policy(policy);
+        let mut fs = BlockCacheDevice::open_with_cache(ram.clone(), options).unwrap();
+        let (_, inode_table) = fs.super_block().regions()[3].clone();
+        let metadata = inode_table.start..inode_table.start + 16;
+        let mut scan = 0;
+        let mut pass = |fs: &mut BlockCacheDevice, blocks: usize| {
+            for blk in metadata.clone() {
+                fs.block_cache(blk).unwrap();
+            }
+            for _ in 0..blocks {
+                fs.data(scan, 0, |_: &u8| {}).unwrap();
+                scan += 1;
+            }
+        };
+        pass(&mut fs, 64);
+        pass(&mut fs, 512);
+        let misses = fs.stats().cache_misses;
+        for blk in metadata {
+            fs.block_cache(blk).unwrap();
+        }
+        fs.stats().cache_misses - misses
+    };
+    assert_eq!(run(ReplacePolicy::Lru), 16);
+    assert_eq!(run(ReplacePolicy::TwoQueue), 0);
+}
@@ -0,0 +1,88 @@
+use std::ops::Range;
+
+use crate::config::BLOCK_SIZE;
+
+/// 首次预读的块数
+pub const MIN_READAHEAD: usize = 4;
+
+/// 每个文件句柄的顺序读检测与预读窗口
+/// 读取紧接上一次读取时视为顺序读,读到已预读区间的一半时继续预读,窗口每次翻倍直到上限
+/// 随机读时窗口清零
+#[derive(Clone, Debug, Default)]
+pub struct Readahead {
+    // 上一次读取结束的字节偏移
+    last_end: usize,
+    // 已预读到的文件块(不含)
+    ahead: usize,
+    window: usize,
+}
+
+impl Readahead {
+    /// 记录一次读取,返回需要预读的文件块区间,包含本次读取尚未预读的块
+    /// max 为窗口上限,为 0 时不预读
+    pub fn access(&mut self, offset: usize, len: usize, max: usize) -> Option<Range<usize>> {
+        let sequential = offset == self.last_end;
+        self.last_end = offset + len;
+        if !sequential || max == 0 {
+            self.ahead = 0;
+            self.window = 0;
+            return None;
+        }
+        let start = offset / BLOCK_SIZE;
+        let end = (offset + len).div_ceil(BLOCK_SIZE);
+        if self.window > 0 && end + self.window / 2 < self.ahead {
+            return None;
+        }
+        self.window = (self.window * 2).max(MIN_READAHEAD).min(max);
+        let from = self.ahead.max(start);
+        self.ahead = end.max(from) + self.window;
+        Some(from..self.ahead)
+    }
+}
+
+#[test]
+fn sequential_read_is_batched() {
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::block_device::block_device::BlockDevice;
+    use crate::block_device::ram_device::RamDevice;
+    use crate::cache::file_handler::FileHandler;
+    use crate::manager::block_cache_manager::BlockCacheDevice;
+    use crate::manager::mkfs::MkfsOptions;
+
+    struct Counting(RamDevice, AtomicUsize);
+    impl BlockDevice for Counting {
+        fn id(&self) -> usize { self.0.id() }
+        fn read(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.read(block, buf)
+        }
+        fn read_blocks(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.read_blocks(block, buf)
+        }
+        fn write(&self, block: usize, data: &[u8]) -> io::Result<()> { self.0.write(block, data) }
+        fn sync(&self) -> io::Result<()> { self.0.sync() }
+        fn blocks(&self) -> io::Result<usize> { self.0.blocks() }
+    }
+
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let content: Vec<u8> = (0..64 * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE) as u8).collect();
+    let file = fs.create_file("file", &content).unwrap();
+    drop(fs);
+
+    let device = Arc::new(Counting(RamDevice::from_snapshot(ram.snapshot()).unwrap(), AtomicUsize::new(0)));
+    let mut fs = BlockCacheDevice::open(device.clone()).unwrap();
+    let mut fh = FileHandler::new(file, &mut fs, 0, 0).unwrap();
+    let before = device.1.load(Ordering::Relaxed);
+    let mut read = Vec::new();
+    let mut buf = [0u8; BLOCK_SIZE];
+    while read.len() < content.len() {
+        let n = fh.read(&mut fs, &mut buf).unwrap();
+        read.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(read, content);
+    // 窗口 4、8、16、32 ... 远少于逐块读取的 64 次
+    assert!(device.1.load(Ordering::Relaxed) - before < 10);
+}
@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::{debug, error};
+
+use crate::block_device::block_device::BlockDevice;
+use crate::cache::block_cache::CacheBlock;
+use crate::manager::stats::{count, Counters};
+
+/// 写回策略
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum WritePolicy {
+    /// 每次修改后立即写回
+    WriteThrough,
+    /// 脏块定期写回,脏块比例超过阈值时提前写回
+    #[default]
+    WriteBack,
+}
+
+impl FromStr for WritePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "write-through" => Ok(WritePolicy::WriteThrough),
+            "write-back" => Ok(WritePolicy::WriteBack),
+            _ => Err(format!("unknown write policy '{}', expected write-through or write-back", s)),
+        }
+    }
+}
+
+impl Display for WritePolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WritePolicy::WriteThrough => "write-through",
+            WritePolicy::WriteBack => "write-back",
+        })
+    }
+}
+
+/// 脏块列表,与后台写回线程共享
+/// CacheBlock 第一次变脏时登记,写回后移除,sync 时无需遍历整个缓存
+/// 锁顺序为 CacheBlock -> blocks,持有 blocks 时不能再锁 CacheBlock
+pub struct WriteBack {
+    blocks: Mutex<BTreeMap<usize, Weak<Mutex<CacheBlock>>>>,
+    write_through: bool,
+    // 启用日志时脏块只能随日志提交写回
+    journaling: AtomicBool,
+    stop: Mutex<bool>,
+    wake: Condvar,
+    counters: Arc<Counters>,
+}
+
+impl WriteBack {
+    pub(crate) fn new(policy: WritePolicy, counters: Arc<Counters>) -> Self {
+        Self {
+            blocks: Mutex::new(BTreeMap::new()),
+            write_through: policy == WritePolicy::WriteThrough,
+            journaling: AtomicBool::new(false),
+            stop: Mutex::new(false),
+            wake: Condvar::new(),
+            counters,
+        }
+    }
+
+    /// 读取块并登记到本列表
+    pub(crate) fn cache(self: &Arc<Self>, device: Arc<dyn BlockDevice>, block: usize) -> io::Result<Arc<Mutex<CacheBlock>>> {
+        Ok(self.track(CacheBlock::new(device, block)?))
+    }
+
+    pub(crate) fn track(self: &Arc<Self>, cache: CacheBlock) -> Arc<Mutex<CacheBlock>> {
+        Arc::new_cyclic(|this| Mutex::new(cache.tracked(self.clone(), this.clone())))
+    }
+
+    pub(crate) fn mark(&self, block: usize, cache: Weak<Mutex<CacheBlock>>) {
+        self.blocks.lock().unwrap().insert(block, cache);
+    }
+
+    /// 记录写回设备的脏块数
+    pub(crate) fn written(&self, n: usize) {
+        count(&self.counters.write_backs, n as u64);
+    }
+
+    pub(crate) fn clean(&self, block: usize) {
+        self.blocks.lock().unwrap().remove(&block);
+    }
+
+    /// 脏块数量
+    pub fn len(&self) -> usize {
+        self.blocks.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 按块号排列的脏块,已释放的块直接移除
+    pub(crate) fn blocks(&self) -> Vec<Arc<Mutex<CacheBlock>>> {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.retain(|_, c| c.strong_count() > 0);
+        blocks.values().filter_map(|c| c.upgrade()).collect()
+    }
+
+    pub fn is_write_through(&self) -> bool {
+        self.write_through && !self.is_journaling()
+    }
+
+    pub fn is_journaling(&self) -> bool {
+        self.journaling.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_journaling(&self, journaling: bool) {
+        self.journaling.store(journaling, Ordering::Relaxed);
+    }
+
+    /// 按块号顺序写回所有脏块,连续的块合并为一次写入,返回写回的块数
+    /// 按块号升序加锁,同时持有多个块的锁也不会死锁
+    pub fn flush(&self, device: &dyn BlockDevice) -> io::Result<usize> {
+        let blocks = self.blocks();
+        let mut run: Vec<MutexGuard<CacheBlock>> = Vec::new();
+        let mut count = 0;
+        for c in blocks.iter() {
+            let cache = c.lock().unwrap();
+            if !cache.is_dirty() {
+                continue;
+            }
+            if run.last().is_some_and(|v| v.block() + 1 != cache.block()) {
+                count += self.write_run(device, &mut run)?;
+            }
+            run.push(cache);
+        }
+        count += self.write_run(device, &mut run)?;
+        device.sync()?;
+        Ok(count)
+    }
+
+    /// 后台写回线程,每隔 interval 写回一次,直到 stop
+    pub(crate) fn run(&self, device: Arc<dyn BlockDevice>, interval: Duration) {
+        loop {
+            let stop = self.stop.lock().unwrap();
+            let (stop, _) = self.wake.wait_timeout_while(stop, interval, |stop| !*stop).unwrap();
+            if *stop {
+                break;
+            }
+            drop(stop);
+            if self.is_journaling() {
+                continue;
+            }
+            match self.flush(&*device) {
+                Ok(0) => {}
+                Ok(n) => debug!("Write back {} dirty blocks", n),
+                Err(e) => error!("Background write back failed: {}", e),
+            }
+        }
+    }
+
+    pub(crate) fn stop(&self) {
+        *self.stop.lock().unwrap() = true;
+        self.wake.notify_all();
+    }
+
+    fn write_run(&self, device: &dyn BlockDevice, run: &mut Vec<MutexGuard<CacheBlock>>) -> io::Result<usize> {
+        let Some(first) = run.first() else { return Ok(0) };
+        let data: Vec<u8> = run.iter().flat_map(|v| v.data().iter().copied()).collect();
+        device.write_blocks(first.block(), &data)?;
+        let count = run.len();
+        for mut cache in run.drain(..) {
+            cache.mark_clean();
+        }
+        self.written(count);
+        Ok(count)
+    }
+}
+
+#[test]
+fn write_back_policies() {
+    use crate::block_device::ram_device::RamDevice;
+    use crate::cache::options::CacheOptions;
+    use crate::config::BLOCK_SIZE;
+    use crate::layout::journal::JournalMode;
+    use crate::manager::block_cache_manager::BlockCacheDevice;
+    use crate::manager::mkfs::MkfsOptions;
+
+    let mkfs = |options: CacheOptions| {
+        let (ram, fs) = BlockCacheDevice::test(&MkfsOptions::new().journal(JournalMode::None));
+        drop(fs);
+        let fs = BlockCacheDevice::open_with_cache(ram.clone(), options).unwrap();
+        (ram, fs)
+    };
+    let on_disk = |ram: &RamDevice, fs: &BlockCacheDevice, id: usize| {
+        let mut buf = [0u8; BLOCK_SIZE];
+        ram.read(fs.data_block(id), &mut buf).unwrap();
+        buf[0]
+    };
+
+    // write-back: 脏块超过容量的 25% 或 sync 时写回
+    let (ram, mut fs) = mkfs(CacheOptions::new().blocks(16).dirty_ratio(25));
+    fs.modify_data(100, |data| data[0] = 1).unwrap();
+    assert_eq!(fs.write_back.len(), 1);
+    assert_eq!(on_disk(&ram, &fs, 100), 0);
+    for id in 101..110 {
+        fs.modify_data(id, |data| data[0] = 1).unwrap();
+    }
+    assert!(fs.write_back.len() <= 5);
+    assert_eq!(on_disk(&ram, &fs, 100), 1);
+    fs.sync().unwrap();
+    assert!(fs.write_back.is_empty());
+    assert_eq!(on_disk(&ram, &fs, 109), 1);
+
+    // 后台线程定期写回
+    let (ram, mut fs) = mkfs(CacheOptions::new().flush_interval(Duration::from_millis(20)));
+    fs.start_flusher().unwrap();
+    fs.modify_data(100, |data| data[0] = 2).unwrap();
+    for _ in 0..100 {
+        if fs.write_back.is_empty() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(on_disk(&ram, &fs, 100), 2);
+
+    let (ram, mut fs) = mkfs(CacheOptions::new().write_policy(WritePolicy::WriteThrough));
+    fs.modify_data(100, |data| data[0] = 3).unwrap();
+    assert!(fs.write_back.is_empty());
+    assert_eq!(on_disk(&ram, &fs, 100), 3);
+}
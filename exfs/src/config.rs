@@ -1,2 +1,11 @@
 // 块大小：4KB
-pub(crate) const BLOCK_SIZE: usize = 4096;
\ No newline at end of file
+pub const BLOCK_SIZE: usize = 4096;
+// 块缓存容量，被引用的块不会被淘汰，缓存可暂时超出该容量
+pub const CACHE_BLOCKS: usize = 128;
+
+// 顺序读预读窗口上限(块)
+pub const READAHEAD_BLOCKS: usize = 32;
+
+// inode 缓存与目录项缓存的条目数
+pub const INODE_CACHE: usize = 1024;
+pub const DENTRY_CACHE: usize = 4096;
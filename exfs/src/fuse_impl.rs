@@ -3,18 +3,40 @@ use std::io::SeekFrom;
 use std::path::Path;
 use std::time::{Duration, SystemTime};
 
-use fuser::{FileAttr, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, ReplyXattr, Request, TimeOrNow};
-use libc::ENOTSUP;
+use fuser::{FileAttr, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow};
+use libc::{c_int, ENOTSUP, EROFS};
+use log::{error, warn};
 
 use crate::config::BLOCK_SIZE;
 use crate::layout::inode::InodeWithId;
+use crate::layout::super_block::FsState;
 use crate::manager::block_cache_manager::BlockCacheDevice;
-use crate::manager::error_code::EBADF;
+use crate::manager::error_code::{EBADF, io_error};
+use crate::typ::file_name::FILE_NAME_LEN;
 use crate::typ::file_type::FileType;
 use crate::typ::request::Req;
 use crate::utils::time::system_time_from_time;
 
 impl Filesystem for BlockCacheDevice {
+    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
+        self.check_geometry()?;
+        let unknown = self.super_block().unknown_ro_compat();
+        if unknown != 0 && !self.read_only {
+            error!("Unsupported read-only compatible features {:#x}, mount read-only", unknown);
+            return Err(EROFS);
+        }
+        if self.mount()? == FsState::Dirty {
+            warn!("File system was not cleanly unmounted");
+        }
+        self.start_flusher().map_err(io_error)
+    }
+
+    fn destroy(&mut self, _req: &Request<'_>) {
+        if let Err(e) = self.unmount() {
+            error!("Unmount failed, errno {}", e);
+        }
+    }
+
     fn lookup(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
         let ttl = Duration::new(60, 0);
         let guard = self.lookup_guard(&_req.into(), cast(_parent), _name.into());
@@ -53,7 +75,7 @@ impl Filesystem for BlockCacheDevice {
         reply: ReplyAttr,
     ) {
         let ttl = Duration::new(60, 0);
-        match self.setattr_guard(
+        match self.transaction(|fs| fs.setattr_guard(
             &_req.into(),
             _ino as usize,
             _mode,
@@ -67,7 +89,7 @@ impl Filesystem for BlockCacheDevice {
             _crtime,
             _chgtime,
             _bkuptime,
-            _flags, )
+            _flags, ))
         {
             Err(e) => reply.error(e),
             Ok(attr) => reply.attr(&ttl, &attr.into())
@@ -91,7 +113,7 @@ impl Filesystem for BlockCacheDevice {
         reply: ReplyEntry,
     ) {
         let ttl = Duration::new(60, 0);
-        match self.mknod_guard(&_req.into(), _parent as usize, _name.into(), _mode, _umask, _rdev) {
+        match self.transaction(|fs| fs.mknod_guard(&_req.into(), _parent as usize, _name.into(), _mode, _umask, _rdev)) {
             Err(e) => reply.error(e),
             Ok(buf) => reply.entry(&ttl, &buf.into(), 0)
         }
@@ -106,21 +128,21 @@ impl Filesystem for BlockCacheDevice {
         reply: ReplyEntry,
     ) {
         let ttl = Duration::new(60, 0);
-        match self.mkdir_guard(&_req.into(), _parent as usize, _name.into(), _mode, _umask) {
+        match self.transaction(|fs| fs.mkdir_guard(&_req.into(), _parent as usize, _name.into(), _mode, _umask)) {
             Err(e) => reply.error(e),
             Ok(buf) => reply.entry(&ttl, &buf.into(), 0)
         }
     }
 
     fn unlink(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        match self.unlink_guard(&_req.into(), _parent as usize, _name.into()) {
+        match self.transaction(|fs| fs.unlink_guard(&_req.into(), _parent as usize, _name.into())) {
             Err(e) => reply.error(e),
             Ok(_) => reply.ok()
         }
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        match self.rmdir_guard(&_req.into(), _parent as usize, _name.into()) {
+        match self.transaction(|fs| fs.rmdir_guard(&_req.into(), _parent as usize, _name.into())) {
             Ok(_) => {
                 reply.ok()
             }
@@ -139,7 +161,7 @@ impl Filesystem for BlockCacheDevice {
         reply: ReplyEntry,
     ) {
         let ttl = Duration::new(60, 0);
-        match self.symlink_guard(&_req.into(), _parent as usize, _name.into(), _link) {
+        match self.transaction(|fs| fs.symlink_guard(&_req.into(), _parent as usize, _name.into(), _link)) {
             Err(e) => reply.error(e),
             Ok(buf) => reply.entry(&ttl, &buf.into(), 0)
         }
@@ -155,7 +177,7 @@ impl Filesystem for BlockCacheDevice {
         _flags: u32,
         reply: ReplyEmpty,
     ) {
-        match self.move_guard(&_req.into(), _parent as usize, _name.into(), _newparent as usize, _newname.into(), _flags) {
+        match self.transaction(|fs| fs.move_guard(&_req.into(), _parent as usize, _name.into(), _newparent as usize, _newname.into(), _flags)) {
             Err(e) => reply.error(e),
             Ok(_) => reply.ok()
         }
@@ -170,14 +192,14 @@ impl Filesystem for BlockCacheDevice {
         reply: ReplyEntry,
     ) {
         let ttl = Duration::new(60, 0);
-        match self.link_guard(&_req.into(), _ino as usize, _newparent as usize, _newname.into()) {
+        match self.transaction(|fs| fs.link_guard(&_req.into(), _ino as usize, _newparent as usize, _newname.into())) {
             Err(e) => reply.error(e),
             Ok(buf) => reply.entry(&ttl, &buf.into(), 0)
         }
     }
 
     fn open(&mut self, _req: &Request, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        match self.open_guard(&_req.into(), _ino as usize, _flags) {
+        match self.transaction(|fs| fs.open_guard(&_req.into(), _ino as usize, _flags)) {
             Err(e) => reply.error(e),
             Ok(fh) => reply.opened(fh as u64, _flags as u32)
         }
@@ -217,14 +239,14 @@ impl Filesystem for BlockCacheDevice {
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        match self.write_guard(&_req.into(), _fh as u32, SeekFrom::Start(_offset as u64), _data) {
+        match self.write_transactions(&_req.into(), _fh as u32, _offset as u64, _data) {
             Err(e) => reply.error(e),
             Ok(len) => reply.written(len as u32)
         }
     }
 
     fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        match self.flush_guard(&_req.into(), _fh as u32) {
+        match self.transaction(|fs| fs.flush_guard(&_req.into(), _fh as u32)) {
             Err(e) => reply.error(e),
             Ok(_) => reply.ok()
         }
@@ -240,7 +262,7 @@ impl Filesystem for BlockCacheDevice {
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        match self.release_guard(&_req.into(), _fh as u32, _flush) {
+        match self.transaction(|fs| fs.release_guard(&_req.into(), _fh as u32, _flush)) {
             Err(e) => reply.error(e),
             Ok(_) => reply.ok()
         }
@@ -294,6 +316,22 @@ impl Filesystem for BlockCacheDevice {
         }
     }
 
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        match self.statfs_internal() {
+            Err(e) => reply.error(e),
+            Ok(st) => reply.statfs(
+                st.blocks as u64,
+                st.free_blocks as u64,
+                st.available_blocks as u64,
+                st.inodes as u64,
+                st.free_inodes as u64,
+                BLOCK_SIZE as u32,
+                FILE_NAME_LEN as u32,
+                BLOCK_SIZE as u32,
+            ),
+        }
+    }
+
     fn access(&mut self, _req: &Request, _ino: u64, _mask: i32, reply: ReplyEmpty) {
         match self.access_guard(&_req.into(), _ino as usize, _mask) {
             Err(e) => reply.error(e),
@@ -313,7 +351,7 @@ impl Filesystem for BlockCacheDevice {
     ) {
         let req = _req.into();
         let ttl = Duration::new(60, 0);
-        match self.create_guard(&req, _parent as usize, _name.into(), _mode, _umask, _flags) {
+        match self.transaction(|fs| fs.create_guard(&req, _parent as usize, _name.into(), _mode, _umask, _flags)) {
             Err(e) => reply.error(e),
             Ok(fh) => {
                 match self.fh(fh, req.pid) {
@@ -1,71 +1,65 @@
-use std::ops::Range;
-
 use log::debug;
 
 use crate::config::BLOCK_SIZE;
 use crate::layout::inode::{Inode, INODE_SIZE};
+use crate::layout::journal::JournalMode;
 use crate::layout::super_block::SuperBlock;
 use crate::manager::block_cache_manager::BlockCacheDevice;
+use crate::manager::error_code::ErrorCode;
+use crate::manager::stats::count;
 
 impl BlockCacheDevice {
-    /// @return Option<usize> data_block_id
-    /// 返回逻辑地址
-    pub fn alloc_block(&mut self, is_inode: bool) -> Option<usize> {
-        let super_block = self.super_block();
-        let size = if is_inode { super_block.inode_size() } else { super_block.data_blocks };
-        for index in 0..size {
-            if !self.used(index, is_inode) {
-                self.set(index, is_inode, true);
-                let id = if is_inode { index + 1 } else { index };
-                debug!("[Alloc{}] {}", if is_inode { "Inode" } else { "Data" }, id);
-                return Some(id);
-            }
-        };
-        None
+    /// @return usize data_block_id
+    /// 返回逻辑地址,空间不足时返回 ENOSPC
+    pub fn alloc_block(&mut self, is_inode: bool) -> Result<usize, ErrorCode> {
+        if is_inode {
+            self.alloc_inode(None)
+        } else {
+            Ok(self.alloc_extent(1, None)?.start)
+        }
     }
 
-    pub fn free_block(&mut self, id: usize, is_inode: bool, free_block: bool) {
+    pub fn free_block(&mut self, id: usize, is_inode: bool, free_block: bool) -> Result<(), ErrorCode> {
         debug!("free block: {}, is_inode:{}", id, is_inode);
         let index = if is_inode { id - 1 } else { id };
-        if self.used(index, is_inode) {
-            self.set(index, is_inode, false);
+        if self.used(index, is_inode)? {
+            self.set(index, is_inode, false)?;
+            count(if is_inode { &self.counters.inode_frees } else { &self.counters.data_frees }, 1);
+            if is_inode {
+                // inode 号可能被重新分配,其目录项也一并失效
+                self.inodes.remove(id);
+                self.dentries.remove_dir(id);
+                self.allocator.forget(id);
+            }
             if free_block {
                 // 对物理块清理
                 if is_inode {
                     let (blk_id, offset) = self.inode_block(id);
                     debug!("free inode block: {}, offset:{}", blk_id, offset);
-                    self.block_cache(blk_id)
+                    self.block_cache(blk_id)?
                         .lock()
                         .unwrap()
                         .modify(offset, |ino: &mut Inode| {
                             *ino = Inode::nil()
                         });
-                } else {
+                } else if self.journal_mode() == JournalMode::None {
+                    // 启用日志时不清零,分配时会清零,避免删除大文件产生巨大的事务
                     let blk_id = self.data_block(id);
-                    self.block_cache(blk_id)
+                    self.block_cache(blk_id)?
                         .lock()
                         .unwrap()
                         .free()
                 }
             }
-            return;
+            return Ok(());
         }
         // 重复释放
         debug!(
             "Try to release the free {} block({})!",
             if is_inode { "Inode" } else { "Data" },
             id
-        )
-    }
-
-    pub fn bitmap_range(&self, is_inode: bool) -> Range<usize> {
-        let super_block = self.super_block();
-        if is_inode {
-            1..1 + super_block.inode_bitmap_blocks
-        } else {
-            1 + super_block.inode_bitmap_blocks
-                ..1 + super_block.inode_bitmap_blocks + super_block.bitmap_blocks
-        }
+        );
+        Ok(())
     }
 
     pub fn super_block(&self) -> SuperBlock {
@@ -79,7 +73,6 @@ impl BlockCacheDevice {
 
     /// @return (blk_id usize, bytes_offset:usize, bit_offset:usize)
     fn bitmap_offset(&self, index: usize, is_inode: bool) -> (usize, usize, usize) {
-        let range = self.bitmap_range(is_inode);
         let super_block = self.super_block();
         if is_inode {
             if index > super_block.inode_size() {
@@ -90,49 +83,81 @@ impl BlockCacheDevice {
                 panic!("out of data blocks bit range");
             }
         }
-        let blk_id = index / (BLOCK_SIZE * 8);
-        if blk_id > range.len() {
-            // out of bounds
-            panic!("out of bounds");
-        }
-        let blk_offset = index % (BLOCK_SIZE * 8);
+        let (blk_id, blk_offset) = super_block.bitmap_bit(index, is_inode);
         let blk_bytes_offset = blk_offset / 8;
         let blk_bit_offset = blk_offset % 8;
-        (blk_id + range.start, blk_bytes_offset, blk_bit_offset)
+        (blk_id, blk_bytes_offset, blk_bit_offset)
     }
 
-    pub fn used(&mut self, index: usize, is_inode: bool) -> bool {
+    pub fn used(&mut self, index: usize, is_inode: bool) -> Result<bool, ErrorCode> {
         let (blk_id, bytes_offset, bit_offset) = self.bitmap_offset(index, is_inode);
         let mut check = false;
-        self.block_cache(blk_id)
+        self.block_cache(blk_id)?
             .lock()
             .unwrap()
             .read(bytes_offset, |byte: &u8| {
                 let mask = 1 << bit_offset;
                 check = byte & mask > 0;
             });
-        check
+        Ok(check)
+    }
+
+    /// 位图中已标记的数量,只统计 inode 表或数据区实际存在的部分
+    pub fn used_count(&mut self, is_inode: bool) -> Result<usize, ErrorCode> {
+        let mut used = 0;
+        for bitmap in self.super_block().bitmaps(is_inode) {
+            used += self.bitmap_block_used(bitmap)?;
+        }
+        Ok(used)
+    }
+
+    /// 位图块前 bits 位中已标记的数量
+    pub(crate) fn bitmap_block_used(&mut self, (blk_id, bits): (usize, usize)) -> Result<usize, ErrorCode> {
+        let mut used = 0;
+        self.block_cache(blk_id)?
+            .lock()
+            .unwrap()
+            .read(0, |bytes: &[u8; BLOCK_SIZE]| {
+                used += bytes[..bits / 8].iter().map(|v| v.count_ones() as usize).sum::<usize>();
+                if bits % 8 != 0 {
+                    used += (bytes[bits / 8] & ((1 << (bits % 8)) - 1)).count_ones() as usize;
+                }
+            });
+        Ok(used)
     }
 
-    fn set(&mut self, id: usize, is_inode: bool, v: bool) {
+    pub(crate) fn set(&mut self, id: usize, is_inode: bool, v: bool) -> Result<(), ErrorCode> {
+        self.mark(id, is_inode, v)?;
+        self.sync_free_extents(id, is_inode, v);
+        Ok(())
+    }
+
+    /// 只修改位图与块组计数,由调用者维护空闲区间索引
+    pub(crate) fn mark(&mut self, id: usize, is_inode: bool, v: bool) -> Result<(), ErrorCode> {
         let (blk_id, bytes_offset, bit_offset) = self.bitmap_offset(id, is_inode);
-        self.block_cache(blk_id)
+        let changed = self.block_cache(blk_id)?
             .lock()
             .unwrap()
             .modify(bytes_offset, |byte: &mut u8| {
+                let old = *byte;
                 if v {
                     *byte |= 1 << bit_offset
                 } else {
                     *byte &= !(1 << bit_offset)
                 }
+                old != *byte
             });
+        if changed {
+            self.count_group(id, is_inode, v)?;
+        }
+        Ok(())
     }
 
-    pub fn clear_bitmap(&mut self) {
+    pub fn clear_bitmap(&mut self) -> Result<(), ErrorCode> {
         let super_block = self.super_block();
-        let bitmap_blocks = super_block.bitmap_blocks + super_block.inode_bitmap_blocks;
-        for blk_id in 0..bitmap_blocks {
-            self.block_cache(blk_id + 1)
+        let bitmaps = super_block.bitmaps(true).into_iter().chain(super_block.bitmaps(false));
+        for (blk_id, _) in bitmaps {
+            self.block_cache(blk_id)?
                 .lock()
                 .unwrap()
                 .modify(0, |bytes: &mut [u8; BLOCK_SIZE]| {
@@ -141,18 +166,20 @@ impl BlockCacheDevice {
                     })
                 })
         }
+        self.allocator.reset();
+        self.fix_groups()
     }
 
     /// 打印当前已经分配的块
     /// 仅作调试使用
-    pub fn print(&mut self) {
+    pub fn print(&mut self) -> Result<(), ErrorCode> {
         let super_block = self.super_block();
         println!("Super Blocks: {:?}", super_block);
         println!("Inode Size: {}",INODE_SIZE);
         let size = super_block.inode_size();
         let mut used = Vec::new();
         for id in 0..size {
-            if self.used(id, true) {
+            if self.used(id, true)? {
                 used.push(id + 1)
             }
         };
@@ -160,10 +187,11 @@ impl BlockCacheDevice {
         let size = super_block.data_blocks;
         let mut used = Vec::new();
         for id in 0..size {
-            if self.used(id, false) {
+            if self.used(id, false)? {
                 used.push(id)
             }
         };
         debug!("Used Data Blocks: {:?}", used);
+        Ok(())
     }
 }
@@ -2,8 +2,11 @@ use std::mem::size_of;
 
 use crate::config::BLOCK_SIZE;
 use crate::typ::file_name::FileName;
+use crate::utils::crc32c::crc32c;
+use crate::utils::slice::slice;
 
 pub type DataBlock = [u8; BLOCK_SIZE];
+pub type DirBlock = [DirEntry; BLOCK_SIZE / DIR_ENTRY_SIZE];
 
 /// 目录项 64 字节
 /// name: 文件名, 56字节以内
@@ -24,3 +27,32 @@ impl DirEntry {
 }
 
 pub const DIR_ENTRY_SIZE: usize = size_of::<DirEntry>();
+/// 目录块的最后一项文件名为空,inode 字段保存之前所有目录项的校验和
+pub const DIR_ENTRIES_PER_BLOCK: usize = BLOCK_SIZE / DIR_ENTRY_SIZE - 1;
+
+impl DirEntry {
+    pub fn empty() -> Self {
+        Self { name: [0u8; 56].into(), inode: 0 }
+    }
+
+    /// 按块排列目录项,每块末尾写入校验和
+    pub fn pack(entries: &[DirEntry]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for chunk in entries.chunks(DIR_ENTRIES_PER_BLOCK) {
+            let mut block = [DirEntry::empty(); BLOCK_SIZE / DIR_ENTRY_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            block[DIR_ENTRIES_PER_BLOCK].inode = dir_block_crc(&block) as u64;
+            buf.extend_from_slice(slice(&block));
+        }
+        buf
+    }
+}
+
+fn dir_block_crc(block: &DirBlock) -> u32 {
+    crc32c(&slice(block)[..DIR_ENTRIES_PER_BLOCK * DIR_ENTRY_SIZE])
+}
+
+pub fn dir_block_ok(block: &DirBlock) -> bool {
+    let tail = &block[DIR_ENTRIES_PER_BLOCK];
+    tail.name.is_empty() && tail.inode == dir_block_crc(block) as u64
+}
@@ -0,0 +1,260 @@
+use std::mem::size_of;
+
+use crate::config::BLOCK_SIZE;
+use crate::layout::super_block::SuperBlock;
+use crate::manager::block_cache_manager::BlockCacheDevice;
+use crate::manager::error_code::ErrorCode;
+
+/// 块组描述符,组描述符表位于超级块之后
+/// 位置可由超级块算出,记录在此便于检查;空闲计数随位图修改
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct GroupDesc {
+    pub data_bitmap: usize,
+    pub inode_bitmap: usize,
+    pub inode_table: usize,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+}
+
+pub const GROUP_DESC_SIZE: usize = size_of::<GroupDesc>();
+pub const GROUP_DESCS_PER_BLOCK: usize = BLOCK_SIZE / GROUP_DESC_SIZE;
+
+impl SuperBlock {
+    /// 组描述符所在的物理块与偏移量
+    pub fn group_desc_block(&self, group: usize) -> (usize, usize) {
+        (1 + group / GROUP_DESCS_PER_BLOCK, (group % GROUP_DESCS_PER_BLOCK) * GROUP_DESC_SIZE)
+    }
+
+    /// 空白块组的描述符,所有数据块与 inode 均空闲
+    pub fn empty_group(&self, group: usize) -> GroupDesc {
+        let start = self.group(group).start;
+        let bitmaps = self.bitmaps(false);
+        GroupDesc {
+            data_bitmap: start,
+            inode_bitmap: start + 1,
+            inode_table: start + 2,
+            free_blocks: bitmaps[group].1 as u32,
+            free_inodes: self.inodes_per_group as u32,
+        }
+    }
+}
+
+/// 块组
+impl BlockCacheDevice {
+    pub fn group_desc(&mut self, group: usize) -> Result<GroupDesc, ErrorCode> {
+        let (blk_id, offset) = self.super_block().group_desc_block(group);
+        Ok(self.block_cache(blk_id)?.lock().unwrap().read(offset, |desc: &GroupDesc| *desc))
+    }
+
+    pub(crate) fn modify_group_desc<V>(&mut self, group: usize, f: impl FnOnce(&mut GroupDesc) -> V) -> Result<V, ErrorCode> {
+        let (blk_id, offset) = self.super_block().group_desc_block(group);
+        Ok(self.block_cache(blk_id)?.lock().unwrap().modify(offset, f))
+    }
+
+    /// mkfs 时写入所有组描述符
+    pub(crate) fn init_groups(&mut self, super_block: &SuperBlock) -> Result<(), ErrorCode> {
+        for group in 0..super_block.groups() {
+            let desc = super_block.empty_group(group);
+            self.modify_group_desc(group, |v| *v = desc)?;
+        }
+        Ok(())
+    }
+
+    /// 位图中 index 被标记或清除后更新所在块组的空闲计数
+    pub(crate) fn count_group(&mut self, index: usize, is_inode: bool, used: bool) -> Result<(), ErrorCode> {
+        let super_block = self.super_block();
+        if !super_block.grouped() {
+            return Ok(());
+        }
+        let group = super_block.group_of(index, is_inode);
+        self.modify_group_desc(group, |desc| {
+            let free = if is_inode { &mut desc.free_inodes } else { &mut desc.free_blocks };
+            *free = if used { free.saturating_sub(1) } else { *free + 1 };
+        })
+    }
+
+    /// 新目录的 inode 位置,选择空闲 inode 最多的块组,相同时选择空闲数据块多的,使目录分散到各组
+    /// 返回 alloc_inode 使用的 hint,未启用块组时返回 None
+    pub(crate) fn dir_inode_hint(&mut self) -> Result<Option<usize>, ErrorCode> {
+        let super_block = self.super_block();
+        let mut best: Option<(usize, (u32, u32))> = None;
+        for group in 0..super_block.groups() {
+            let desc = self.group_desc(group)?;
+            let free = (desc.free_inodes, desc.free_blocks);
+            if desc.free_inodes > 0 && best.is_none_or(|(_, v)| free > v) {
+                best = Some((group, free));
+            }
+        }
+        Ok(best.map(|(group, _)| group * super_block.inodes_per_group))
+    }
+
+    /// 还没有数据的文件从其 inode 所在块组的数据区开始分配
+    pub(crate) fn group_data_hint(&self, ino: usize) -> Option<usize> {
+        let super_block = self.super_block();
+        super_block.grouped().then(|| super_block.group_of(ino - 1, true) * super_block.data_per_group())
+    }
+
+    /// 按位图统计每组的空闲数据块与空闲 inode
+    pub fn group_usage(&mut self) -> Result<Vec<(u32, u32)>, ErrorCode> {
+        let super_block = self.super_block();
+        let mut usage = Vec::new();
+        if !super_block.grouped() {
+            return Ok(usage);
+        }
+        let (data, inodes) = (super_block.bitmaps(false), super_block.bitmaps(true));
+        for (data, inodes) in data.into_iter().zip(inodes) {
+            let free_blocks = data.1 - self.bitmap_block_used(data)?;
+            let free_inodes = inodes.1 - self.bitmap_block_used(inodes)?;
+            usage.push((free_blocks as u32, free_inodes as u32));
+        }
+        Ok(usage)
+    }
+
+    /// 位置或空闲计数与超级块、位图不符的块组
+    pub fn bad_groups(&mut self) -> Result<Vec<usize>, ErrorCode> {
+        let mut bad = Vec::new();
+        for (group, desc) in self.expected_groups()?.into_iter().enumerate() {
+            if self.group_desc(group)? != desc {
+                bad.push(group);
+            }
+        }
+        Ok(bad)
+    }
+
+    /// 按超级块与位图重写组描述符
+    pub(crate) fn fix_groups(&mut self) -> Result<(), ErrorCode> {
+        for (group, desc) in self.expected_groups()?.into_iter().enumerate() {
+            self.modify_group_desc(group, |v| *v = desc)?;
+        }
+        Ok(())
+    }
+
+    fn expected_groups(&mut self) -> Result<Vec<GroupDesc>, ErrorCode> {
+        let super_block = self.super_block();
+        Ok(self.group_usage()?
+            .into_iter()
+            .enumerate()
+            .map(|(group, (free_blocks, free_inodes))| GroupDesc {
+                free_blocks,
+                free_inodes,
+                ..super_block.empty_group(group)
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn block_groups() {
+    use std::collections::BTreeSet;
+
+    use crate::manager::fsck::{Problem, ROOT_INODE};
+    use crate::manager::mkfs::MkfsOptions;
+    use crate::typ::file_type::FileType;
+
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new().blocks(4096).block_groups(1024));
+    let sb = fs.super_block();
+    assert!(sb.grouped());
+    assert_eq!(sb.groups(), 4);
+    fs.super_block().validate(4096).unwrap();
+    // 各区域按顺序相接
+    let regions = sb.regions();
+    assert!(regions.windows(2).all(|v| v[0].1.end == v[1].1.start), "{:?}", regions);
+    assert_eq!(regions.last().unwrap().1.end, sb.blocks());
+    // 数据块与 inode 映射到互不重叠的物理位置
+    let mut blocks = BTreeSet::new();
+    for id in 0..sb.data_blocks {
+        let blk = sb.data_block(id);
+        assert!(!sb.is_metadata(blk) && blocks.insert(blk));
+        assert_eq!(sb.data_id(blk), Some(id));
+    }
+    let inode_blocks: BTreeSet<usize> = (1..=sb.inode_size()).map(|id| sb.inode_block(id).0).collect();
+    assert_eq!(inode_blocks.len(), sb.inode_blocks);
+    assert!(inode_blocks.iter().all(|blk| sb.is_metadata(*blk) && !blocks.contains(blk)));
+
+    // 新目录分散到其他块组,其中文件的 inode 与数据留在同一组
+    let root = fs.inode(ROOT_INODE).unwrap().with_id(ROOT_INODE);
+    let a = fs.make_node_internal("a", &root, FileType::Dir << 12 | 0o755, 0, 0).unwrap();
+    let root = fs.inode(ROOT_INODE).unwrap().with_id(ROOT_INODE);
+    let b = fs.make_node_internal("b", &root, FileType::Dir << 12 | 0o755, 0, 0).unwrap();
+    let (group_a, group_b) = (sb.group_of(a - 1, true), sb.group_of(b - 1, true));
+    assert!(group_a != 0 && group_b != 0 && group_a != group_b);
+    let parent = fs.inode(a).unwrap().with_id(a);
+    let file = fs.make_node_internal("file", &parent, FileType::File << 12 | 0o644, 0, 0).unwrap();
+    let inode = fs.inode(file).unwrap().with_id(file);
+    fs.write_system(0, &inode, &[1u8; 4 * BLOCK_SIZE], false).unwrap();
+    assert_eq!(sb.group_of(file - 1, true), group_a);
+    let inode = fs.inode(file).unwrap();
+    assert!(fs.inode_data_blk_list(&inode).unwrap().iter().all(|id| sb.group_of(*id, false) == group_a));
+    let desc = fs.group_desc(group_a).unwrap();
+    assert_eq!(desc.free_inodes as usize, sb.inodes_per_group - 2);
+    assert!(fs.bad_groups().unwrap().is_empty());
+    fs.sync().unwrap();
+    drop(fs);
+
+    // 重新打开后计数仍与位图一致,损坏的计数由 fsck 修复
+    let mut fs = BlockCacheDevice::open(ram).unwrap();
+    assert!(fs.fsck(false).unwrap().is_clean());
+    fs.modify_group_desc(group_b, |desc| desc.free_blocks = 0).unwrap();
+    let report = fs.fsck(true).unwrap();
+    assert!(report.problems.contains(&Problem::BadGroup(group_b)));
+    assert!(report.remaining.is_empty(), "{:?}", report.remaining);
+}
+
+#[test]
+fn runs_across_groups() {
+    use crate::cache::file_handler::FileHandler;
+    use crate::manager::mkfs::MkfsOptions;
+
+    // 块组只剩最后 2 个数据块,之后逻辑上连续的块跨越组边界,物理上被下一组的元数据隔开
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new().blocks(2048).block_groups(512));
+    let sb = fs.super_block();
+    let per_group = sb.data_per_group();
+    let fill = |fs: &mut BlockCacheDevice, group: usize| {
+        for id in group * per_group..(group + 1) * per_group - 2 {
+            if !fs.used(id, false).unwrap() {
+                fs.set(id, false, true).unwrap();
+            }
+        }
+    };
+    let metadata = |group: usize| sb.group(group).start..sb.group(group).start + 2 + sb.group_inode_blocks();
+    let content: Vec<u8> = (0..6 * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE + 1) as u8).collect();
+
+    // 预读不会把下一组的位图与 inode 表当作数据块读入
+    fill(&mut fs, 0);
+    let file = fs.create_file("file", &content).unwrap();
+    let inode = fs.inode(file).unwrap();
+    let ids = fs.inode_data_blk_list(&inode).unwrap();
+    assert!(sb.group_of(ids[0], false) == 0 && sb.group_of(ids[5], false) == 1, "{:?}", ids);
+    fs.sync().unwrap();
+    drop(fs);
+    let mut fs = BlockCacheDevice::open(ram.clone()).unwrap();
+    let mut fh = FileHandler::new(file, &mut fs, 0, 0).unwrap();
+    let mut read = Vec::new();
+    let mut buf = [0u8; BLOCK_SIZE];
+    while read.len() < content.len() {
+        let n = fh.read(&mut fs, &mut buf).unwrap();
+        read.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(read, content);
+    for blk in metadata(1) {
+        assert!(fs.caches.peek(&blk).is_none_or(|cache| cache.lock().unwrap().is_metadata()), "{}", blk);
+    }
+
+    // 直接写入新块时按物理地址拆分,不会覆盖下一组的元数据
+    fill(&mut fs, 1);
+    fs.sync().unwrap();
+    let image = ram.snapshot();
+    let (node, level) = fs.write_data(&content, 0).unwrap();
+    fs.sync().unwrap();
+    let ids = node.list(&mut fs, level).unwrap();
+    assert!(sb.group_of(ids[0], false) == 1 && sb.group_of(ids[5], false) == 2, "{:?}", ids);
+    let after = ram.snapshot();
+    for (i, id) in ids.iter().enumerate() {
+        let blk = sb.data_block(*id);
+        assert!(after[blk * BLOCK_SIZE..(blk + 1) * BLOCK_SIZE] == content[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE], "{}", blk);
+    }
+    let table = sb.group(2).start + 2;
+    assert!(after[table * BLOCK_SIZE..metadata(2).end * BLOCK_SIZE] == image[table * BLOCK_SIZE..metadata(2).end * BLOCK_SIZE]);
+    assert!(fs.bad_groups().unwrap().is_empty());
+}
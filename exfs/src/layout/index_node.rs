@@ -1,6 +1,10 @@
 use std::mem::size_of;
+use std::ops::Range;
 use crate::config::BLOCK_SIZE;
 use crate::manager::block_cache_manager::BlockCacheDevice;
+use crate::manager::error_code::ErrorCode;
+use crate::utils::crc32c::crc32c;
+use crate::utils::slice::slice;
 
 /// 多级索引项
 /// 8 bytes / 16 bytes
@@ -13,19 +17,28 @@ use crate::manager::block_cache_manager::BlockCacheDevice;
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default)]
 pub struct IndexNode {
-    start_blk: usize,
+    pub(crate) start_blk: usize,
     // inclusive
-    len: usize, // exclusive
+    pub(crate) len: usize, // exclusive
 }
 
 
 pub const INDEX_NODE_SIZE: usize = size_of::<IndexNode>();
+pub type IndexBlock = [IndexNode; BLOCK_SIZE / INDEX_NODE_SIZE];
+/// 索引块的最后一项 len 为 0,start_blk 保存之前所有索引项的校验和
+pub const INDEXES_PER_BLOCK: usize = BLOCK_SIZE / INDEX_NODE_SIZE - 1;
+/// 8 级索引已可表示 64ZB 的文件,超出即视为损坏
+pub const MAX_INDEX_LEVEL: u8 = 8;
 
 impl IndexNode {
     pub fn is_valid(&self) -> bool {
         self.len != 0
     }
-    pub fn list(&self, device: &mut BlockCacheDevice, level: u8) -> Vec<usize> {
+    /// 覆盖的块 id 区间,损坏的节点不会溢出
+    pub fn range(&self) -> Range<usize> {
+        self.start_blk..self.start_blk.saturating_add(self.len)
+    }
+    pub fn list(&self, device: &mut BlockCacheDevice, level: u8) -> Result<Vec<usize>, ErrorCode> {
         let mut vec = Vec::new();
         for blk_id in self.start_blk..(self.start_blk + self.len) {
             // debug!("blk_id {}", blk_id);
@@ -33,78 +46,74 @@ impl IndexNode {
                 // 一级索引直接将块 id 返回
                 vec.push(blk_id)
             } else {
-                device.block_cache(blk_id).lock().unwrap().read(
-                    0,
-                    |data: &[IndexNode; BLOCK_SIZE / INDEX_NODE_SIZE]| {
-                        data.iter().for_each(|v| {
-                            // vec.push(v.clone());
-                            v.list(device, level - 1)
-                                .iter()
-                                .for_each(|data| vec.push(data.clone()));
-                        })
-                    },
-                )
+                // 索引块 id 同样是逻辑地址
+                let data = device.index_block(blk_id)?;
+                for v in data.iter() {
+                    vec.extend(v.list(device, level - 1)?);
+                }
             }
         }
-        vec
+        Ok(vec)
     }
-    pub fn list_level_blk(&self, device: &mut BlockCacheDevice, level: u8, need: u8) -> Vec<usize> {
+    /// 返回第 need 级索引块的 id,数据块为第 0 级
+    pub fn list_level_blk(&self, device: &mut BlockCacheDevice, level: u8, need: u8) -> Result<Vec<usize>, ErrorCode> {
         let mut vec = Vec::new();
         for blk_id in self.start_blk..(self.start_blk + self.len) {
-            if level <= need + 1 {
+            if level == need + 1 {
                 // 所需级索引直接将块 id 返回
-                if blk_id != 0 {
-                    vec.push(blk_id)
+                vec.push(blk_id)
+            } else if level > need + 1 {
+                let data = device.index_block(blk_id)?;
+                for v in data.iter() {
+                    vec.extend(v.list_level_blk(device, level - 1, need)?);
                 }
-            } else {
-                device.block_cache(blk_id).lock().unwrap().read(
-                    0,
-                    |data: &[IndexNode; BLOCK_SIZE / INDEX_NODE_SIZE]| {
-                        data.iter().for_each(|v| {
-                            // vec.push(v.clone());
-                            v.list(device, level - 1)
-                                .iter()
-                                .for_each(|data| vec.push(data.clone()));
-                        })
-                    },
-                )
             }
         }
-        vec
+        Ok(vec)
+    }
+    /// 按文件顺序返回最底层的数据块区间,途经的索引块 id 追加到 index_blocks
+    pub fn extents(&self, device: &mut BlockCacheDevice, level: u8, index_blocks: &mut Vec<usize>) -> Result<Vec<IndexNode>, ErrorCode> {
+        if level <= 1 {
+            return Ok(if self.is_valid() { vec![*self] } else { Vec::new() });
+        }
+        let mut vec = Vec::new();
+        for blk_id in self.range() {
+            index_blocks.push(blk_id);
+            let data = device.index_block(blk_id)?;
+            for v in data.iter().filter(|v| v.is_valid()) {
+                vec.extend(v.extents(device, level - 1, index_blocks)?);
+            }
+        }
+        Ok(vec)
     }
     /// 删除当前索引节点以及下属索引节点
     /// keep_data: 是否保留 DataBlock
-    pub fn delete(&self, device: &mut BlockCacheDevice, level: u8, keep_data: bool) {
+    pub fn delete(&self, device: &mut BlockCacheDevice, level: u8, keep_data: bool) -> Result<(), ErrorCode> {
         for blk_id in self.start_blk..(self.start_blk + self.len) {
             // debug!("{},{}->{}",blk_id,self.start_blk,self.start_blk +self.len);
             if level <= 1 {
                 // 数据块 id
                 if !keep_data {
                     // 删除数据块
-                    device
-                        .block_cache(device.data_block(blk_id))
-                        .lock()
-                        .unwrap()
-                        .free();
-                    device.free_block(blk_id, false, true);
+                    device.free_block(blk_id, false, true)?;
                 }
             } else {
-                device
-                    .block_cache(device.data_block(blk_id))
+                let data = device.index_block(blk_id)?;
+                for v in data.iter() {
+                    if v.is_valid() {
+                        v.delete(device, level - 1, keep_data)?;
+                    }
+                }
+                device.index_block_cache(blk_id)?
                     .lock()
                     .unwrap()
-                    .modify(0, |data: &mut [IndexNode; BLOCK_SIZE / INDEX_NODE_SIZE]| {
-                        data.iter_mut().for_each(|v| {
-                            // vec.push(v.clone());
-                            if v.is_valid() {
-                                v.delete(device, level - 1, keep_data);
-                            }
-                            *v = IndexNode::default();
-                        });
+                    .modify(0, |data: &mut IndexBlock| {
+                        data.iter_mut().for_each(|v| *v = IndexNode::default());
                     });
-                device.free_block(blk_id, false, true)
+                device.free_block(blk_id, false, true)?
             }
         }
+        Ok(())
     }
 }
 
@@ -135,4 +144,25 @@ impl IndexNode {
         }
         nodes
     }
+
+    /// 按块排列索引项,每块末尾写入校验和
+    pub fn pack(nodes: &[IndexNode]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for chunk in nodes.chunks(INDEXES_PER_BLOCK) {
+            let mut block = [IndexNode::default(); BLOCK_SIZE / INDEX_NODE_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            block[INDEXES_PER_BLOCK].start_blk = index_block_crc(&block) as usize;
+            buf.extend_from_slice(slice(&block));
+        }
+        buf
+    }
+}
+
+fn index_block_crc(block: &IndexBlock) -> u32 {
+    crc32c(&slice(block)[..INDEXES_PER_BLOCK * INDEX_NODE_SIZE])
+}
+
+pub fn index_block_ok(block: &IndexBlock) -> bool {
+    let tail = &block[INDEXES_PER_BLOCK];
+    !tail.is_valid() && tail.start_blk == index_block_crc(block) as usize
 }
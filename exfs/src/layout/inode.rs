@@ -1,9 +1,12 @@
 use std::mem::size_of;
+use std::ops::Range;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::config::BLOCK_SIZE;
 use crate::layout::index_node::IndexNode;
 use crate::typ::file_type::FileType;
+use crate::utils::crc32c::crc32c_append;
+use crate::utils::slice::slice;
 
 ///
 /// Inode 文件索引节点
@@ -27,6 +30,7 @@ use crate::typ::file_type::FileType;
 pub struct Inode {
     // 1 索引等级,最小为 0,直接指向数据块,当当前等级的索引无法满足上限后将索引升一级,最高 255 级
     pub index_level: u8,
+    // 最后 4 字节为校验和
     pub extra: [u8; 9],
     pub mode: u16,
     pub link_count: u32,
@@ -88,6 +92,27 @@ impl Inode {
 }
 
 pub const INODE_SIZE: usize = size_of::<Inode>();
+const CHECKSUM: Range<usize> = 5..9;
+
+/// 校验和以 inode 号为初值,计算时 extra 中的校验和视为 0
+impl Inode {
+    fn crc(&self, id: usize) -> u32 {
+        let mut inode = *self;
+        inode.extra[CHECKSUM].fill(0);
+        crc32c_append(id as u32, slice(&inode))
+    }
+
+    /// 写入前更新校验和
+    pub fn seal(&mut self, id: usize) {
+        let crc = self.crc(id);
+        self.extra[CHECKSUM].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// 从未使用或已释放的 inode 全为 0,视为有效
+    pub fn checksum_ok(&self, id: usize) -> bool {
+        slice(self).iter().all(|v| *v == 0) || self.extra[CHECKSUM] == self.crc(id).to_le_bytes()
+    }
+}
 
 #[derive(Copy, Clone, Debug)]
 pub struct InodeWithId {
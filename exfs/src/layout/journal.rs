@@ -0,0 +1,118 @@
+use std::fmt::{Display, Formatter};
+use std::mem::size_of;
+use std::str::FromStr;
+
+use crate::config::BLOCK_SIZE;
+
+/// 日志区域
+/// | Header | Descriptor | Block ... | Descriptor | Block ... | Commit |
+/// 每次提交都从 Header 之后开始写入,写回原位置后递增 Header 中的 sequence
+/// 挂载时只重放 sequence 与 Header 相同且 Commit 校验通过的事务,其余的丢弃
+pub const JOURNAL_MAGIC: u64 = 0x4558_4653_4a4e_4c31;
+
+pub const JOURNAL_HEADER: u64 = 1;
+pub const JOURNAL_DESCRIPTOR: u64 = 2;
+pub const JOURNAL_COMMIT: u64 = 3;
+
+/// 日志最少需要 Header、Descriptor、一个块与 Commit
+pub const MIN_JOURNAL_BLOCKS: usize = 4;
+/// mkfs 创建的日志不少于该块数,一次目录操作涉及的 inode、位图、目录与索引块都能放入一个事务
+pub const MKFS_MIN_JOURNAL_BLOCKS: usize = 32;
+
+/// 每个日志块开头的公共部分
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct JournalBlock {
+    pub magic: u64,
+    pub kind: u64,
+    pub sequence: u64,
+    // Descriptor: 本块记录的块数, Commit: 事务的总块数
+    pub count: u64,
+}
+
+/// 一个 Descriptor 块可记录的目标块数量
+pub const JOURNAL_TAGS: usize = (BLOCK_SIZE - size_of::<JournalBlock>()) / size_of::<u64>();
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct JournalDescriptor {
+    pub head: JournalBlock,
+    // 紧随其后的各块应写回的物理块号
+    pub tags: [u64; JOURNAL_TAGS],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct JournalCommit {
+    pub head: JournalBlock,
+    pub checksum: u64,
+}
+
+impl JournalBlock {
+    pub fn new(kind: u64, sequence: u64, count: u64) -> Self {
+        Self { magic: JOURNAL_MAGIC, kind, sequence, count }
+    }
+    pub fn is(&self, kind: u64, sequence: u64) -> bool {
+        self.magic == JOURNAL_MAGIC && self.kind == kind && self.sequence == sequence
+    }
+}
+
+/// 一次提交最多可写入的块数,需为 Header、Descriptor 与 Commit 留出空间
+pub fn journal_capacity(journal_blocks: usize) -> usize {
+    let avail = journal_blocks.saturating_sub(2);
+    avail - avail.div_ceil(JOURNAL_TAGS + 1)
+}
+
+/// FNV-1a,覆盖目标块号与块内容
+pub fn journal_checksum(hash: u64, block: usize, data: &[u8]) -> u64 {
+    (block as u64).to_le_bytes().iter().chain(data.iter())
+        .fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+pub const JOURNAL_CHECKSUM_SEED: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// 日志模式
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum JournalMode {
+    /// 不记录日志,脏块按 LRU 淘汰顺序写回
+    None = 0,
+    /// 只记录元数据,普通文件的数据在提交前直接写回原位置
+    #[default]
+    Metadata = 1,
+    /// 所有修改都先写入日志
+    Data = 2,
+}
+
+impl From<u8> for JournalMode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => JournalMode::Metadata,
+            2 => JournalMode::Data,
+            _ => JournalMode::None,
+        }
+    }
+}
+
+impl FromStr for JournalMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(JournalMode::None),
+            "metadata" => Ok(JournalMode::Metadata),
+            "data" => Ok(JournalMode::Data),
+            _ => Err(format!("unknown journal mode '{}', expected none, metadata or data", s)),
+        }
+    }
+}
+
+impl Display for JournalMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            JournalMode::None => "none",
+            JournalMode::Metadata => "metadata",
+            JournalMode::Data => "data",
+        })
+    }
+}
@@ -1,5 +1,7 @@
 pub(crate) mod bitmap;
-pub(crate) mod inode;
-pub(crate) mod super_block;
-pub(crate) mod data_block;
-pub(crate) mod index_node;
+pub mod group;
+pub mod inode;
+pub mod super_block;
+pub mod data_block;
+pub mod index_node;
+pub mod journal;
@@ -1,20 +1,104 @@
+use std::fmt::{Display, Formatter};
+use std::mem::offset_of;
+use std::ops::Range;
+
 use crate::config::BLOCK_SIZE;
+use crate::layout::group::GROUP_DESCS_PER_BLOCK;
 use crate::layout::inode::INODE_SIZE;
+use crate::layout::journal::{JournalMode, MIN_JOURNAL_BLOCKS};
+use crate::utils::crc32c::{crc32c, crc32c_append};
+use crate::utils::slice::slice;
 
 const MAGIC: usize = 0x0aca_baca_01a7_88cc;
 
+/// 磁盘格式版本,0 为引入版本号之前的镜像,版本更高的镜像拒绝打开
+/// 版本 2 起超级块校验和同时覆盖 checksum 之后的字段
+pub const FORMAT_VERSION: u32 = 2;
+
+/// 兼容特性,不认识时仍可读写
+pub const COMPAT_JOURNAL: u32 = 1 << 0;
+pub const COMPAT_BACKUP_SUPER: u32 = 1 << 1;
+pub const COMPAT_SUPPORTED: u32 = COMPAT_JOURNAL | COMPAT_BACKUP_SUPER;
+/// 只读兼容特性,不认识时只能只读挂载
+/// 超级块、inode、索引块与目录块带有 CRC32C 校验和,读取时校验
+pub const RO_COMPAT_METADATA_CSUM: u32 = 1 << 0;
+pub const RO_COMPAT_SUPPORTED: u32 = RO_COMPAT_METADATA_CSUM;
+/// 不兼容特性,不认识时拒绝打开
+/// 位图与 inode 表按块组划分,超级块之后为组描述符表
+pub const INCOMPAT_BLOCK_GROUPS: u32 = 1 << 0;
+pub const INCOMPAT_SUPPORTED: u32 = INCOMPAT_BLOCK_GROUPS;
+
+/// 超级块备份位于从该块开始的 2 的幂次物理块,只使用落在数据区中的位置
+/// 超级块损坏时按同样的规则在设备上查找
+pub const BACKUP_START: usize = 256;
+
+/// 可能存放超级块备份的物理块
+pub fn backup_candidates(device_blocks: usize) -> impl Iterator<Item = usize> {
+    (BACKUP_START.trailing_zeros()..usize::BITS)
+        .map(|v| 1usize << v)
+        .take_while(move |v| *v < device_blocks)
+}
+
 /// 磁盘布局
-/// | SuperBlock | Bitmap | Data Blocks ->| Free Space |<- Inode Blocks |
-/// |     1块    |   n块   |      x块      |     **     |       y块      |
-///             |bm_blocks|           db_last      ib_last        ib_first
+/// | SuperBlock | Inode Bitmap | Data Bitmap | Inode Blocks | Data Blocks | Journal |
+/// |     1块    |      n块      |     m块     |      y块      |     x块     |   j块   |
+/// 启用块组时:
+/// | SuperBlock | Group Descriptors | Group 0 | Group 1 | ... | Journal |
+/// 每个块组为 | Data Bitmap | Inode Bitmap | Inode Blocks | Data Blocks |,各 1、1、y、x 块,最后一组的数据块可以较少
+/// 此时 inode_bitmap_blocks 与 bitmap_blocks 均等于块组数,inode_blocks 与 data_blocks 为所有块组之和
+/// 数据块 id 与 inode 号仍是全局连续的,按每组的数量依次落在各块组中
 #[repr(C)]
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub struct SuperBlock {
     magic: usize,
     pub inode_bitmap_blocks: usize,
     pub bitmap_blocks: usize,
     pub inode_blocks: usize, // inode 所占据的物理块的数量
     pub data_blocks: usize,
+    pub reserved_blocks: usize, // 仅 root 可使用的数据块数量
+    pub label: [u8; 16],        // 卷标
+    pub journal_blocks: usize,  // 位于末尾的日志区域,为 0 时不记录日志
+    pub journal_mode: u8,       // 挂载时默认使用的 JournalMode
+    pub state: u8,              // FsState,挂载期间为 Dirty
+    reserved: [u8; 2],          // 显式填充,使校验范围内没有未定义的字节
+    pub mount_count: u32,       // 格式化以来的挂载次数
+    pub mount_time: u64,        // 最后挂载时间(秒)
+    pub write_time: u64,        // 超级块最后写入时间(秒)
+    pub fsck_time: u64,         // 最后一次 fsck 修复时间(秒),0 表示从未检查
+    pub uuid: [u8; 16],
+    pub version: u32,
+    pub feature_compat: u32,
+    pub feature_ro_compat: u32,
+    pub feature_incompat: u32,
+    checksum: u32,              // 除自身以外所有字段的 CRC32C,版本 1 只覆盖之前的字段
+    padding: u32,
+    pub blocks_per_group: usize, // 启用块组时每组的物理块数,否则为 0
+    pub inodes_per_group: usize,
+}
+
+/// 文件系统状态,挂载时置为 Dirty,正常卸载或 fsck 修复后置为 Clean
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FsState {
+    Clean = 0,
+    Dirty = 1,
+}
+
+impl From<u8> for FsState {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => FsState::Clean,
+            _ => FsState::Dirty,
+        }
+    }
+}
+
+impl Display for FsState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FsState::Clean => "clean",
+            FsState::Dirty => "not clean",
+        })
+    }
 }
 
 impl SuperBlock {
@@ -22,40 +106,383 @@ impl SuperBlock {
     pub fn inode_size(&self) -> usize {
         self.inode_blocks * BLOCK_SIZE / INODE_SIZE
     }
-    pub fn new(blocks: usize) -> Self {
+    /// blocks: 文件系统总块数
+    /// bytes_per_inode: 每多少字节的空间分配一个 inode,需不小于 2 * INODE_SIZE
+    pub fn new(blocks: usize, bytes_per_inode: usize) -> Self {
         assert!(blocks > 10); // 设备至少有 10 个可分配的块(随意指定的一个数量确保绝大部分 fs 都大于且足够划分空间)
-        // data:n bitmap:y=(n+BS*8-1)/(BS*8) inode:n * INODE_SIZE/BS inode_bitmap:
-        let data = blocks;
-        let bitmap_blocks = (data + BLOCK_SIZE * 8 - 1) / (BLOCK_SIZE * 8);
-        let inode = data;
-        let inode_blocks = inode * INODE_SIZE / BLOCK_SIZE;
-        let inode_bitmap = (inode + BLOCK_SIZE * 8 - 1) / (BLOCK_SIZE * 8);
-        let all_blocks = 1 + data + bitmap_blocks + inode_bitmap + inode_blocks;
-        let scale = blocks as f64 / all_blocks as f64;
-
-        let data = (blocks as f64 * scale) as usize;
-        let bitmap_blocks = (data + BLOCK_SIZE * 8 - 1) / (BLOCK_SIZE * 8);
-        let left = blocks - 1 - data - bitmap_blocks;
-        let inode_bitmap_blocks = (left + BLOCK_SIZE * 8) / (BLOCK_SIZE * 8 + 1);
-        let inode_blocks = left - inode_bitmap_blocks;
+        assert!(bytes_per_inode >= 2 * INODE_SIZE);
+        let bits = BLOCK_SIZE * 8;
+        let per_block = BLOCK_SIZE / INODE_SIZE;
+        // inode 表按比例划分,向上取整到整块,inode 位图需要覆盖 inode 表中的每一个 inode
+        let inodes = (blocks * BLOCK_SIZE / bytes_per_inode).max(1);
+        let inode_blocks = inodes.div_ceil(per_block);
+        let inode_bitmap_blocks = (inode_blocks * per_block).div_ceil(bits);
+        // 剩余空间划分为数据位图与数据块,每个位图块管理 bits 个数据块
+        let left = blocks - 1 - inode_bitmap_blocks - inode_blocks;
+        let bitmap_blocks = (left + bits) / (bits + 1);
         Self {
             magic: MAGIC,
             inode_bitmap_blocks,
             bitmap_blocks,
-            inode_blocks: inode_blocks,
-            data_blocks: data,
+            inode_blocks,
+            data_blocks: left - bitmap_blocks,
+            reserved_blocks: 0,
+            label: [0u8; 16],
+            journal_blocks: 0,
+            journal_mode: JournalMode::None as u8,
+            state: FsState::Clean as u8,
+            reserved: [0u8; 2],
+            mount_count: 0,
+            mount_time: 0,
+            write_time: 0,
+            fsck_time: 0,
+            uuid: [0u8; 16],
+            version: FORMAT_VERSION,
+            feature_compat: COMPAT_BACKUP_SUPER,
+            feature_ro_compat: 0,
+            feature_incompat: 0,
+            checksum: 0,
+            padding: 0,
+            blocks_per_group: 0,
+            inodes_per_group: 0,
+        }
+    }
+
+    /// 按块组划分的布局,每组 blocks_per_group 块,需大于每组元数据的块数且不超过 BLOCK_SIZE * 8
+    /// 剩余不足一组的块在能容纳元数据与至少一个数据块时组成最后一组,否则不使用
+    pub fn with_groups(blocks: usize, bytes_per_inode: usize, blocks_per_group: usize) -> Option<Self> {
+        let bits = BLOCK_SIZE * 8;
+        let per_block = BLOCK_SIZE / INODE_SIZE;
+        if bytes_per_inode < 2 * INODE_SIZE || blocks_per_group > bits || blocks <= 1 {
+            return None;
+        }
+        // 每组的 inode 按比例划分,向上取整到整块
+        let inode_blocks = (blocks_per_group * BLOCK_SIZE / bytes_per_inode).max(1).div_ceil(per_block);
+        let overhead = 2 + inode_blocks;
+        if overhead >= blocks_per_group {
+            return None;
         }
+        // 组描述符表的大小取决于块组数,按上限估计
+        let table_blocks = (blocks - 1).div_ceil(blocks_per_group).div_ceil(GROUP_DESCS_PER_BLOCK);
+        let left = blocks.checked_sub(1 + table_blocks)?;
+        let full = left / blocks_per_group;
+        let last = left % blocks_per_group;
+        let groups = full + (last > overhead) as usize;
+        if groups == 0 {
+            return None;
+        }
+        let data_blocks = full * (blocks_per_group - overhead) + last.saturating_sub(overhead);
+        let mut super_block = Self::new(blocks, bytes_per_inode);
+        super_block.inode_bitmap_blocks = groups;
+        super_block.bitmap_blocks = groups;
+        super_block.inode_blocks = groups * inode_blocks;
+        super_block.data_blocks = data_blocks;
+        super_block.blocks_per_group = blocks_per_group;
+        super_block.inodes_per_group = inode_blocks * per_block;
+        super_block.feature_incompat |= INCOMPAT_BLOCK_GROUPS;
+        Some(super_block)
     }
     pub fn is_valid(&self) -> bool {
         self.magic == MAGIC
     }
 
+    pub fn label(&self) -> String {
+        let len = self.label.iter().position(|c| *c == 0).unwrap_or(self.label.len());
+        String::from_utf8_lossy(&self.label[..len]).to_string()
+    }
+
+    pub fn state(&self) -> FsState {
+        self.state.into()
+    }
+
+    /// 按 8-4-4-4-12 格式显示
+    pub fn uuid(&self) -> String {
+        let hex: String = self.uuid.iter().map(|v| format!("{:02x}", v)).collect();
+        format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+    }
+
+    /// 超级块备份所在的物理块
+    pub fn backups(&self) -> Vec<usize> {
+        if self.feature_compat & COMPAT_BACKUP_SUPER == 0 {
+            return Vec::new();
+        }
+        backup_candidates(self.data_end()).filter(|v| self.data_id(*v).is_some_and(|id| id > 0)).collect()
+    }
+
+    pub fn metadata_csum(&self) -> bool {
+        self.feature_ro_compat & RO_COMPAT_METADATA_CSUM != 0
+    }
+
+    fn crc(&self) -> u32 {
+        let bytes = slice(self);
+        let offset = offset_of!(SuperBlock, checksum);
+        let crc = crc32c(&bytes[..offset]);
+        if self.version < 2 {
+            return crc;
+        }
+        crc32c_append(crc, &bytes[offset + 4..])
+    }
+
+    /// 写入前更新校验和
+    pub fn seal(&mut self) {
+        self.checksum = self.crc();
+    }
+
+    pub fn checksum_ok(&self) -> bool {
+        self.checksum == self.crc()
+    }
+
+    /// 存在不认识的只读兼容特性,只能只读挂载
+    pub fn unknown_ro_compat(&self) -> u32 {
+        self.feature_ro_compat & !RO_COMPAT_SUPPORTED
+    }
+
+    pub fn grouped(&self) -> bool {
+        self.feature_incompat & INCOMPAT_BLOCK_GROUPS != 0
+    }
+
+    /// 块组数,未启用块组时为 0
+    pub fn groups(&self) -> usize {
+        if self.grouped() { self.bitmap_blocks } else { 0 }
+    }
+
+    /// 组描述符表占用的块数
+    pub fn group_table_blocks(&self) -> usize {
+        self.groups().div_ceil(GROUP_DESCS_PER_BLOCK)
+    }
+
+    /// 每组 inode 表的块数
+    pub fn group_inode_blocks(&self) -> usize {
+        self.inodes_per_group * INODE_SIZE / BLOCK_SIZE
+    }
+
+    /// 每组的数据块数,最后一组可能较少
+    pub fn data_per_group(&self) -> usize {
+        self.blocks_per_group - 2 - self.group_inode_blocks()
+    }
+
+    /// 块组的物理块范围
+    pub fn group(&self, group: usize) -> Range<usize> {
+        let start = 1 + self.group_table_blocks() + group * self.blocks_per_group;
+        let data = if group + 1 == self.groups() {
+            self.data_blocks - group * self.data_per_group()
+        } else {
+            self.data_per_group()
+        };
+        start..start + 2 + self.group_inode_blocks() + data
+    }
+
+    /// 数据块 id 或 inode 号所在的块组
+    pub fn group_of(&self, index: usize, is_inode: bool) -> usize {
+        index / if is_inode { self.inodes_per_group } else { self.data_per_group() }
+    }
+
+    /// 各区域的物理块范围,按磁盘顺序排列,启用块组时每组的区域依次列出
+    pub fn regions(&self) -> Vec<(&'static str, Range<usize>)> {
+        let mut regions = vec![("super_block", 0..1)];
+        if self.grouped() {
+            regions.push(("group_table", 1..1 + self.group_table_blocks()));
+            for group in 0..self.groups() {
+                let range = self.group(group);
+                let inode_table = range.start + 2..range.start + 2 + self.group_inode_blocks();
+                regions.push(("data_bitmap", range.start..range.start + 1));
+                regions.push(("inode_bitmap", range.start + 1..range.start + 2));
+                regions.push(("data", inode_table.end..range.end));
+                regions.insert(regions.len() - 1, ("inode_table", inode_table));
+            }
+        } else {
+            let inode_bitmap = 1..1 + self.inode_bitmap_blocks;
+            let bitmap = inode_bitmap.end..inode_bitmap.end + self.bitmap_blocks;
+            let inode_table = bitmap.end..bitmap.end + self.inode_blocks;
+            let data = inode_table.end..inode_table.end + self.data_blocks;
+            regions.push(("inode_bitmap", inode_bitmap));
+            regions.push(("data_bitmap", bitmap));
+            regions.push(("inode_table", inode_table));
+            regions.push(("data", data));
+        }
+        regions.push(("journal", self.journal()));
+        regions
+    }
+
+    /// 数据区之后的第一个物理块
+    fn data_end(&self) -> usize {
+        if self.grouped() {
+            self.group(self.groups() - 1).end
+        } else {
+            self.data_block(self.data_blocks)
+        }
+    }
+
+    /// 日志区域的物理块范围
+    pub fn journal(&self) -> Range<usize> {
+        let start = self.data_end();
+        start..start + self.journal_blocks
+    }
+
+    /// 文件系统占用的物理块总数
+    pub fn blocks(&self) -> usize {
+        1 + self.group_table_blocks() + self.inode_bitmap_blocks + self.bitmap_blocks + self.inode_blocks
+            + self.data_blocks + self.journal_blocks
+    }
+
+    /// 超级块、组描述符表、位图与 inode 表所在的块
+    pub fn is_metadata(&self, blk: usize) -> bool {
+        if !self.grouped() {
+            return blk < self.data_block(0);
+        }
+        let first = 1 + self.group_table_blocks();
+        if blk < first {
+            return true;
+        }
+        let group = (blk - first) / self.blocks_per_group;
+        group < self.groups() && (blk - first) % self.blocks_per_group < 2 + self.group_inode_blocks()
+    }
+
+    /// 物理块对应的数据块 id,不在数据区时返回 None
+    pub fn data_id(&self, blk: usize) -> Option<usize> {
+        if !self.grouped() {
+            let start = self.data_block(0);
+            return (start..start + self.data_blocks).contains(&blk).then(|| blk - start);
+        }
+        let first = 1 + self.group_table_blocks();
+        let group = blk.checked_sub(first)? / self.blocks_per_group;
+        let offset = (blk - first) % self.blocks_per_group;
+        let id = (offset.checked_sub(2 + self.group_inode_blocks())?) + group * self.data_per_group();
+        (id < self.data_blocks).then_some(id)
+    }
+
+    /// 位图中 index 对应的物理块与块内第几位
+    pub fn bitmap_bit(&self, index: usize, is_inode: bool) -> (usize, usize) {
+        if self.grouped() {
+            let group = self.group_of(index, is_inode);
+            let per_group = if is_inode { self.inodes_per_group } else { self.data_per_group() };
+            return (self.group(group).start + is_inode as usize, index - group * per_group);
+        }
+        let bits = BLOCK_SIZE * 8;
+        let start = if is_inode { 1 } else { 1 + self.inode_bitmap_blocks };
+        (start + index / bits, index % bits)
+    }
+
+    /// 按 index 顺序排列的位图块及其中有效的位数
+    pub fn bitmaps(&self, is_inode: bool) -> Vec<(usize, usize)> {
+        let mut left = if is_inode { self.inode_size() } else { self.data_blocks };
+        let per_block = if !self.grouped() {
+            BLOCK_SIZE * 8
+        } else if is_inode {
+            self.inodes_per_group
+        } else {
+            self.data_per_group()
+        };
+        let count = if is_inode { self.inode_bitmap_blocks } else { self.bitmap_blocks };
+        (0..count)
+            .map(|i| {
+                let bits = left.min(per_block);
+                left -= bits;
+                (self.bitmap_bit(i * per_block, is_inode).0, bits)
+            })
+            .collect()
+    }
+
+    /// 校验超级块的各区域大小是否自洽,且不超出设备末尾
+    /// device_blocks: 设备的物理块数量
+    pub fn validate(&self, device_blocks: usize) -> Result<(), String> {
+        if !self.is_valid() {
+            return Err(format!("bad magic {:#x}, device is not formatted as exfs", self.magic));
+        }
+        if self.version > FORMAT_VERSION {
+            return Err(format!("format version {} is newer than supported version {}", self.version, FORMAT_VERSION));
+        }
+        if self.feature_incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(format!("unsupported incompatible features {:#x}", self.feature_incompat & !INCOMPAT_SUPPORTED));
+        }
+        if self.metadata_csum() && !self.checksum_ok() {
+            return Err(format!("bad checksum {:#x}, should be {:#x}", self.checksum, self.crc()));
+        }
+        if self.data_blocks == 0 || self.inode_blocks == 0 {
+            return Err(format!(
+                "empty region: {} inode blocks, {} data blocks",
+                self.inode_blocks, self.data_blocks
+            ));
+        }
+        let bits = BLOCK_SIZE * 8;
+        if self.bitmap_blocks.checked_mul(bits).is_some_and(|v| v < self.data_blocks) {
+            return Err(format!(
+                "{} bitmap blocks can not cover {} data blocks",
+                self.bitmap_blocks, self.data_blocks
+            ));
+        }
+        let inodes = self.inode_blocks.checked_mul(BLOCK_SIZE / INODE_SIZE)
+            .ok_or_else(|| format!("{} inode blocks is too large", self.inode_blocks))?;
+        if self.inode_bitmap_blocks.checked_mul(bits).is_some_and(|v| v < inodes) {
+            return Err(format!(
+                "{} inode bitmap blocks can not cover {} inodes",
+                self.inode_bitmap_blocks, inodes
+            ));
+        }
+        if self.grouped() {
+            self.validate_groups()?;
+        }
+        if self.journal_blocks != 0 && self.journal_blocks < MIN_JOURNAL_BLOCKS {
+            return Err(format!("journal of {} blocks is too small", self.journal_blocks));
+        }
+        let table_blocks = if self.grouped() { self.group_table_blocks() } else { 0 };
+        let blocks = [table_blocks, self.inode_bitmap_blocks, self.bitmap_blocks, self.inode_blocks, self.data_blocks, self.journal_blocks]
+            .iter()
+            .try_fold(1usize, |acc, v| acc.checked_add(*v));
+        match blocks {
+            Some(blocks) if blocks <= device_blocks => Ok(()),
+            Some(blocks) => Err(format!(
+                "file system needs {} blocks, but device only has {}",
+                blocks, device_blocks
+            )),
+            None => Err("region sizes overflow".to_string()),
+        }
+    }
+
+    /// 每组一个数据位图块与一个 inode 位图块,inode 表与数据块的数量与 blocks_per_group 相符
+    fn validate_groups(&self) -> Result<(), String> {
+        let bits = BLOCK_SIZE * 8;
+        let groups = self.bitmap_blocks;
+        let invalid = groups == 0
+            || self.inode_bitmap_blocks != groups
+            || self.blocks_per_group > bits
+            || self.inodes_per_group == 0
+            || self.inodes_per_group > bits
+            || !self.inodes_per_group.is_multiple_of(BLOCK_SIZE / INODE_SIZE)
+            || self.blocks_per_group <= 2 + self.group_inode_blocks()
+            || groups.checked_mul(self.group_inode_blocks()) != Some(self.inode_blocks);
+        if invalid {
+            return Err(format!(
+                "invalid block groups: {} groups of {} blocks with {} inodes",
+                groups, self.blocks_per_group, self.inodes_per_group
+            ));
+        }
+        let per_group = self.data_per_group();
+        if groups.checked_mul(per_group).is_none_or(|v| v < self.data_blocks) || (groups - 1) * per_group >= self.data_blocks {
+            return Err(format!("{} data blocks do not fit in {} groups of {} blocks", self.data_blocks, groups, per_group));
+        }
+        Ok(())
+    }
+
     // 通过数据块id计算物理块地址
     // id 最小值为 1,id为 0 时表示无效地址
     pub fn data_block(&self, id: usize) -> usize {
+        if self.grouped() {
+            let group = self.group_of(id, false);
+            return self.group(group).start + 2 + self.group_inode_blocks() + id - group * self.data_per_group();
+        }
         1 + self.inode_bitmap_blocks + self.bitmap_blocks + self.inode_blocks + id
     }
 
+    /// 从数据块 id 开始最多 n 块中物理地址连续的块数,启用块组时不跨越组边界
+    pub fn contiguous(&self, id: usize, n: usize) -> usize {
+        if self.grouped() {
+            let per_group = self.data_per_group();
+            return n.min(per_group - id % per_group);
+        }
+        n
+    }
+
     /// 通过 inode 号计算实际物理块地址与偏移量
     /// inode 块是倒序存储的,内部是顺序存储的
     /// @return block_id(物理),offset
@@ -63,6 +490,11 @@ impl SuperBlock {
         assert!(id > 0);
         let id = id - 1;
         let block_cap = BLOCK_SIZE / INODE_SIZE;
+        if self.grouped() {
+            let group = self.group_of(id, true);
+            let index = id - group * self.inodes_per_group;
+            return (self.group(group).start + 2 + index / block_cap, (index % block_cap) * INODE_SIZE);
+        }
         let blk_index = id / block_cap;
         let inode_blk = 1 + self.inode_bitmap_blocks + self.bitmap_blocks + blk_index;
         (inode_blk, (id % block_cap) * INODE_SIZE)
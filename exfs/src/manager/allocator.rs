@@ -0,0 +1,516 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::str::FromStr;
+
+use log::debug;
+use lru::LruCache;
+
+use crate::config::BLOCK_SIZE;
+use crate::layout::journal::JournalMode;
+use crate::manager::block_cache_manager::BlockCacheDevice;
+use crate::manager::error_code::{ENOSPC, ErrorCode};
+use crate::manager::stats::count;
+
+/// 文件分配目标之后保留给该文件的块数,其他分配尽量不占用
+pub const RESERVE_BLOCKS: usize = 8;
+/// 记录分配目标的文件数
+const GOALS: usize = 64;
+
+/// 空闲区间的选择策略
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum AllocPolicy {
+    /// 选择能容纳请求的最短区间,尽量保留长区间
+    #[default]
+    BestFit,
+    /// 从上次分配结束的位置向后查找,分配位置在整个分区内轮转
+    NextFit,
+}
+
+impl FromStr for AllocPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "best-fit" => Ok(AllocPolicy::BestFit),
+            "next-fit" => Ok(AllocPolicy::NextFit),
+            _ => Err(format!("unknown allocation policy '{}', expected best-fit or next-fit", s)),
+        }
+    }
+}
+
+impl Display for AllocPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AllocPolicy::BestFit => "best-fit",
+            AllocPolicy::NextFit => "next-fit",
+        })
+    }
+}
+
+/// 位图中空闲位组成的区间,按起点与长度分别索引
+#[derive(Clone, Debug, Default)]
+pub struct FreeExtents {
+    // 起点 -> 长度
+    by_start: BTreeMap<usize, usize>,
+    // (长度, 起点)
+    by_len: BTreeSet<(usize, usize)>,
+    free: usize,
+    // next-fit 下次开始查找的位置
+    cursor: usize,
+}
+
+impl FreeExtents {
+    /// 由位图构建,只处理前 size 位
+    pub fn from_bitmap(bitmap: &[u8], size: usize) -> Self {
+        let mut extents = Self::default();
+        extents.append(0, bitmap, size);
+        extents
+    }
+
+    /// 加入从 base 开始的一段位图,只处理前 bits 位,base 需不小于已有的位
+    /// 与之前结尾相接的区间合并,块组的位图可以依次加入
+    pub fn append(&mut self, base: usize, bitmap: &[u8], bits: usize) {
+        let mut start = None;
+        for index in 0..bits {
+            let used = bitmap[index / 8] & (1 << (index % 8)) != 0;
+            match (used, start) {
+                (false, None) => start = Some(base + index),
+                (true, Some(s)) => {
+                    self.release(s..base + index);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            self.release(s..base + bits);
+        }
+    }
+
+    /// 空闲位数
+    pub fn free(&self) -> usize {
+        self.free
+    }
+
+    /// 区间数
+    pub fn len(&self) -> usize {
+        self.by_start.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_start.is_empty()
+    }
+
+    /// 按起点顺序遍历
+    pub fn iter(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.by_start.iter().map(|(&start, &len)| start..start + len)
+    }
+
+    fn insert(&mut self, start: usize, len: usize) {
+        self.by_start.insert(start, len);
+        self.by_len.insert((len, start));
+        self.free += len;
+    }
+
+    fn remove(&mut self, start: usize) -> usize {
+        let len = self.by_start.remove(&start).unwrap();
+        self.by_len.remove(&(len, start));
+        self.free -= len;
+        len
+    }
+
+    /// 标记为已用,不在索引中的部分忽略
+    pub fn reserve(&mut self, range: Range<usize>) {
+        let overlaps: Vec<(usize, usize)> = self.by_start
+            .range(..range.end)
+            .rev()
+            .take_while(|(&start, &len)| start + len > range.start)
+            .map(|(&start, &len)| (start, len))
+            .collect();
+        for (start, len) in overlaps {
+            self.remove(start);
+            if start < range.start {
+                self.insert(start, range.start - start);
+            }
+            if start + len > range.end {
+                self.insert(range.end, start + len - range.end);
+            }
+        }
+    }
+
+    /// 标记为空闲,与相邻的区间合并
+    pub fn release(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        self.reserve(range.clone());
+        let mut start = range.start;
+        let mut end = range.end;
+        if let Some((&s, &len)) = self.by_start.range(..start).next_back() {
+            if s + len == start {
+                self.remove(s);
+                start = s;
+            }
+        }
+        if self.by_start.contains_key(&end) {
+            end += self.remove(end);
+        }
+        self.insert(start, end - start);
+    }
+
+    /// 取出最多 n 个连续位
+    /// hint 处空闲时从 hint 开始;被占用时从 hint 向后查找第一个足够长的区间,没有 hint 时按 policy 选择
+    /// 没有足够长的区间时取最长的区间
+    pub fn alloc(&mut self, n: usize, hint: Option<usize>, policy: AllocPolicy) -> Option<Range<usize>> {
+        self.alloc_avoiding(n, hint, policy, &[])
+    }
+
+    /// 同 alloc,尽量不占用 avoid 中的区间,找不到时才忽略 avoid
+    /// 从 hint 开始分配时到下一个 avoid 区间为止,新的位置与其后的 avoid 区间之间至少留出 RESERVE_BLOCKS 位
+    pub fn alloc_avoiding(
+        &mut self,
+        n: usize,
+        hint: Option<usize>,
+        policy: AllocPolicy,
+        avoid: &[Range<usize>],
+    ) -> Option<Range<usize>> {
+        let n = n.max(1);
+        let at_hint = hint.and_then(|hint| {
+            let (&start, &len) = self.by_start.range(..=hint).next_back()?;
+            let end = avoid.iter().map(|r| r.start).filter(|&s| s > hint).fold(start + len, usize::min);
+            (hint < end).then(|| (hint, end - hint))
+        });
+        let (start, len) = at_hint
+            .or_else(|| self.find(n + RESERVE_BLOCKS, hint, policy, avoid))
+            .or_else(|| self.find(n, hint, policy, &[]))
+            .or_else(|| self.by_len.iter().next_back().map(|&(len, start)| (start, len)))?;
+        let range = start..start + len.min(n);
+        self.reserve(range.clone());
+        self.cursor = range.end;
+        Some(range)
+    }
+
+    /// 查找去掉 avoid 后长度不小于 n 的一段,返回 (起点, 长度)
+    fn find(&self, n: usize, hint: Option<usize>, policy: AllocPolicy, avoid: &[Range<usize>]) -> Option<(usize, usize)> {
+        match (hint, policy) {
+            (None, AllocPolicy::BestFit) => self.by_len
+                .range((n, 0)..)
+                .find_map(|&(len, start)| Self::usable(start..start + len, n, avoid)),
+            _ => {
+                let from = hint.unwrap_or(self.cursor);
+                self.by_start
+                    .range(from..)
+                    .chain(self.by_start.range(..from))
+                    .find_map(|(&start, &len)| Self::usable(start..start + len, n, avoid))
+            }
+        }
+    }
+
+    /// extent 中去掉 avoid 后第一段长度不小于 n 的部分
+    fn usable(extent: Range<usize>, n: usize, avoid: &[Range<usize>]) -> Option<(usize, usize)> {
+        let mut start = extent.start;
+        while start + n <= extent.end {
+            let next = avoid
+                .iter()
+                .filter(|r| r.end > start && r.start < extent.end)
+                .min_by_key(|r| r.start);
+            match next {
+                Some(r) if r.start <= start => start = r.end,
+                Some(r) if r.start - start >= n => return Some((start, r.start - start)),
+                Some(r) => start = r.end,
+                None => return Some((start, extent.end - start)),
+            }
+        }
+        None
+    }
+}
+
+/// 数据块与 inode 的空闲区间索引
+/// 第一次分配时由位图构建,之后随位图一起修改
+pub struct Allocator {
+    pub policy: AllocPolicy,
+    data: Option<FreeExtents>,
+    inodes: Option<FreeExtents>,
+    // inode 号 -> 该文件下次分配的目标块,只记录最近写入的文件
+    goals: LruCache<usize, usize>,
+    // 未提交的事务中释放的数据块,提交前不能重新分配
+    freed: Vec<usize>,
+    // 发起当前操作的用户,非 root 不能占用保留块
+    uid: u32,
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self {
+            policy: AllocPolicy::default(),
+            data: None,
+            inodes: None,
+            goals: LruCache::new(NonZeroUsize::new(GOALS).unwrap()),
+            freed: Vec::new(),
+            uid: 0,
+        }
+    }
+}
+
+impl Allocator {
+    pub fn goal(&self, ino: usize) -> Option<usize> {
+        self.goals.peek(&ino).copied()
+    }
+
+    pub fn set_goal(&mut self, ino: usize, goal: usize) {
+        self.goals.put(ino, goal);
+    }
+
+    /// 文件关闭或删除后不再为其保留空间
+    pub fn forget(&mut self, ino: usize) {
+        self.goals.pop(&ino);
+    }
+
+    /// 其他文件分配目标之后保留的区间
+    fn windows(&self, owner: Option<usize>) -> Vec<Range<usize>> {
+        self.goals
+            .iter()
+            .filter(|(&ino, _)| Some(ino) != owner)
+            .map(|(_, &goal)| goal..goal + RESERVE_BLOCKS)
+            .collect()
+    }
+
+    fn extents(&mut self, is_inode: bool) -> &mut Option<FreeExtents> {
+        if is_inode { &mut self.inodes } else { &mut self.data }
+    }
+
+    /// 位图被整体改写后丢弃索引,下次分配时重新构建
+    pub fn reset(&mut self) {
+        self.data = None;
+        self.inodes = None;
+        self.freed.clear();
+    }
+}
+
+/// 块分配
+/// 位图仍是磁盘上的唯一记录,索引只存在于内存中
+impl BlockCacheDevice {
+    pub fn alloc_policy(&self) -> AllocPolicy {
+        self.allocator.policy
+    }
+
+    pub fn set_alloc_policy(&mut self, policy: AllocPolicy) {
+        self.allocator.policy = policy;
+    }
+
+    /// 分配最多 n 个连续的数据块,返回逻辑地址区间,长度在 1..=n 之间
+    /// hint 处空闲时从 hint 开始分配,否则从 hint 向后查找,空间不足时返回 ENOSPC
+    pub fn alloc_extent(&mut self, n: usize, hint: Option<usize>) -> Result<Range<usize>, ErrorCode> {
+        self.alloc_data(n, hint, None)
+    }
+
+    /// 为文件 ino 分配最多 n 个数据块,last 为文件当前的最后一块
+    /// 从 last 之后开始,文件还没有数据时使用创建时记录的目标或所在块组,分配后目标移到区间末尾
+    pub(crate) fn alloc_file_extent(&mut self, ino: usize, n: usize, last: Option<usize>) -> Result<Range<usize>, ErrorCode> {
+        let hint = last.map(|id| id + 1).or_else(|| self.allocator.goal(ino)).or_else(|| self.group_data_hint(ino));
+        let range = self.alloc_data(n, hint, Some(ino))?;
+        self.allocator.set_goal(ino, range.end);
+        Ok(range)
+    }
+
+    /// 以 uid 的身份执行 f,其间的数据块分配受保留块限制
+    pub fn as_user<T>(&mut self, uid: u32, f: impl FnOnce(&mut Self) -> Result<T, ErrorCode>) -> Result<T, ErrorCode> {
+        let old = std::mem::replace(&mut self.allocator.uid, uid);
+        let result = f(self);
+        self.allocator.uid = old;
+        result
+    }
+
+    fn alloc_data(&mut self, n: usize, hint: Option<usize>, owner: Option<usize>) -> Result<Range<usize>, ErrorCode> {
+        // 空闲块只剩保留块时只有 root 可以继续分配
+        let n = if self.allocator.uid != 0 {
+            let reserved = self.super_block().reserved_blocks;
+            let free = self.free_extents(false)?.free();
+            if free <= reserved {
+                return Err(ENOSPC);
+            }
+            n.min(free - reserved)
+        } else {
+            n
+        };
+        let avoid = self.allocator.windows(owner);
+        let range = self.alloc_range(n, hint, false, &avoid)?;
+        for id in range.clone() {
+            // 快速格式化不会清零数据块,分配时清零以免读到旧数据
+            self.modify_data(id, |data| data.fill(0))?;
+        }
+        debug!("[AllocData] {:?}", range);
+        Ok(range)
+    }
+
+    /// 分配一个 inode,返回 inode 号
+    /// 指定 parent 时优先使用其后的第一个空闲 inode,同一目录下的 inode 在 inode 表中相邻
+    pub(crate) fn alloc_inode(&mut self, parent: Option<usize>) -> Result<usize, ErrorCode> {
+        // inode 号从 1 开始,parent 号即为其后一个 inode 的位置
+        let id = self.alloc_range(1, parent, true, &[])?.start + 1;
+        debug!("[AllocInode] {}", id);
+        Ok(id)
+    }
+
+    /// 空闲区间索引,尚未构建时读取位图构建
+    pub fn free_extents(&mut self, is_inode: bool) -> Result<&FreeExtents, ErrorCode> {
+        self.build_free_extents(is_inode)?;
+        Ok(self.allocator.extents(is_inode).as_ref().unwrap())
+    }
+
+    fn build_free_extents(&mut self, is_inode: bool) -> Result<(), ErrorCode> {
+        if self.allocator.extents(is_inode).is_some() {
+            return Ok(());
+        }
+        let mut extents = FreeExtents::default();
+        let mut base = 0;
+        for (blk_id, bits) in self.super_block().bitmaps(is_inode) {
+            self.block_cache(blk_id)?
+                .lock()
+                .unwrap()
+                .read(0, |bytes: &[u8; BLOCK_SIZE]| extents.append(base, bytes, bits));
+            base += bits;
+        }
+        if !is_inode {
+            for index in self.allocator.freed.iter() {
+                extents.reserve(*index..index + 1);
+            }
+        }
+        debug!("Free {} extents: {}, free: {}", if is_inode { "inode" } else { "data" }, extents.len(), extents.free());
+        *self.allocator.extents(is_inode) = Some(extents);
+        Ok(())
+    }
+
+    /// 从索引中取出区间并在位图中标记
+    fn alloc_range(
+        &mut self,
+        n: usize,
+        hint: Option<usize>,
+        is_inode: bool,
+        avoid: &[Range<usize>],
+    ) -> Result<Range<usize>, ErrorCode> {
+        self.build_free_extents(is_inode)?;
+        let policy = self.allocator.policy;
+        let range = self.allocator
+            .extents(is_inode)
+            .as_mut()
+            .unwrap()
+            .alloc_avoiding(n, hint, policy, avoid)
+            .ok_or(ENOSPC)?;
+        for index in range.clone() {
+            self.mark(index, is_inode, true)?;
+        }
+        count(if is_inode { &self.counters.inode_allocs } else { &self.counters.data_allocs }, range.len() as u64);
+        Ok(range)
+    }
+
+    /// 位图修改后同步索引,索引尚未构建时忽略
+    /// 启用日志时释放的数据块等事务提交后再加入索引,以免旧文件的块在提交前被新数据覆盖
+    pub(crate) fn sync_free_extents(&mut self, index: usize, is_inode: bool, used: bool) {
+        if !used && !is_inode && self.journal_mode() != JournalMode::None {
+            self.allocator.freed.push(index);
+            return;
+        }
+        if let Some(extents) = self.allocator.extents(is_inode) {
+            if used {
+                extents.reserve(index..index + 1);
+            } else {
+                extents.release(index..index + 1);
+            }
+        }
+    }
+
+    /// 事务提交后,其中释放的数据块才能重新分配
+    pub(crate) fn release_freed(&mut self) -> Result<(), ErrorCode> {
+        for index in std::mem::take(&mut self.allocator.freed) {
+            // 提交前可能又被直接标记为已用
+            if self.used(index, false)? {
+                continue;
+            }
+            if let Some(extents) = self.allocator.extents(false) {
+                extents.release(index..index + 1);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn alloc_extents() {
+    use crate::manager::mkfs::MkfsOptions;
+
+    // 0..4 已用,4..6 空闲,6 已用,7..16 空闲
+    let mut extents = FreeExtents::from_bitmap(&[0b0100_1111, 0], 16);
+    assert_eq!(extents.iter().collect::<Vec<_>>(), vec![4..6, 7..16]);
+    assert_eq!(extents.free(), 11);
+    assert_eq!(extents.alloc(2, None, AllocPolicy::BestFit), Some(4..6));
+    assert_eq!(extents.alloc(3, Some(10), AllocPolicy::BestFit), Some(10..13));
+    // 没有足够长的区间时返回最长的部分
+    assert_eq!(extents.alloc(8, None, AllocPolicy::BestFit), Some(13..16));
+    extents.release(5..6);
+    extents.release(4..5);
+    extents.release(13..16);
+    assert_eq!(extents.iter().collect::<Vec<_>>(), vec![4..6, 7..10, 13..16]);
+    assert_eq!(extents.alloc(1, None, AllocPolicy::NextFit), Some(4..5));
+    assert_eq!(extents.alloc(2, None, AllocPolicy::NextFit), Some(7..9));
+    assert_eq!(extents.alloc(3, None, AllocPolicy::NextFit), Some(13..16));
+    assert_eq!(extents.alloc(1, None, AllocPolicy::NextFit), Some(5..6));
+    assert_eq!(extents.free(), 1);
+    // 避开其他文件保留的区间,找不到时才占用
+    let reserved = [Range { start: 2, end: 10 }];
+    let mut extents = FreeExtents::from_bitmap(&[0; 4], 32);
+    assert_eq!(extents.alloc_avoiding(4, Some(0), AllocPolicy::BestFit, &reserved), Some(0..2));
+    assert_eq!(extents.alloc_avoiding(4, None, AllocPolicy::BestFit, &reserved), Some(10..14));
+    assert_eq!(extents.alloc_avoiding(4, Some(1), AllocPolicy::BestFit, &reserved), Some(14..18));
+    assert_eq!(extents.alloc_avoiding(20, None, AllocPolicy::BestFit, &reserved), Some(18..32));
+
+    let (_, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let free = fs.statfs_internal().unwrap().free_blocks;
+    assert_eq!(fs.free_extents(false).unwrap().free(), free);
+    let a = fs.create_file("a", &[1u8; 8 * BLOCK_SIZE]).unwrap();
+    let inode = fs.inode(a).unwrap();
+    let blks = fs.inode_data_blk_list(&inode).unwrap();
+    assert!(blks.windows(2).all(|v| v[1] == v[0] + 1), "{:?}", blks);
+    // 释放的块在事务提交后回到索引,与位图保持一致
+    let inode = fs.inode(a).unwrap().with_id(a);
+    fs.write_system(0, &inode, &[], true).unwrap();
+    fs.sync().unwrap();
+    assert_eq!(fs.free_extents(false).unwrap().free(), free);
+    assert_eq!(fs.alloc_extent(4, Some(blks[0])).unwrap(), blks[0]..blks[0] + 4);
+    assert_eq!(fs.statfs_internal().unwrap().free_blocks, free - 4);
+
+    // 交替写入的文件各自保持连续,同一目录下的 inode 相邻
+    let b = fs.create_file("b", &[]).unwrap();
+    let c = fs.create_file("c", &[]).unwrap();
+    assert_eq!((b, c), (a + 1, a + 2));
+    for i in 0..6 {
+        for id in [b, c] {
+            let inode = fs.inode(id).unwrap().with_id(id);
+            fs.write_system(i * BLOCK_SIZE, &inode, &[1u8; BLOCK_SIZE], false).unwrap();
+        }
+    }
+    for id in [b, c] {
+        let inode = fs.inode(id).unwrap();
+        assert_eq!(inode.index_level, 1, "{:?}", fs.inode_data_blk_list(&inode));
+    }
+}
+
+#[test]
+fn reserved_for_root() {
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (_, mut fs) = BlockCacheDevice::test(&MkfsOptions::new().reserved_percent(50).journal(JournalMode::None));
+    let reserved = fs.super_block().reserved_blocks;
+    let file = fs.create_file("file", &[]).unwrap();
+    let inode = fs.inode(file).unwrap().with_id(file);
+    let data = vec![1u8; fs.super_block().data_blocks * BLOCK_SIZE];
+    // 普通用户用尽非保留块后返回 ENOSPC
+    assert_eq!(fs.as_user(1000, |fs| fs.write_system(0, &inode, &data, false)), Err(ENOSPC));
+    assert_eq!(fs.free_extents(false).unwrap().free(), reserved);
+    assert_eq!(fs.statfs_internal().unwrap().available_blocks, 0);
+    assert_eq!(fs.as_user(1000, |fs| fs.alloc_extent(1, None)), Err(ENOSPC));
+    // root 仍可使用保留块
+    assert_eq!(fs.alloc_extent(4, None).unwrap().len(), 4);
+}
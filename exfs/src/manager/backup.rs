@@ -0,0 +1,110 @@
+use log::warn;
+
+use crate::config::BLOCK_SIZE;
+use crate::layout::data_block::DataBlock;
+use crate::layout::super_block::{backup_candidates, FsState, SuperBlock};
+use crate::manager::block_cache_manager::BlockCacheDevice;
+use crate::manager::error_code::{EIO, ErrorCode, io_error};
+use crate::utils::slice::from_slice;
+
+/// 超级块备份
+/// 备份直接写入设备,不经过缓存与日志,只在超级块损坏时使用
+impl BlockCacheDevice {
+    /// 超级块与上次写入的备份不同时更新所有备份
+    pub(crate) fn sync_backups(&mut self) -> Result<(), ErrorCode> {
+        let super_block = self.super_block();
+        if super_block == self.backup_super_block {
+            return Ok(());
+        }
+        self.write_backups()?;
+        self.backup_super_block = super_block;
+        Ok(())
+    }
+
+    pub(crate) fn write_backups(&mut self) -> Result<(), ErrorCode> {
+        let data = *self.super_block.lock().unwrap().data();
+        for blk in self.super_block().backups() {
+            self.device.write(blk, &data).map_err(io_error)?;
+        }
+        self.device.sync().map_err(io_error)
+    }
+
+    /// 内容与超级块不一致的备份
+    pub fn stale_backups(&mut self) -> Result<Vec<usize>, ErrorCode> {
+        let data = *self.super_block.lock().unwrap().data();
+        let mut buf = [0u8; BLOCK_SIZE];
+        let mut stale = Vec::new();
+        for blk in self.super_block().backups() {
+            self.device.read(blk, &mut buf).map_err(io_error)?;
+            if buf != data {
+                stale.push(blk);
+            }
+        }
+        Ok(stale)
+    }
+
+    /// 在备份位置中查找有效的超级块,使用最近写入的一份替换损坏的超级块并返回其位置
+    /// 恢复后状态置为 not clean,sync 时写回
+    pub(crate) fn restore_super_block(&mut self, device_blocks: usize) -> Result<usize, ErrorCode> {
+        let mut found: Option<(usize, DataBlock, SuperBlock)> = None;
+        let mut buf = [0u8; BLOCK_SIZE];
+        for blk in backup_candidates(device_blocks) {
+            if self.device.read(blk, &mut buf).is_err() {
+                continue;
+            }
+            let super_block: SuperBlock = from_slice(&buf);
+            // 备份必须位于它自己记录的备份位置,避免把文件内容中的镜像当作备份
+            if super_block.validate(device_blocks).is_err() || !super_block.backups().contains(&blk) {
+                continue;
+            }
+            if found.as_ref().is_none_or(|(_, _, v)| (super_block.write_time, super_block.mount_count) > (v.write_time, v.mount_count)) {
+                found = Some((blk, buf, super_block));
+            }
+        }
+        let Some((blk, data, _)) = found else { return Err(EIO) };
+        warn!("Super block is damaged, restored from backup at block {}", blk);
+        self.super_block.lock().unwrap().modify(0, |v: &mut DataBlock| *v = data);
+        self.modify_super_block(|sb| sb.state = FsState::Dirty as u8);
+        Ok(blk)
+    }
+}
+
+#[test]
+fn restore_from_backup() {
+    use crate::block_device::block_device::BlockDevice;
+    use crate::layout::super_block::INCOMPAT_SUPPORTED;
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let sb = fs.super_block();
+    assert_eq!(sb.backups(), vec![256, 512]);
+    assert!(fs.stale_backups().unwrap().is_empty());
+    // 备份块不会被分配
+    for blk in sb.backups() {
+        assert!(fs.used(blk - sb.data_block(0), false).unwrap());
+    }
+    fs.mount().unwrap();
+    assert!(fs.stale_backups().unwrap().is_empty());
+    fs.unmount().unwrap();
+    drop(fs);
+
+    ram.write(0, &[0u8; BLOCK_SIZE]).unwrap();
+    let mut fs = BlockCacheDevice::open(ram.clone()).unwrap();
+    let restored = fs.super_block();
+    assert_eq!((restored.uuid, restored.mount_count), (sb.uuid, 1));
+    assert_eq!(restored.state(), FsState::Dirty);
+    let report = fs.fsck(true).unwrap();
+    assert!(report.remaining.is_empty(), "{:?}", report.remaining);
+    assert!(fs.stale_backups().unwrap().is_empty());
+    drop(fs);
+    let mut buf = [0u8; BLOCK_SIZE];
+    ram.read(0, &mut buf).unwrap();
+    assert!(from_slice::<SuperBlock>(&buf).is_valid());
+
+    // 不认识的不兼容特性
+    let mut fs = BlockCacheDevice::open(ram.clone()).unwrap();
+    fs.modify_super_block(|sb| sb.feature_incompat = !INCOMPAT_SUPPORTED & 1 << 31);
+    fs.sync().unwrap();
+    drop(fs);
+    assert!(BlockCacheDevice::open(ram.clone()).is_err());
+}
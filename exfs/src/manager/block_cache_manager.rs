@@ -1,47 +1,134 @@
 use std::collections::BTreeMap;
-use std::mem::size_of;
-use std::num::NonZeroUsize;
+use std::io;
+use std::io::{Error, ErrorKind};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
+use std::time::SystemTime;
 
-use libc::{c_int, EBADF, ENOSPC};
-use lru::LruCache;
+use libc::{c_int, EBADF, EINVAL};
+use log::{debug, error};
 
 use crate::block_device::block_device::BlockDevice;
+use crate::block_device::overlay_device::OverlayDevice;
 use crate::cache::block_cache::CacheBlock;
 use crate::cache::file_handler::FileHandler;
+use crate::cache::inode_cache::{DentryCache, InodeCache};
+use crate::cache::options::CacheOptions;
+use crate::cache::policy::CachePolicy;
+use crate::cache::write_back::{WriteBack, WritePolicy};
 use crate::config::BLOCK_SIZE;
 use crate::layout::data_block::DataBlock;
 use crate::layout::index_node::IndexNode;
-use crate::layout::inode::{Inode, InodeWithId};
-use crate::layout::super_block::SuperBlock;
-use crate::manager::error_code::ErrorCode;
+use crate::layout::inode::{Inode, INODE_SIZE, InodeWithId};
+use crate::layout::journal::{JournalMode, MKFS_MIN_JOURNAL_BLOCKS};
+use crate::layout::super_block::{COMPAT_JOURNAL, RO_COMPAT_METADATA_CSUM, SuperBlock};
+use crate::manager::allocator::Allocator;
+use crate::manager::error_code::{ErrorCode, io_error};
+use crate::manager::journal::Journal;
+use crate::manager::mkfs::MkfsOptions;
+use crate::manager::stats::{count, CountedDevice, Counters};
 use crate::typ::file_type::FileType;
-use crate::utils::slice::vec2slice;
+use crate::utils::time::time_sys;
+use crate::utils::uuid::random_uuid;
 
 /// 块设备缓存管理器
 pub struct BlockCacheDevice {
-    device: Arc<dyn BlockDevice>,
-    caches: LruCache<usize, Arc<Mutex<CacheBlock>>>,
+    pub(crate) device: Arc<dyn BlockDevice>,
+    // 每个物理块只有一个 CacheBlock,超级块单独缓存
+    pub(crate) caches: Box<dyn CachePolicy>,
+    cache_options: CacheOptions,
+    // caches 中元数据块的数量
+    metadata_cached: usize,
+    // 启用日志时被淘汰的脏块,提交前不能写回
+    pub(crate) pinned: BTreeMap<usize, Arc<Mutex<CacheBlock>>>,
+    pub(crate) journal: Option<Journal>,
+    pub(crate) write_back: Arc<WriteBack>,
+    flusher: Option<JoinHandle<()>>,
+    // 只读挂载时不记录挂载状态
+    pub(crate) read_only: bool,
+    // 最后一次写入备份的超级块,sync 时与当前超级块不同则更新备份
+    pub(crate) backup_super_block: SuperBlock,
+    pub(crate) counters: Arc<Counters>,
+    pub(crate) inodes: InodeCache,
+    pub(crate) dentries: DentryCache,
+    pub(crate) allocator: Allocator,
     file_handlers: BTreeMap<u64, FileHandler>,
     recycled_fh: Vec<u64>,
     pub super_block: Arc<Mutex<CacheBlock>>,
 }
 
 impl BlockCacheDevice {
-    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
-        let cache_blk = Arc::new(Mutex::new(CacheBlock::new(device.clone(), 0)));
-        Self {
+    /// 不校验超级块,仅供 mkfs 使用,打开已有镜像请使用 open
+    pub fn new(device: Arc<dyn BlockDevice>) -> io::Result<Self> {
+        Self::with_cache(device, CacheOptions::default())
+    }
+
+    /// 同 new,使用指定的缓存参数
+    pub fn with_cache(device: Arc<dyn BlockDevice>, cache_options: CacheOptions) -> io::Result<Self> {
+        let counters = Arc::new(Counters::default());
+        let device: Arc<dyn BlockDevice> = Arc::new(CountedDevice { inner: device, counters: counters.clone() });
+        let write_back = Arc::new(WriteBack::new(cache_options.write_policy, counters.clone()));
+        let cache_blk = write_back.cache(device.clone(), 0)?;
+        Ok(Self {
             device,
-            caches: LruCache::new(NonZeroUsize::new(128).unwrap()),
+            caches: cache_options.policy.build(cache_options.capacity()),
+            inodes: InodeCache::new(cache_options.inodes),
+            dentries: DentryCache::new(cache_options.dentries),
+            allocator: Allocator::default(),
+            cache_options,
+            metadata_cached: 0,
+            pinned: BTreeMap::new(),
+            journal: None,
+            write_back,
+            flusher: None,
+            read_only: false,
+            backup_super_block: SuperBlock::default(),
+            counters,
             file_handlers: BTreeMap::new(),
             recycled_fh: Vec::new(),
             super_block: cache_blk,
+        })
+    }
+
+    /// 打开已格式化的镜像,超级块无效或与设备不符时返回 InvalidData
+    /// 超级块损坏时使用最新的有效备份,日志中已提交的事务会在此时重放
+    pub fn open(device: Arc<dyn BlockDevice>) -> io::Result<Self> {
+        Self::open_with_cache(device, CacheOptions::default())
+    }
+
+    /// 同 open,使用指定的缓存参数
+    pub fn open_with_cache(device: Arc<dyn BlockDevice>, cache_options: CacheOptions) -> io::Result<Self> {
+        let mut fs = Self::with_cache(device, cache_options)?;
+        let device_blocks = fs.device.blocks()?;
+        match fs.super_block().validate(device_blocks) {
+            Ok(_) => fs.backup_super_block = fs.super_block(),
+            Err(e) => {
+                fs.restore_super_block(device_blocks)
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, e))?;
+            }
         }
+        fs.load_journal()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("can not recover journal: {}", Error::from_raw_os_error(e))))?;
+        Ok(fs)
+    }
+
+    /// 只读打开,供检查工具与只读挂载使用
+    /// 日志在内存覆盖层中重放,包括释放时的提交在内都不会写入镜像
+    pub fn open_read_only(device: Arc<dyn BlockDevice>) -> io::Result<Self> {
+        Self::open_read_only_with_cache(device, CacheOptions::default())
+    }
+
+    /// 同 open_read_only,使用指定的缓存参数
+    pub fn open_read_only_with_cache(device: Arc<dyn BlockDevice>, cache_options: CacheOptions) -> io::Result<Self> {
+        let mut fs = Self::open_with_cache(Arc::new(OverlayDevice::new(device)), cache_options)?;
+        fs.set_read_only(true);
+        Ok(fs)
     }
 
     pub fn fh(&self, fh: u32, pid: u32) -> Option<&FileHandler> {
         let key = (pid as u64) << 32 | fh as u64;
-        println!("fh: {:x},pid:{:x},key:{:x},kv:{:x?}", fh, pid, key, self.file_handlers.keys());
+        debug!("fh: {:x},pid:{:x},key:{:x},kv:{:x?}", fh, pid, key, self.file_handlers.keys());
         let fh = self.file_handlers.get(&key);
         if fh.is_none() { panic!("get fh bad descriptor") }
         fh
@@ -60,7 +147,7 @@ impl BlockCacheDevice {
             self.recycled_fh.pop().unwrap()
         };
         FileHandler::new(inode, self, offset, flags).map(|v| {
-            println!("open pid:{:x},key:{:x}", pid, key);
+            debug!("open pid:{:x},key:{:x}", pid, key);
             self.file_handlers.insert(key, v);
             key as u32
         })
@@ -68,29 +155,144 @@ impl BlockCacheDevice {
 
     pub fn close_internal(&mut self, fh: u32, pid: u32, flush: bool) -> Result<(), c_int> {
         let key = (pid as u64) << 32 | fh as u64;
-        match self.file_handlers.get(&key) {
-            Some(_) => {
-                self.file_handlers.remove(&key).map(|fh| {
-                    if flush {
-                        fh.flush(self);
-                    }
-                });
+        match self.file_handlers.remove(&key) {
+            Some(fh) => {
                 self.recycled_fh.push(key);
+                self.allocator.forget(fh.inode_with_id().inode);
+                if flush {
+                    fh.flush(self)?;
+                }
                 Ok(())
             }
             None => Err(EBADF),
         }
     }
 
-    pub fn block_cache(&mut self, block: usize) -> Arc<Mutex<CacheBlock>> {
-        match self.caches.get(&block) {
-            Some(cache) => cache.clone(),
+    /// 超级块、位图与 inode 区域的块作为元数据缓存
+    pub fn block_cache(&mut self, block: usize) -> Result<Arc<Mutex<CacheBlock>>, ErrorCode> {
+        let metadata = self.super_block().is_metadata(block);
+        self.cache_block(block, metadata)
+    }
+
+    /// 索引块的缓存,id 为数据块逻辑 id
+    pub fn index_block_cache(&mut self, id: usize) -> Result<Arc<Mutex<CacheBlock>>, ErrorCode> {
+        let blk_id = self.data_block(id);
+        self.cache_block(blk_id, true)
+    }
+
+    fn cache_block(&mut self, block: usize, metadata: bool) -> Result<Arc<Mutex<CacheBlock>>, ErrorCode> {
+        if block == 0 {
+            return Ok(self.super_block.clone());
+        }
+        let cache = match self.caches.get(&block) {
+            Some(cache) => {
+                count(&self.counters.cache_hits, 1);
+                cache.clone()
+            }
             None => {
-                let cache = Arc::new(Mutex::new(CacheBlock::new(self.device.clone(), block)));
-                let _ = self.caches.push(block, cache.clone());
+                let cache = match self.pinned.remove(&block) {
+                    Some(cache) => {
+                        count(&self.counters.cache_hits, 1);
+                        cache
+                    }
+                    None => {
+                        count(&self.counters.cache_misses, 1);
+                        self.write_back.cache(self.device.clone(), block).map_err(io_error)?
+                    }
+                };
+                self.insert_cache(block, cache.clone());
                 cache
             }
+        };
+        if metadata && !cache.lock().unwrap().is_metadata() {
+            // 数据区中的块被当作索引块读取后转为元数据
+            cache.lock().unwrap().set_metadata();
+            self.metadata_cached += 1;
+        }
+        self.shrink_cache()?;
+        if !self.write_back.is_journaling()
+            && self.write_back.len() * 100 > self.cache_options.blocks * self.cache_options.dirty_ratio as usize {
+            self.write_back.flush(&*self.device).map_err(io_error)?;
+        }
+        Ok(cache)
+    }
+
+    fn insert_cache(&mut self, block: usize, cache: Arc<Mutex<CacheBlock>>) {
+        if cache.lock().unwrap().is_metadata() {
+            self.metadata_cached += 1;
+        }
+        count(&self.counters.cached_blocks, 1);
+        self.caches.put(block, cache);
+    }
+
+    /// 以一次设备读取将物理地址连续的 n 个数据块读入缓存,已缓存的块保持不变
+    /// 返回新读入的块数
+    pub fn prefetch(&mut self, id: usize, n: usize) -> Result<usize, ErrorCode> {
+        let start = self.data_block(id);
+        let missing: Vec<usize> = (start..start + n)
+            .filter(|blk| !self.caches.contains(blk) && !self.pinned.contains_key(blk))
+            .collect();
+        let (Some(&first), Some(&last)) = (missing.first(), missing.last()) else { return Ok(0) };
+        let mut buf = vec![0u8; (last + 1 - first) * BLOCK_SIZE];
+        self.device.read_blocks(first, &mut buf).map_err(io_error)?;
+        for blk in missing.iter() {
+            let offset = (blk - first) * BLOCK_SIZE;
+            let data = buf[offset..offset + BLOCK_SIZE].try_into().unwrap();
+            let cache = self.write_back.track(CacheBlock::from_data(self.device.clone(), *blk, data));
+            self.insert_cache(*blk, cache);
+        }
+        count(&self.counters.readahead_blocks, missing.len() as u64);
+        self.shrink_cache()?;
+        Ok(missing.len())
+    }
+
+    /// 预读窗口上限,不超过缓存容量的 1/4
+    pub(crate) fn readahead_blocks(&self) -> usize {
+        self.cache_options.readahead_blocks.min(self.cache_options.blocks / 4)
+    }
+
+    /// 按替换策略给出的顺序淘汰没有被外部引用的块,被引用的块视为 pin 住,保留在缓存中
+    /// 设置了元数据容量时元数据与数据块分别淘汰,否则共用 blocks 容量
+    /// 淘汰的脏块先写回,启用日志时移入 pinned 等待提交,再次读取时不会得到旧数据
+    fn shrink_cache(&mut self) -> Result<(), ErrorCode> {
+        loop {
+            let (metadata, over) = match self.cache_options.metadata_blocks {
+                Some(cap) if self.metadata_cached > cap => (Some(true), true),
+                Some(_) => (Some(false), self.caches.len() - self.metadata_cached > self.cache_options.blocks),
+                None => (None, self.caches.len() > self.cache_options.blocks),
+            };
+            if !over {
+                break;
+            }
+            let Some(victim) = self.caches.victims()
+                .find(|(_, c)| {
+                    Arc::strong_count(c) == 1 && metadata.is_none_or(|v| c.lock().unwrap().is_metadata() == v)
+                })
+                .map(|(id, _)| *id) else {
+                debug!("All {} cached blocks are in use", self.caches.len());
+                break;
+            };
+            let cache = self.caches.pop(&victim).unwrap();
+            if cache.lock().unwrap().is_metadata() {
+                self.metadata_cached -= 1;
+            }
+            count(&self.counters.evictions, 1);
+            self.counters.cached_blocks.fetch_sub(1, Ordering::Relaxed);
+            if !cache.lock().unwrap().is_dirty() {
+                continue;
+            }
+            if self.journal_mode() != JournalMode::None {
+                self.pinned.insert(victim, cache);
+                continue;
+            }
+            let synced = cache.lock().unwrap().sync();
+            if let Err(e) = synced {
+                error!("Write back block {} failed on eviction: {}", victim, e);
+                self.insert_cache(victim, cache);
+                return Err(io_error(e));
+            }
         }
+        Ok(())
     }
 
     pub fn data_block(&self, id: usize) -> usize {
@@ -107,90 +309,115 @@ impl BlockCacheDevice {
     /// @return block_id(物理),offset
     pub fn inode_block(&self, id: usize) -> (usize, usize) {
         let (blk_id, offset) = self.super_block().inode_block(id);
-        println!("[Inode Block] {} -> {}({})", id, blk_id, offset);
+        debug!("[Inode Block] {} -> {}({})", id, blk_id, offset);
         (blk_id, offset)
     }
 
-    pub fn data<T>(&mut self, id: usize, offset: usize, f: impl FnOnce(&T)) {
+    pub fn data<T>(&mut self, id: usize, offset: usize, f: impl FnOnce(&T)) -> Result<(), ErrorCode> {
         let blk_id = self.data_block(id);
-        self.block_cache(blk_id).lock().unwrap().read(offset, f);
+        self.block_cache(blk_id)?.lock().unwrap().read(offset, f);
+        Ok(())
     }
 
-    pub fn modify_data<V>(&mut self, id: usize, f: impl FnOnce(&mut DataBlock) -> V) {
+    pub fn modify_data<V>(&mut self, id: usize, f: impl FnOnce(&mut DataBlock) -> V) -> Result<V, ErrorCode> {
         let blk_id = self.data_block(id);
-        self.block_cache(blk_id).lock().unwrap().modify(0, f);
+        Ok(self.block_cache(blk_id)?.lock().unwrap().modify(0, f))
     }
 
-    pub fn inode(&mut self, id: usize) -> Inode {
-        let (blk_id, offset) = self.inode_block(id);
-        let mut inode: Inode = Inode::nil();
-        self.block_cache(blk_id)
-            .lock()
-            .unwrap()
-            .read(offset, |i: &Inode| {
-                inode = *i;
-            });
-        inode
+    pub fn inode(&mut self, id: usize) -> Result<Inode, ErrorCode> {
+        if let Some(inode) = self.inodes.get(id) {
+            return Ok(inode);
+        }
+        let inode = self.load_inode(id)?;
+        self.inodes.put(id, inode);
+        Ok(inode)
     }
 
     /// inode 数据存储所在的 数据块 id 列表
     /// 非物理块
-    pub fn inode_data_blk_list(&mut self, inode: &Inode) -> Vec<usize> {
+    pub fn inode_data_blk_list(&mut self, inode: &Inode) -> Result<Vec<usize>, ErrorCode> {
         inode.index_node.list(self, inode.index_level)
     }
 
     /// id: inode id
-    pub fn read_all(&mut self, id: usize) -> Vec<u8> {
-        let inode = self.inode(id);
+    pub fn read_all(&mut self, id: usize) -> Result<Vec<u8>, ErrorCode> {
+        let inode = self.inode(id)?;
         let mut data_ = Vec::new();
         // debug!("dir list: {:?},{}", inode.index_node, inode.index_level);
-        inode
-            .index_node
-            .list(self, inode.index_level)
-            .iter()
-            .for_each(|v| {
-                // debug!("data blocks: {}", v);
-                self.block_cache(self.data_block(*v))
-                    .lock()
-                    .unwrap()
-                    .read(0, |data: &[u8; BLOCK_SIZE]| {
-                        data.iter().for_each(|v| data_.push(*v))
-                    });
-            });
+        for v in inode.index_node.list(self, inode.index_level)? {
+            // debug!("data blocks: {}", v);
+            self.block_cache(self.data_block(v))?
+                .lock()
+                .unwrap()
+                .read(0, |data: &[u8; BLOCK_SIZE]| {
+                    data.iter().for_each(|v| data_.push(*v))
+                });
+        }
         data_.truncate(inode.size as usize);
-        data_
+        Ok(data_)
     }
 
-    pub fn modify_inode<V>(&mut self, id: usize, f: impl FnOnce(&mut Inode) -> V) -> V {
+    pub fn modify_inode<V>(&mut self, id: usize, f: impl FnOnce(&mut Inode) -> V) -> Result<V, ErrorCode> {
         let (blk_id, offset) = self.inode_block(id);
-        self.block_cache(blk_id).lock().unwrap().modify(offset, f)
+        let (v, inode) = self.block_cache(blk_id)?.lock().unwrap().modify(offset, |ino: &mut Inode| {
+            let v = f(ino);
+            ino.seal(id);
+            (v, *ino)
+        });
+        self.inodes.put(id, inode);
+        Ok(v)
     }
 
     /// 写入完整数据,并自动为其创建完整的索引节点,返回根节点和 level
-    pub fn write_data(&mut self, buf: &[u8], level: u8) -> (IndexNode, u8) {
+    pub fn write_data(&mut self, buf: &[u8], level: u8) -> Result<(IndexNode, u8), ErrorCode> {
         let len = buf.len();
         if len == 0 {
-            return (IndexNode::default(), 0);
+            return Ok((IndexNode::default(), 0));
         }
         let blocks_need = (len + BLOCK_SIZE - 1) / BLOCK_SIZE;
         let mut blks = Vec::new();
-        for i in 0..blocks_need {
-            let blk_id = self.alloc_block(false).expect("No enough space left");
-
-            self.block_cache(self.data_block(blk_id))
-                .lock()
-                .unwrap()
-                .modify(0, |v: &mut [u8; BLOCK_SIZE]| {
-                    let range = i * BLOCK_SIZE..len.min((i + 1) * BLOCK_SIZE);
-                    let slice = &buf[range];
-                    v[..slice.len()].copy_from_slice(slice)
-                });
-            blks.push(blk_id);
+        while blks.len() < blocks_need {
+            let hint = blks.last().map(|id| id + 1);
+            blks.extend(self.alloc_extent(blocks_need - blks.len(), hint)?);
+        }
+        // 索引按块 id 排序,数据也按此顺序写入
+        blks.sort_unstable();
+        // 物理地址连续的新块合并为一次写入
+        let mut i = 0;
+        while i < blks.len() {
+            let n = blks[i..].iter().enumerate().take_while(|(j, id)| **id == blks[i] + j).count();
+            let n = self.super_block().contiguous(blks[i], n);
+            let end = len.min((i + n) * BLOCK_SIZE);
+            let mut run = vec![0u8; n * BLOCK_SIZE];
+            run[..end - i * BLOCK_SIZE].copy_from_slice(&buf[i * BLOCK_SIZE..end]);
+            self.write_new_blocks(blks[i], &run)?;
+            i += n;
         }
 
         self.make_indexes(blks, level + 1)
     }
 
+    /// 直接写入刚分配的物理地址连续的数据块,缓存中的副本同步更新
+    /// 新块在引用它的元数据提交前不会被读到,本事务释放的块也要等提交后才会重新分配,不需要经过日志
+    /// data 模式下所有数据都经过日志,改为写入缓存
+    fn write_new_blocks(&mut self, id: usize, data: &[u8]) -> Result<(), ErrorCode> {
+        if self.journal_mode() == JournalMode::Data {
+            for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+                self.modify_data(id + i, |block| block.copy_from_slice(chunk))?;
+            }
+            return Ok(());
+        }
+        let start = self.data_block(id);
+        self.device.write_blocks(start, data).map_err(io_error)?;
+        for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+            let blk = start + i;
+            if let Some(cache) = self.caches.peek(&blk).or(self.pinned.get(&blk)) {
+                cache.lock().unwrap().load(chunk);
+            }
+        }
+        Ok(())
+    }
+
     // 保留已有节点，仅连接新增子节点
     /// data_level: 数据块为 0
     ///  new_data_blocks: 现在的所有数据块，包含原有块，若不包含则表示删除数据块，将会缩减索引
@@ -212,122 +439,316 @@ impl BlockCacheDevice {
                     ino.index_node = *new_index.first().unwrap();
                     ino.index_level = data_level + 1;
                 }
-            });
+            })?;
             return Ok(());
         }
         // 将索引转换为字节存储
-        let buf: Vec<u8> = vec2slice(new_index);
-        let need_blk_num = (buf.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let buf = IndexNode::pack(&new_index);
+        let need_blk_num = buf.len() / BLOCK_SIZE;
         let mut index_blk = inode.inode().index_node.list_level_blk(
             self,
             inode.inode().index_level,
             data_level + 1,
-        );
+        )?;
         if need_blk_num > index_blk.len() {
             // 扩容
-            for _ in index_blk.len()..need_blk_num {
-                match self.alloc_block(false) {
-                    None => return Err(ENOSPC),
-                    Some(data_id) => index_blk.push(data_id),
-                }
+            while index_blk.len() < need_blk_num {
+                let hint = index_blk.last().map(|id| id + 1);
+                let extent = self.alloc_extent(need_blk_num - index_blk.len(), hint)?;
+                index_blk.extend(extent);
             }
         } else if need_blk_num < index_blk.len() {
             // 缩容
             for _ in need_blk_num..index_blk.len() {
-                self.free_block(index_blk.pop().unwrap(), false, true);
+                self.free_block(index_blk.pop().unwrap(), false, true)?;
             }
         }
-        let mut offset = 0;
         for i in 0..need_blk_num {
             // 需要写几块
-            self.modify_data(index_blk[i], |data| {
-                let end = BLOCK_SIZE.min(buf.len() - offset);
-                data[..end]
-                    .copy_from_slice(&buf[i * BLOCK_SIZE..buf.len().min((i + 1) * BLOCK_SIZE)]);
+            self.index_block_cache(index_blk[i])?.lock().unwrap().modify(0, |data: &mut DataBlock| {
+                data.copy_from_slice(&buf[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE]);
             });
-            offset += BLOCK_SIZE
         }
         self.make_index_part(inode, index_blk, data_level + 1)
     }
 
-    pub fn make_indexes(&mut self, data_blocks: Vec<usize>, level: u8) -> (IndexNode, u8) {
-        let index_node_list = IndexNode::from(data_blocks.clone());
+    /// 为数据块建立索引,返回根节点和 level,没有数据块时返回空节点
+    pub fn make_indexes(&mut self, data_blocks: Vec<usize>, level: u8) -> Result<(IndexNode, u8), ErrorCode> {
+        let index_node_list = IndexNode::from(data_blocks);
         if index_node_list.is_empty() {
-            panic!("Empty data blocks![{:?}]", data_blocks);
-            // return (IndexNode::default(),0);
+            return Ok((IndexNode::default(), 0));
         }
         if index_node_list.len() > 1 {
-            let buf: Vec<u8> = index_node_list
-                .iter()
-                .map(|v| unsafe {
-                    std::slice::from_raw_parts(
-                        v as *const IndexNode as *const u8,
-                        size_of::<IndexNode>(),
-                    )
-                })
-                .flatten()
-                .cloned()
-                .collect();
-            self.write_data(buf.as_slice(), level);
+            let buf = IndexNode::pack(&index_node_list);
+            return self.write_data(buf.as_slice(), level);
         }
-        return (index_node_list[0], level);
+        Ok((index_node_list[0], level))
     }
 }
 
-impl From<Arc<dyn BlockDevice>> for BlockCacheDevice {
-    fn from(value: Arc<dyn BlockDevice>) -> Self {
+impl Drop for BlockCacheDevice {
+    fn drop(&mut self) {
+        self.write_back.stop();
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+        // 脏块随缓存释放写回前需先经过日志
+        if self.journal_mode() != JournalMode::None {
+            if let Err(e) = self.journal_commit() {
+                error!("Journal commit failed on drop, errno {}", e);
+            }
+        }
+    }
+}
+
+impl TryFrom<Arc<dyn BlockDevice>> for BlockCacheDevice {
+    type Error = io::Error;
+
+    fn try_from(value: Arc<dyn BlockDevice>) -> io::Result<Self> {
         BlockCacheDevice::new(value)
     }
 }
 
 /// 初始化接口
 impl BlockCacheDevice {
-    pub fn mkfs(&mut self, block_size: usize) {
-        // 清空磁盘所有块
-        for blk_id in 0..block_size {
-            self.block_cache(blk_id).lock().unwrap().free()
+    /// blocks: 文件系统大小,None 时使用整个设备
+    pub fn mkfs(&mut self, blocks: Option<usize>) -> Result<(), ErrorCode> {
+        let options = match blocks {
+            Some(blocks) => MkfsOptions::new().blocks(blocks),
+            None => MkfsOptions::new(),
+        };
+        self.mkfs_with(&options).map(|_| ())
+    }
+
+    /// 按给定参数格式化,返回写入的超级块
+    pub fn mkfs_with(&mut self, options: &MkfsOptions) -> Result<SuperBlock, ErrorCode> {
+        let device_blocks = self.device.blocks().map_err(io_error)?;
+        let block_size = options.blocks.unwrap_or(device_blocks);
+        if block_size > device_blocks || block_size <= 10 {
+            error!("Can not make file system of {} blocks on device of {} blocks", block_size, device_blocks);
+            return Err(EINVAL);
+        }
+        if options.bytes_per_inode < 2 * INODE_SIZE || options.reserved_percent > 50 {
+            error!("Invalid mkfs options: {:?}", options);
+            return Err(EINVAL);
+        }
+        let journal_blocks = match options.journal {
+            JournalMode::None => 0,
+            _ => options.journal_blocks.unwrap_or((block_size / 32).clamp(MKFS_MIN_JOURNAL_BLOCKS, 8192)),
+        };
+        if journal_blocks != 0 && journal_blocks < MKFS_MIN_JOURNAL_BLOCKS || journal_blocks + 10 >= block_size {
+            error!("Invalid journal size {} for {} blocks", journal_blocks, block_size);
+            return Err(EINVAL);
+        }
+        let mut super_block = match options.blocks_per_group {
+            None => SuperBlock::new(block_size - journal_blocks, options.bytes_per_inode),
+            Some(blocks_per_group) => {
+                SuperBlock::with_groups(block_size - journal_blocks, options.bytes_per_inode, blocks_per_group)
+                    .ok_or_else(|| {
+                        error!("Can not divide {} blocks into groups of {} blocks", block_size - journal_blocks, blocks_per_group);
+                        EINVAL
+                    })?
+            }
+        };
+        super_block.journal_blocks = journal_blocks;
+        if journal_blocks != 0 {
+            super_block.feature_compat |= COMPAT_JOURNAL;
+        }
+        super_block.journal_mode = options.journal as u8;
+        super_block.reserved_blocks = super_block.data_blocks * options.reserved_percent as usize / 100;
+        if options.checksums {
+            super_block.feature_ro_compat |= RO_COMPAT_METADATA_CSUM;
+        }
+        super_block.label = options.label;
+        super_block.uuid = random_uuid();
+        super_block.write_time = time_sys(SystemTime::now());
+        // 丢弃旧缓存,避免其中的旧数据覆盖清零后的块
+        self.journal = None;
+        self.pinned.clear();
+        self.caches.clear();
+        self.inodes.clear();
+        self.dentries.clear();
+        self.allocator.reset();
+        self.metadata_cached = 0;
+        self.counters.cached_blocks.store(0, Ordering::Relaxed);
+        self.write_back.set_journaling(false);
+        // 清空磁盘,fast 模式只清空元数据区域,数据块在分配时清零
+        let zero = [0u8; BLOCK_SIZE];
+        for blk_id in (1..block_size).filter(|v| !options.fast || super_block.is_metadata(*v)) {
+            self.device.write(blk_id, &zero).map_err(io_error)?;
         }
         // 初始化超级块
         self.super_block
             .lock()
             .unwrap()
-            .modify(0, |sb: &mut SuperBlock| {
-                *sb = SuperBlock::new(block_size);
+            .modify(0, |blk: &mut DataBlock| {
+                blk.fill(0);
             });
-        self.super_block.lock().unwrap().sync();
-        self.clear_bitmap();
+        self.modify_super_block(|sb| *sb = super_block);
+        self.super_block.lock().unwrap().sync().map_err(io_error)?;
+        self.init_groups(&super_block)?;
+        // 超级块备份所在的数据块不会被分配
+        for blk in super_block.backups() {
+            self.set(super_block.data_id(blk).unwrap(), false, true)?;
+        }
         // self.print();
-        // 创建根节点
-        self.mk_root();
-        // 同步至磁盘
-        self.sync();
-    }
-    pub fn sync(&mut self) {
-        self.caches
-            .iter()
-            .for_each(|(_, c)| c.lock().unwrap().sync());
-        self.device.sync();
-    }
-    pub fn flush_internal(&mut self, inode: &InodeWithId) {
-        let mut data_blocks = self.inode_data_blk_list(inode.inode());
+        // 创建根节点与 lost+found
+        self.mk_root()?;
+        self.lost_found()?;
+        // 同步至磁盘,初始内容直接写入,之后的修改才经过日志
+        self.sync()?;
+        self.init_journal(&super_block)?;
+        Ok(super_block)
+    }
+    /// 超级块描述的布局需自洽且不能超出设备末尾
+    pub fn check_geometry(&self) -> Result<(), ErrorCode> {
+        let device_blocks = self.device.blocks().map_err(io_error)?;
+        self.super_block().validate(device_blocks).map_err(|e| {
+            error!("Invalid super block: {}", e);
+            EINVAL
+        })
+    }
+    /// 启用日志时所有脏块作为一个事务提交
+    pub fn sync(&mut self) -> Result<(), ErrorCode> {
+        if self.journal_mode() != JournalMode::None {
+            self.journal_commit()?;
+        } else {
+            self.write_back.flush(&*self.device).map_err(io_error)?;
+        }
+        self.sync_backups()
+    }
+    /// 启动后台写回线程,write-through 或写回间隔为 0 时不启动
+    pub fn start_flusher(&mut self) -> io::Result<()> {
+        let interval = self.cache_options.flush_interval;
+        if self.flusher.is_some() || interval.is_zero() || self.cache_options.write_policy != WritePolicy::WriteBack {
+            return Ok(());
+        }
+        let write_back = self.write_back.clone();
+        let device = self.device.clone();
+        self.flusher = Some(std::thread::Builder::new()
+            .name("exfs-writeback".to_string())
+            .spawn(move || write_back.run(device, interval))?);
+        Ok(())
+    }
+    pub fn flush_internal(&mut self, inode: &InodeWithId) -> Result<(), ErrorCode> {
+        if self.journal_mode() != JournalMode::None {
+            return self.journal_commit();
+        }
+        let mut data_blocks = self.inode_data_blk_list(inode.inode())?;
         data_blocks = data_blocks.iter().map(|data_id| {
             self.data_block(*data_id)
         }).collect();
         let (inode_blk, _) = self.inode_block(inode.inode);
         data_blocks.push(inode_blk);
-        self.caches
-            .iter()
-            .filter(|(id, _)| {
-                data_blocks.contains(*id)
-            })
-            .for_each(|(_, c)| c.lock().unwrap().sync());
-        self.device.sync();
-    }
-    fn mk_root(&mut self) {
-        let inode = self.alloc_block(true).unwrap();
+        for c in data_blocks.iter().filter_map(|id| self.caches.peek(id)) {
+            c.lock().unwrap().sync().map_err(io_error)?;
+        }
+        self.device.sync().map_err(io_error)
+    }
+    fn mk_root(&mut self) -> Result<(), ErrorCode> {
+        let inode = self.alloc_block(true)?;
         self.modify_inode(inode, |root| {
             *root = Inode::new((FileType::Dir as u16) << 12 | 0b111101101, 0, 0);
             root.size = BLOCK_SIZE as u64
         })
     }
 }
+
+#[test]
+fn open_existing() {
+    use crate::block_device::ram_device::RamDevice;
+
+    let ram = Arc::new(RamDevice::new(1024));
+    let err = BlockCacheDevice::open(ram.clone()).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    let mut fs = BlockCacheDevice::new(ram.clone()).unwrap();
+    fs.mkfs(None).unwrap();
+    drop(fs);
+    let mut fs = BlockCacheDevice::open(ram).unwrap();
+    assert!(fs.inode(1).unwrap().is_dir());
+}
+
+#[test]
+fn one_buffer_per_block() {
+    use crate::config::CACHE_BLOCKS;
+
+    let (_, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let held = fs.block_cache(fs.data_block(100)).unwrap();
+    fs.modify_data(200, |data| data[0] = 2).unwrap();
+    // 超出容量,200 被淘汰,100 仍被引用
+    for id in 300..300 + 2 * CACHE_BLOCKS {
+        fs.data(id, 0, |_: &u8| {}).unwrap();
+    }
+    assert!(fs.caches.len() <= CACHE_BLOCKS);
+    held.lock().unwrap().modify(0, |v: &mut u8| *v = 1);
+    assert!(Arc::ptr_eq(&held, &fs.block_cache(fs.data_block(100)).unwrap()));
+    let mut v = 0;
+    fs.data(200, 0, |data: &u8| v = *data).unwrap();
+    assert_eq!(v, 2);
+    drop(held);
+    for id in 300..300 + 2 * CACHE_BLOCKS {
+        fs.data(id, 0, |_: &u8| {}).unwrap();
+    }
+    fs.data(100, 0, |data: &u8| v = *data).unwrap();
+    assert_eq!(v, 1);
+}
+
+#[test]
+fn metadata_cache_budget() {
+    let (ram, fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    drop(fs);
+    let options = CacheOptions::new().blocks(16).metadata_blocks(8);
+    let mut fs = BlockCacheDevice::open_with_cache(ram, options).unwrap();
+    let (inode_blk, _) = fs.inode_block(1);
+    fs.inode(1).unwrap();
+    // 顺序读取大量数据块不会淘汰 inode 块
+    for id in 0..64 {
+        fs.data(id, 0, |_: &u8| {}).unwrap();
+    }
+    assert!(fs.caches.contains(&inode_blk));
+    assert!(fs.caches.len() <= 16 + 8);
+
+    fs.cache_options = CacheOptions::new().blocks(16);
+    for id in 0..64 {
+        fs.data(id, 0, |_: &u8| {}).unwrap();
+    }
+    assert!(!fs.caches.contains(&inode_blk));
+    assert_eq!(fs.caches.len(), 16);
+}
+
+#[test]
+fn write_data_in_runs() {
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    // 已缓存的空闲块也要与直接写入的内容一致
+    fs.data(3, 0, |_: &u8| {}).unwrap();
+    let buf: Vec<u8> = (0..4 * BLOCK_SIZE + 10).map(|i| (i % 251) as u8).collect();
+    let (node, level) = fs.write_data(&buf, 0).unwrap();
+    assert_eq!(level, 1);
+    let mut cached = Vec::new();
+    for id in node.list(&mut fs, level).unwrap() {
+        fs.data(id, 0, |data: &DataBlock| cached.extend_from_slice(data)).unwrap();
+    }
+    assert_eq!(&cached[..buf.len()], &buf[..]);
+    assert!(cached[buf.len()..].iter().all(|v| *v == 0));
+    let start = fs.data_block(node.range().start) * BLOCK_SIZE;
+    assert_eq!(&ram.snapshot()[start..start + cached.len()], &cached[..]);
+
+    // 新块不连续时数据块列表写入索引块,返回索引的根节点
+    for id in 0..fs.super_block().data_blocks {
+        if id % 2 == 0 && !fs.used(id, false).unwrap() {
+            fs.set(id, false, true).unwrap();
+        }
+    }
+    let (node, level) = fs.write_data(&buf, 0).unwrap();
+    assert_eq!(level, 2);
+    let ids = node.list(&mut fs, level).unwrap();
+    assert_eq!(ids.len(), 5);
+    let mut data = Vec::new();
+    for id in ids {
+        fs.data(id, 0, |block: &DataBlock| data.extend_from_slice(block)).unwrap();
+    }
+    assert_eq!(&data[..buf.len()], &buf[..]);
+    let (node, level) = fs.make_indexes(Vec::new(), 0).unwrap();
+    assert!(node.range().is_empty() && level == 0);
+}
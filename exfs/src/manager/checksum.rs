@@ -0,0 +1,148 @@
+use std::sync::{Arc, Mutex};
+
+use log::error;
+
+use crate::cache::block_cache::CacheBlock;
+use crate::layout::data_block::{dir_block_ok, DirBlock};
+use crate::layout::index_node::{index_block_ok, IndexBlock};
+use crate::layout::inode::Inode;
+use crate::manager::block_cache_manager::BlockCacheDevice;
+use crate::manager::error_code::{EIO, ErrorCode};
+use crate::manager::stats::count;
+
+/// 元数据校验
+/// 写入时总是计算校验和,超级块启用 RO_COMPAT_METADATA_CSUM 时读取才校验
+impl BlockCacheDevice {
+    pub fn metadata_csum(&self) -> bool {
+        self.super_block().metadata_csum()
+    }
+
+    /// 读取 inode,不经过 inode 缓存也不校验,供 fsck 检查损坏的 inode
+    pub fn inode_unchecked(&mut self, id: usize) -> Result<Inode, ErrorCode> {
+        let (blk_id, offset) = self.inode_block(id);
+        Ok(self.block_cache(blk_id)?.lock().unwrap().read(offset, |i: &Inode| *i))
+    }
+
+    /// 读取 inode 并校验,不符时返回 EIO
+    pub(crate) fn load_inode(&mut self, id: usize) -> Result<Inode, ErrorCode> {
+        let inode = self.inode_unchecked(id)?;
+        if self.metadata_csum() && !inode.checksum_ok(id) {
+            let (blk_id, _) = self.inode_block(id);
+            return Err(self.corrupted("inode", id, blk_id));
+        }
+        Ok(inode)
+    }
+
+    /// 读取索引块,id 为数据块逻辑 id,校验和不符时返回 EIO
+    pub fn index_block(&mut self, id: usize) -> Result<IndexBlock, ErrorCode> {
+        let cache = self.index_block_cache(id)?;
+        self.verify(&cache, "index block", id, index_block_ok)?;
+        let data = cache.lock().unwrap().read(0, |data: &IndexBlock| *data);
+        Ok(data)
+    }
+
+    /// 读取目录块,id 为数据块逻辑 id,校验和不符时返回 EIO
+    pub fn dir_block(&mut self, id: usize) -> Result<DirBlock, ErrorCode> {
+        let cache = self.block_cache(self.data_block(id))?;
+        self.verify(&cache, "directory block", id, dir_block_ok)?;
+        let data = cache.lock().unwrap().read(0, |data: &DirBlock| *data);
+        Ok(data)
+    }
+
+    /// 每次从设备读入或修改后只校验一次
+    fn verify<T>(
+        &self,
+        cache: &Arc<Mutex<CacheBlock>>,
+        kind: &str,
+        id: usize,
+        ok: impl FnOnce(&T) -> bool,
+    ) -> Result<(), ErrorCode> {
+        if !self.metadata_csum() {
+            return Ok(());
+        }
+        let mut blk = cache.lock().unwrap();
+        if blk.is_verified() {
+            return Ok(());
+        }
+        if !blk.read(0, ok) {
+            return Err(self.corrupted(kind, id, blk.block()));
+        }
+        blk.set_verified();
+        Ok(())
+    }
+
+    /// 记录损坏的元数据,返回 EIO
+    fn corrupted(&self, kind: &str, id: usize, block: usize) -> ErrorCode {
+        error!("Checksum mismatch in {} {} (block {}), metadata is corrupted", kind, id, block);
+        count(&self.counters.checksum_errors, 1);
+        EIO
+    }
+}
+
+#[test]
+fn detect_corruption() {
+    use crate::block_device::ram_device::RamDevice;
+    use crate::config::BLOCK_SIZE;
+    use crate::layout::super_block::FsState;
+    use crate::manager::error_code::ENOENT;
+    use crate::manager::fsck::Problem;
+    use crate::manager::mkfs::MkfsOptions;
+
+    // 翻转镜像中的一位后重新打开
+    let flip = |image: &[u8], blk: usize, offset: usize| {
+        let mut image = image.to_vec();
+        image[blk * BLOCK_SIZE + offset] ^= 1;
+        BlockCacheDevice::open(Arc::new(RamDevice::from_snapshot(image).unwrap())).unwrap()
+    };
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let a = fs.create_file("a", &[]).unwrap();
+    // 每写一块就占用其后的一块,使 a 的数据块不连续,索引升为两级
+    for i in 0..4 {
+        let inode = fs.inode(a).unwrap().with_id(a);
+        fs.write_system(i * BLOCK_SIZE, &inode, &[1u8; BLOCK_SIZE], false).unwrap();
+        let inode = fs.inode(a).unwrap();
+        let last = *fs.inode_data_blk_list(&inode).unwrap().last().unwrap();
+        fs.set(last + 1, false, true).unwrap();
+    }
+    let inode = fs.inode(a).unwrap();
+    assert_eq!(inode.index_level, 2);
+    let index_id = inode.index_node.start_blk;
+    let root = fs.inode(1).unwrap();
+    let dir_id = fs.inode_data_blk_list(&root).unwrap()[0];
+    let (inode_blk, offset) = fs.inode_block(a);
+    let (index_blk, dir_blk) = (fs.data_block(index_id), fs.data_block(dir_id));
+    drop(fs);
+    let image = ram.snapshot();
+
+    let mut fs = flip(&image, dir_blk, 0);
+    let root = fs.inode(1).unwrap().with_id(1);
+    assert_eq!(fs.lookup_internal(&root, "a".into()).err(), Some(EIO));
+    assert_eq!(fs.stats().checksum_errors, 1);
+    let report = fs.fsck(false).unwrap();
+    assert!(report.problems.contains(&Problem::BlockChecksum { inode: 1, block: dir_id }), "{:?}", report.problems);
+
+    let mut fs = flip(&image, inode_blk, offset + 16);
+    assert_eq!(fs.inode(a).err(), Some(EIO));
+    assert!(fs.fsck(false).unwrap().problems.contains(&Problem::InodeChecksum(a)));
+
+    let mut fs = flip(&image, index_blk, 0);
+    let inode = fs.inode(a).unwrap();
+    assert_eq!(fs.inode_data_blk_list(&inode).err(), Some(EIO));
+    assert!(fs.fsck(false).unwrap().problems.contains(&Problem::BlockChecksum { inode: a, block: index_id }));
+
+    // 超级块损坏时使用备份
+    let fs = flip(&image, 0, 0);
+    assert!(fs.metadata_csum());
+    assert_eq!(fs.super_block().state(), FsState::Dirty);
+
+    // 未启用校验和的镜像不检查
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new().checksums(false));
+    let root = fs.inode(1).unwrap();
+    let dir_id = fs.inode_data_blk_list(&root).unwrap()[0];
+    let dir_blk = fs.data_block(dir_id);
+    drop(fs);
+    let mut fs = flip(&ram.snapshot(), dir_blk, 0);
+    let root = fs.inode(1).unwrap().with_id(1);
+    assert_eq!(fs.lookup_internal(&root, "lost+found".into()).err(), Some(ENOENT));
+    assert_eq!(fs.ls_internal(root.inode()).unwrap().len(), 1);
+}
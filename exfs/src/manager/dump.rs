@@ -0,0 +1,279 @@
+use serde::Serialize;
+
+use crate::config::BLOCK_SIZE;
+use crate::manager::block_cache_manager::BlockCacheDevice;
+use crate::manager::error_code::{EIO, ErrorCode};
+
+/// 镜像布局与使用情况,可序列化为 JSON
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageDump {
+    pub super_block: SuperBlockDump,
+    pub regions: Vec<RegionDump>,
+    pub inodes: BitmapUsage,
+    pub data_blocks: BitmapUsage,
+    pub files: Vec<FileDump>,
+    pub fragmentation: Fragmentation,
+    /// 未启用块组时为空
+    pub groups: Vec<GroupDump>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SuperBlockDump {
+    pub valid: bool,
+    pub label: String,
+    pub uuid: String,
+    /// clean 或 not clean
+    pub state: String,
+    pub mount_count: u32,
+    pub mount_time: u64,
+    pub write_time: u64,
+    pub fsck_time: u64,
+    pub version: u32,
+    pub feature_compat: u32,
+    pub feature_ro_compat: u32,
+    pub feature_incompat: u32,
+    /// 超级块备份所在的物理块
+    pub backups: Vec<usize>,
+    pub block_size: usize,
+    pub blocks: usize,
+    pub inode_bitmap_blocks: usize,
+    pub bitmap_blocks: usize,
+    pub inode_blocks: usize,
+    pub data_blocks: usize,
+    pub reserved_blocks: usize,
+    pub blocks_per_group: usize,
+    pub inodes_per_group: usize,
+}
+
+/// 组描述符,start 与 end 为块组的物理块范围
+#[derive(Clone, Debug, Serialize)]
+pub struct GroupDump {
+    pub group: usize,
+    pub start: usize,
+    pub end: usize,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+}
+
+/// 物理块区间 [start, end)
+#[derive(Clone, Debug, Serialize)]
+pub struct RegionDump {
+    pub name: &'static str,
+    pub start: usize,
+    pub end: usize,
+    pub blocks: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BitmapUsage {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// 数据块区间,start 为逻辑 id
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Extent {
+    pub start: usize,
+    pub len: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FileDump {
+    pub inode: usize,
+    pub file_type: String,
+    pub size: u64,
+    pub link_count: u32,
+    pub index_level: u8,
+    /// inode 或索引块校验和不符时为 false,此时不列出区间
+    pub checksum_ok: bool,
+    /// 按文件顺序排列的数据块区间
+    pub extents: Vec<Extent>,
+    pub index_blocks: Vec<usize>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Fragmentation {
+    /// 有数据块的文件数
+    pub files: usize,
+    /// 数据块分散在多个区间的文件数
+    pub fragmented_files: usize,
+    pub extents: usize,
+    pub extents_per_file: f64,
+    /// 空闲数据块组成的连续区间数与最长区间
+    pub free_extents: usize,
+    pub largest_free_extent: usize,
+}
+
+impl ImageDump {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// 布局导出
+impl BlockCacheDevice {
+    /// 导出超级块、区域、位图使用情况与每个文件的区间
+    /// inode 不经过缓存也不校验,损坏的 inode 记录在对应文件中而不中止导出
+    pub fn dump(&mut self) -> Result<ImageDump, ErrorCode> {
+        let sb = self.super_block();
+        let super_block = SuperBlockDump {
+            valid: sb.is_valid(),
+            label: sb.label(),
+            uuid: sb.uuid(),
+            state: sb.state().to_string(),
+            mount_count: sb.mount_count,
+            mount_time: sb.mount_time,
+            write_time: sb.write_time,
+            fsck_time: sb.fsck_time,
+            version: sb.version,
+            feature_compat: sb.feature_compat,
+            feature_ro_compat: sb.feature_ro_compat,
+            feature_incompat: sb.feature_incompat,
+            backups: sb.backups(),
+            block_size: BLOCK_SIZE,
+            blocks: sb.blocks(),
+            inode_bitmap_blocks: sb.inode_bitmap_blocks,
+            bitmap_blocks: sb.bitmap_blocks,
+            inode_blocks: sb.inode_blocks,
+            data_blocks: sb.data_blocks,
+            reserved_blocks: sb.reserved_blocks,
+            blocks_per_group: sb.blocks_per_group,
+            inodes_per_group: sb.inodes_per_group,
+        };
+        let regions = sb.regions().into_iter()
+            .map(|(name, range)| RegionDump { name, start: range.start, end: range.end, blocks: range.len() })
+            .collect();
+
+        let mut used_inodes = 0;
+        let mut files = Vec::new();
+        for id in 1..=sb.inode_size() {
+            if self.used(id - 1, true)? {
+                used_inodes += 1;
+            }
+            let inode = self.inode_unchecked(id)?;
+            let mut checksum_ok = !self.metadata_csum() || inode.checksum_ok(id);
+            if checksum_ok && !inode.exist() {
+                continue;
+            }
+            let mut index_blocks = Vec::new();
+            let mut extents = Vec::new();
+            if checksum_ok {
+                match inode.index_node.extents(self, inode.index_level, &mut index_blocks) {
+                    Ok(v) => extents = v.iter().map(|v| Extent { start: v.start_blk, len: v.len }).collect(),
+                    Err(EIO) => {
+                        checksum_ok = false;
+                        index_blocks.clear();
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            files.push(FileDump {
+                inode: id,
+                file_type: format!("{:?}", inode.file_type()),
+                size: inode.size,
+                link_count: inode.link_count,
+                index_level: inode.index_level,
+                checksum_ok,
+                extents,
+                index_blocks,
+            });
+        }
+
+        let mut fragmentation = Fragmentation::default();
+        let mut used_blocks = 0;
+        let mut free_run = 0;
+        for id in 0..=sb.data_blocks {
+            if id < sb.data_blocks && !self.used(id, false)? {
+                free_run += 1;
+                continue;
+            }
+            used_blocks += (id < sb.data_blocks) as usize;
+            if free_run > 0 {
+                fragmentation.free_extents += 1;
+                fragmentation.largest_free_extent = fragmentation.largest_free_extent.max(free_run);
+                free_run = 0;
+            }
+        }
+        for file in files.iter().filter(|v| !v.extents.is_empty()) {
+            fragmentation.files += 1;
+            fragmentation.extents += file.extents.len();
+            if file.extents.len() > 1 {
+                fragmentation.fragmented_files += 1;
+            }
+        }
+        if fragmentation.files > 0 {
+            fragmentation.extents_per_file = fragmentation.extents as f64 / fragmentation.files as f64;
+        }
+        let mut groups = Vec::new();
+        for group in 0..sb.groups() {
+            let desc = self.group_desc(group)?;
+            let range = sb.group(group);
+            groups.push(GroupDump {
+                group,
+                start: range.start,
+                end: range.end,
+                free_blocks: desc.free_blocks,
+                free_inodes: desc.free_inodes,
+            });
+        }
+
+        Ok(ImageDump {
+            super_block,
+            regions,
+            inodes: BitmapUsage { total: sb.inode_size(), used: used_inodes, free: sb.inode_size() - used_inodes },
+            data_blocks: BitmapUsage { total: sb.data_blocks, used: used_blocks, free: sb.data_blocks - used_blocks },
+            files,
+            fragmentation,
+            groups,
+        })
+    }
+}
+
+#[test]
+fn dump_after_write() {
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (_, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let file = fs.create_file("file", &[1u8; 3 * BLOCK_SIZE]).unwrap();
+
+    let dump = fs.dump().unwrap();
+    assert_eq!(dump.inodes.used, 3);
+    // 根目录、文件各占用数据块,lost+found 为空,超级块备份占用的块把空闲区间分开
+    let backups = dump.super_block.backups.len();
+    assert_eq!(dump.data_blocks.used, 4 + backups);
+    assert_eq!(dump.data_blocks.used + dump.data_blocks.free, dump.super_block.data_blocks);
+    let file = dump.files.iter().find(|v| v.inode == file).unwrap();
+    assert_eq!(file.extents, vec![Extent { start: 1, len: 3 }]);
+    assert_eq!(dump.fragmentation.fragmented_files, 0);
+    assert_eq!(dump.fragmentation.free_extents, 1 + backups);
+    let json: serde_json::Value = serde_json::from_str(&dump.to_json()).unwrap();
+    assert_eq!(json["regions"][4]["name"], "data");
+}
+
+#[test]
+fn dump_with_bad_inode() {
+    use std::sync::Arc;
+
+    use crate::block_device::ram_device::RamDevice;
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let a = fs.create_file("a", &[1u8; BLOCK_SIZE]).unwrap();
+    let b = fs.create_file("b", &[2u8; BLOCK_SIZE]).unwrap();
+    let (blk, offset) = fs.inode_block(a);
+    drop(fs);
+    let mut image = ram.snapshot();
+    image[blk * BLOCK_SIZE + offset + 16] ^= 1;
+    let mut fs = BlockCacheDevice::open(Arc::new(RamDevice::from_snapshot(image).unwrap())).unwrap();
+
+    // 损坏的 inode 单独标记,其余文件照常导出
+    let dump = fs.dump().unwrap();
+    let file = dump.files.iter().find(|v| v.inode == a).unwrap();
+    assert!(!file.checksum_ok);
+    assert!(file.extents.is_empty());
+    let file = dump.files.iter().find(|v| v.inode == b).unwrap();
+    assert!(file.checksum_ok);
+    assert_eq!(file.extents.len(), 1);
+    assert_eq!(dump.files.iter().filter(|v| !v.checksum_ok).count(), 1);
+}
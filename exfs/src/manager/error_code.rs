@@ -1,4 +1,6 @@
 use libc::c_int;
+use log::error;
+
 pub type ErrorCode = c_int;
 #[allow(unused)]
 // EPERM: Operation not permitted
@@ -105,3 +107,9 @@ pub const ERANGE: c_int = 34;
 #[allow(unused)]
 // EWOULDBLOCK: Operation would block
 pub const EWOULDBLOCK: c_int = EAGAIN;
+
+/// 设备层的 io::Error 统一转换为 EIO 返回给 FUSE
+pub fn io_error(e: std::io::Error) -> ErrorCode {
+    error!("Block device I/O error: {}", e);
+    EIO
+}
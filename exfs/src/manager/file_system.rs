@@ -14,7 +14,6 @@ use crate::manager::error_code::{EBADF, EEXIST, EIO, ENOENT, ENOTDIR, EPERM, Err
 use crate::typ::file_name::FileName;
 use crate::typ::file_type::FileType;
 use crate::typ::request::{Mask, Req};
-use crate::utils::slice::vec2slice;
 use crate::utils::time::{time_sec, time_sys};
 
 /// 上层接口，实现了权限管理
@@ -25,18 +24,12 @@ impl BlockCacheDevice {
         _parent: usize,
         name: FileName,
     ) -> Result<InodeWithId, ErrorCode> {
-        let parent = self.inode(_parent);
-        parent.access_guard_f(req, Mask::RX, || match self.ls_internal(&parent) {
-            Ok(v) => match v.iter().find(|entry| name == entry.name) {
-                None => Err(ENOENT),
-                Some(e) => Ok(self.inode(e.inode as usize).with_id(e.inode as usize)),
-            },
-            Err(e) => Err(e),
-        })
+        let parent = self.inode(_parent)?;
+        parent.access_guard_f(req, Mask::RX, || self.lookup_internal(&parent.with_id(_parent), name))
     }
 
     pub fn getattr_guard(&mut self, req: &Req, inode_id: usize) -> Result<InodeWithId, ErrorCode> {
-        let inode = self.inode(inode_id);
+        let inode = self.inode(inode_id)?;
         inode.access_guard(req, Mask::R, inode.with_id(inode_id))
     }
 
@@ -81,12 +74,12 @@ impl BlockCacheDevice {
             } else {
                 Err(EPERM)
             }
-        })
+        })?
     }
 
     pub fn readlink_guard(&mut self, req: &Req, inode_id: usize) -> Result<Vec<u8>, ErrorCode> {
-        let inode = self.inode(inode_id);
-        inode.access_guard(req, Mask::R, self.read_all(inode_id))
+        let inode = self.inode(inode_id)?;
+        inode.access_guard_f(req, Mask::R, || self.read_all(inode_id))
     }
 
     pub fn mknod_guard(
@@ -98,16 +91,18 @@ impl BlockCacheDevice {
         _umask: u32,
         _rdev: u32,
     ) -> Result<InodeWithId, ErrorCode> {
-        let parent = self.inode(_parent);
-        parent.access_guard_f(req, Mask::WX, || {
-            self.make_node_internal(
-                String::from(_name).as_str(),
-                &parent.with_id(_parent),
-                _mode as u16,
-                req.uid,
-                req.gid,
-            )
-                .map(|v| self.inode(v).with_id(v))
+        self.as_user(req.uid, |fs| {
+            let parent = fs.inode(_parent)?;
+            parent.access_guard_f(req, Mask::WX, || {
+                fs.make_node_internal(
+                    String::from(_name).as_str(),
+                    &parent.with_id(_parent),
+                    _mode as u16,
+                    req.uid,
+                    req.gid,
+                )
+                    .and_then(|v| Ok(fs.inode(v)?.with_id(v)))
+            })
         })
     }
 
@@ -119,28 +114,30 @@ impl BlockCacheDevice {
         _mode: u32,
         _umask: u32,
     ) -> Result<InodeWithId, ErrorCode> {
-        let parent = self.inode(_parent);
-        parent.access_guard_f(req, Mask::WX, || {
-            self.make_node_internal(
-                String::from(_name).as_str(),
-                &parent.with_id(_parent),
-                FileType::Dir << 12 | _mode as u16,
-                req.uid,
-                req.gid,
-            )
-                .map(|v| self.inode(v).with_id(v))
+        self.as_user(req.uid, |fs| {
+            let parent = fs.inode(_parent)?;
+            parent.access_guard_f(req, Mask::WX, || {
+                fs.make_node_internal(
+                    String::from(_name).as_str(),
+                    &parent.with_id(_parent),
+                    FileType::Dir << 12 | _mode as u16,
+                    req.uid,
+                    req.gid,
+                )
+                    .and_then(|v| Ok(fs.inode(v)?.with_id(v)))
+            })
         })
     }
 
     pub fn unlink_guard(&mut self, req: &Req, _parent: usize, _name: FileName) -> Result<(), ErrorCode> {
-        let parent = self.inode(_parent);
+        let parent = self.inode(_parent)?;
         parent.access_guard_f(req, Mask::WX, || {
             self.unlink_internal(&parent.with_id(_parent), _name.into())
         })
     }
 
     pub fn rmdir_guard(&mut self, req: &Req, _parent: usize, _name: FileName) -> Result<(), ErrorCode> {
-        let parent = self.inode(_parent).with_id(_parent);
+        let parent = self.inode(_parent)?.with_id(_parent);
         self.lookup_guard(req, _parent, _name)
             .and_then(|v| {
                 self.remove_dir_internal(&v).and_then(|_| {
@@ -157,22 +154,24 @@ impl BlockCacheDevice {
         _name: FileName,
         _link: &Path,
     ) -> Result<InodeWithId, ErrorCode> {
-        // debug!("SymLink: {:?}", _name)
-        let parent = self.inode(_parent);
-        parent.access_guard_f(req, Mask::WX, || {
-            self.make_node_internal(
-                String::from(_name).as_str(),
-                &parent.with_id(_parent),
-                FileType::SymbolLink << 12 | 0o744u16,
-                req.uid,
-                req.gid,
-            )
-                .and_then(|v| {
-                    let buf = _link.to_str().unwrap();
-                    let inode = self.inode(v);
-                    self.write_system(0, &inode.with_id(v), buf.as_ref(), true)
-                        .map(|_| inode.with_id(v))
-                })
+        self.as_user(req.uid, |fs| {
+            // debug!("SymLink: {:?}", _name)
+            let parent = fs.inode(_parent)?;
+            parent.access_guard_f(req, Mask::WX, || {
+                fs.make_node_internal(
+                    String::from(_name).as_str(),
+                    &parent.with_id(_parent),
+                    FileType::SymbolLink << 12 | 0o744u16,
+                    req.uid,
+                    req.gid,
+                )
+                    .and_then(|v| {
+                        let buf = _link.to_str().unwrap();
+                        let inode = fs.inode(v)?;
+                        fs.write_system(0, &inode.with_id(v), buf.as_ref(), true)
+                            .map(|_| inode.with_id(v))
+                    })
+            })
         })
     }
 
@@ -185,16 +184,18 @@ impl BlockCacheDevice {
         _new_name: FileName,
         _flags: u32,
     ) -> Result<(), ErrorCode> {
-        let parent = self.inode(_parent);
-        let new_parent = self.inode(_new_parent);
-        parent.access_guard_f(req, Mask::WX, || {
-            new_parent.access_guard_f(req, Mask::WX, || {
-                self.rename_internal(
-                    &parent.with_id(_parent),
-                    _name.into(),
-                    &new_parent.with_id(_new_parent),
-                    _new_name.into(),
-                )
+        self.as_user(req.uid, |fs| {
+            let parent = fs.inode(_parent)?;
+            let new_parent = fs.inode(_new_parent)?;
+            parent.access_guard_f(req, Mask::WX, || {
+                new_parent.access_guard_f(req, Mask::WX, || {
+                    fs.rename_internal(
+                        &parent.with_id(_parent),
+                        _name.into(),
+                        &new_parent.with_id(_new_parent),
+                        _new_name.into(),
+                    )
+                })
             })
         })
     }
@@ -206,32 +207,35 @@ impl BlockCacheDevice {
         _new_parent: usize,
         _new_name: FileName,
     ) -> Result<InodeWithId, ErrorCode> {
-        let new_parent = self.inode(_new_parent);
-        new_parent.access_guard_f(req, Mask::WX, || {
-            self.ls_internal(&new_parent).and_then(|mut dirs| {
-                match self.lookup_internal(&new_parent, _new_name.into()) {
-                    Err(ENOENT) => {
-                        dirs.push(DirEntry {
-                            name: _new_name.into(),
-                            inode: _ino as u64,
-                        });
-                        let inode = self.modify_inode(_ino, |ino| {
-                            ino.link_count += 1;
-                            ino.clone()
-                        }).with_id(_ino);
-                        let buf: Vec<u8> = vec2slice(dirs);
-                        self.write_system(0, &new_parent.with_id(_new_parent), &buf, true)
-                            .map(|_| inode)
+        self.as_user(req.uid, |fs| {
+            let new_parent = fs.inode(_new_parent)?;
+            new_parent.access_guard_f(req, Mask::WX, || {
+                fs.ls_internal(&new_parent).and_then(|mut dirs| {
+                    match fs.lookup_internal(&new_parent.with_id(_new_parent), _new_name.into()) {
+                        Err(ENOENT) => {
+                            dirs.push(DirEntry {
+                                name: _new_name.into(),
+                                inode: _ino as u64,
+                            });
+                            let inode = fs.modify_inode(_ino, |ino| {
+                                ino.link_count += 1;
+                                ino.clone()
+                            })?.with_id(_ino);
+                            let buf = DirEntry::pack(&dirs);
+                            fs.write_system(0, &new_parent.with_id(_new_parent), &buf, true)?;
+                            fs.dentries.insert(_new_parent, _new_name, Some(_ino));
+                            Ok(inode)
+                        }
+                        Ok(_) => Err(EEXIST),
+                        Err(e) => Err(e),
                     }
-                    Ok(_) => Err(EEXIST),
-                    Err(e) => Err(e),
-                }
+                })
             })
         })
     }
 
     pub fn open_guard(&mut self, req: &Req, _ino: usize, _flags: i32) -> Result<u32, ErrorCode> {
-        let inode = self.inode(_ino);
+        let inode = self.inode(_ino)?;
         Mask::from_flag(_flags).map_or(Err(EIO), |mask| {
             inode.access_guard_f(req, mask, || {
                 self.open_internal(_ino, 0, _flags, req.pid)
@@ -252,7 +256,7 @@ impl BlockCacheDevice {
             Some(fh) => {
                 let mut fh = fh.clone();
                 fh.seek(offset);
-                Ok(fh.read(self, buf))
+                fh.read(self, buf)
             }
         }
     }
@@ -265,14 +269,16 @@ impl BlockCacheDevice {
         data: &[u8],
         //_lock_owner: Option<u64>,
     ) -> Result<usize, ErrorCode> {
-        match self.fh(fh, req.pid) {
-            None => Err(EBADF),
-            Some(fh) => {
-                let mut fh = fh.clone();
-                fh.seek(offset);
-                fh.write(self, data)
+        self.as_user(req.uid, |fs| {
+            match fs.fh(fh, req.pid) {
+                None => Err(EBADF),
+                Some(fh) => {
+                    let mut fh = fh.clone();
+                    fh.seek(offset);
+                    fh.write(fs, data)
+                }
             }
-        }
+        })
     }
 
     pub fn flush_guard(&mut self, req: &Req, fh: u32) -> Result<(), ErrorCode> {
@@ -280,8 +286,7 @@ impl BlockCacheDevice {
             None => Err(EBADF),
             Some(fh) => {
                 let fh = fh.clone();
-                fh.flush(self);
-                Ok(())
+                fh.flush(self)
             }
         }
     }
@@ -296,7 +301,7 @@ impl BlockCacheDevice {
     }
 
     pub fn opendir_guard(&mut self, req: &Req, _ino: usize, _flags: i32) -> Result<u32, ErrorCode> {
-        let inode = self.inode(_ino);
+        let inode = self.inode(_ino)?;
         if inode.is_dir() {
             self.open_guard(req, _ino, _flags)
         } else {
@@ -313,28 +318,29 @@ impl BlockCacheDevice {
             None => Err(EBADF),
             Some(fh) => {
                 // println!("!!!1");
-                let mut fh = fh.clone();
+                let fh = fh.clone();
                 // println!("!!!2");
                 let mut vec = Vec::new();
-                fh.read_block(self, blk_id, 0, |dirs: &[DirEntry; BLOCK_SIZE / DIR_ENTRY_SIZE]| {
-                    dirs.iter().skip(blk_offset).for_each(|dir| {
+                let data = self.inode_data_blk_list(fh.inode_with_id().inode())?;
+                if let Some(id) = data.get(blk_id) {
+                    self.dir_block(*id)?.iter().skip(blk_offset).for_each(|dir| {
                         if dir.valid() {
                             vec.push(dir.clone())
                         }
                     })
-                });
+                }
                 // println!("dir_entry: {:?}",vec);
-                Ok(vec.iter().map(|dir| {
-                    let inode = self.inode(dir.inode as usize);
+                vec.iter().map(|dir| {
+                    let inode = self.inode(dir.inode as usize)?;
                     let id = offset_id;
                     offset_id += 1;
-                    DirEntryDetail {
+                    Ok(DirEntryDetail {
                         name: String::from(dir.name),
                         inode_id: dir.inode as usize,
                         offset: id,
                         inode,
-                    }
-                }).collect())
+                    })
+                }).collect()
             }
         }
     }
@@ -352,7 +358,7 @@ impl BlockCacheDevice {
 
     pub fn access_guard(&mut self, req: &Req, _ino: usize, _mask: i32) -> Result<(), ErrorCode> {
         // debug!("Access: {}", _ino);
-        let inode = self.inode(_ino);
+        let inode = self.inode(_ino)?;
         inode.access_guard(req, Mask::from_mask(_mask), ())
     }
 
@@ -365,16 +371,18 @@ impl BlockCacheDevice {
         _umask: u32,
         flags: i32,
     ) -> Result<u32, ErrorCode> {
-        println!("Create guard: parent:{} mode:{} umask:{} flags:{}", _parent, mode, _umask, flags);
-        let parent = self.inode(_parent);
-        self.make_node_internal(
-            String::from(name).as_str(),
-            &parent.with_id(_parent),
-            FileType::File << 12 | mode as u16,
-            req.uid,
-            req.gid,
-        ).and_then(|v| {
-            self.open_guard(req, v, flags)
+        self.as_user(req.uid, |fs| {
+            println!("Create guard: parent:{} mode:{} umask:{} flags:{}", _parent, mode, _umask, flags);
+            let parent = fs.inode(_parent)?;
+            fs.make_node_internal(
+                String::from(name).as_str(),
+                &parent.with_id(_parent),
+                FileType::File << 12 | mode as u16,
+                req.uid,
+                req.gid,
+            ).and_then(|v| {
+                fs.open_guard(req, v, flags)
+            })
         })
     }
 }
@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use crate::layout::data_block::{dir_block_ok, DirBlock, DirEntry};
+use crate::layout::index_node::{index_block_ok, IndexBlock, IndexNode, MAX_INDEX_LEVEL};
+use crate::layout::inode::Inode;
+use crate::layout::journal::JournalMode;
+use crate::layout::super_block::FsState;
+use crate::manager::block_cache_manager::BlockCacheDevice;
+use crate::manager::error_code::{ENOENT, EROFS, ErrorCode};
+use crate::typ::file_name::FileName;
+use crate::typ::file_type::FileType;
+
+pub const ROOT_INODE: usize = 1;
+pub const LOST_FOUND: &str = "lost+found";
+// Walker.owner 中表示超级块备份占用的数据块
+const RESERVED: usize = usize::MAX;
+
+/// fsck 发现的不一致
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// 根目录不存在或不是目录,无法继续检查
+    BadRoot,
+    /// index_level 超出上限或与 index_node 不匹配
+    InvalidIndexLevel { inode: usize, level: u8 },
+    /// 索引指向数据区以外的块
+    BlockOutOfRange { inode: usize, block: usize },
+    /// 数据块被多次引用
+    DuplicateBlock { block: usize, inode: usize, other: usize },
+    /// 目录项指向不存在的 inode
+    DanglingEntry { dir: usize, name: String, inode: usize },
+    /// 在用 inode 未在位图中标记
+    InodeNotMarked(usize),
+    /// 位图中标记但未被使用的 inode
+    InodeLeaked(usize),
+    /// 存在但无法从根目录到达的 inode
+    Orphan(usize),
+    /// 在用数据块未在位图中标记
+    BlockNotMarked(usize),
+    /// 位图中标记但未被引用的数据块
+    BlockLeaked(usize),
+    /// link_count 与目录项引用数不符
+    LinkCount { inode: usize, stored: u32, actual: u32 },
+    /// 根目录下缺少 lost+found
+    NoLostFound,
+    /// 上次挂载后没有正常卸载
+    NotClean,
+    /// 超级块备份与超级块不一致
+    StaleBackup(usize),
+    /// 索引指向超级块备份等保留块
+    ReservedBlock { inode: usize, block: usize },
+    /// inode 校验和不符
+    InodeChecksum(usize),
+    /// 索引块或目录块校验和不符
+    BlockChecksum { inode: usize, block: usize },
+    /// 组描述符中的位置或空闲计数与超级块、位图不符
+    BadGroup(usize),
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::BadRoot => write!(f, "root inode {} is not a directory", ROOT_INODE),
+            Problem::InvalidIndexLevel { inode, level } =>
+                write!(f, "inode {} has invalid index level {}", inode, level),
+            Problem::BlockOutOfRange { inode, block } =>
+                write!(f, "inode {} references block {} outside the data area", inode, block),
+            Problem::DuplicateBlock { block, inode, other } =>
+                write!(f, "block {} is claimed by inode {} and inode {}", block, other, inode),
+            Problem::DanglingEntry { dir, name, inode } =>
+                write!(f, "entry '{}' in directory {} points to free inode {}", name, dir, inode),
+            Problem::InodeNotMarked(inode) => write!(f, "inode {} is in use but free in bitmap", inode),
+            Problem::InodeLeaked(inode) => write!(f, "inode {} is marked in bitmap but unused", inode),
+            Problem::Orphan(inode) => write!(f, "inode {} is not reachable from root", inode),
+            Problem::BlockNotMarked(block) => write!(f, "block {} is in use but free in bitmap", block),
+            Problem::BlockLeaked(block) => write!(f, "block {} is marked in bitmap but unused", block),
+            Problem::LinkCount { inode, stored, actual } =>
+                write!(f, "inode {} has link count {}, should be {}", inode, stored, actual),
+            Problem::NoLostFound => write!(f, "/{} is missing", LOST_FOUND),
+            Problem::NotClean => write!(f, "file system was not cleanly unmounted"),
+            Problem::StaleBackup(block) => write!(f, "backup super block at block {} is out of date", block),
+            Problem::ReservedBlock { inode, block } =>
+                write!(f, "inode {} references reserved block {}", inode, block),
+            Problem::InodeChecksum(inode) => write!(f, "inode {} has bad checksum", inode),
+            Problem::BlockChecksum { inode, block } =>
+                write!(f, "block {} of inode {} has bad checksum", block, inode),
+            Problem::BadGroup(group) => write!(f, "descriptor of group {} does not match its bitmaps", group),
+        }
+    }
+}
+
+/// 检查结果
+#[derive(Clone, Debug, Default)]
+pub struct FsckReport {
+    /// 检查时发现的问题
+    pub problems: Vec<Problem>,
+    /// 修复后仍存在的问题,未修复时与 problems 相同
+    pub remaining: Vec<Problem>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// 从根目录遍历得到的使用情况
+struct Walker {
+    data_blocks: usize,
+    /// 是否检查校验和
+    csum: bool,
+    /// 数据块 -> 引用它的 inode, 0 表示未被引用
+    owner: Vec<usize>,
+    /// inode -> 目录项引用次数
+    refs: Vec<u32>,
+    reached: Vec<bool>,
+    /// 指向无效 inode 的目录项: 目录 -> 文件名
+    dangling: BTreeMap<usize, Vec<FileName>>,
+    problems: Vec<Problem>,
+}
+
+impl Walker {
+    fn new(fs: &BlockCacheDevice) -> Self {
+        let super_block = fs.super_block();
+        let inodes = super_block.inode_size();
+        let mut owner = vec![0; super_block.data_blocks];
+        for blk in super_block.backups() {
+            owner[super_block.data_id(blk).unwrap()] = RESERVED;
+        }
+        Self {
+            data_blocks: super_block.data_blocks,
+            csum: super_block.metadata_csum(),
+            owner,
+            refs: vec![0; inodes + 1],
+            reached: vec![false; inodes + 1],
+            dangling: BTreeMap::new(),
+            problems: Vec::new(),
+        }
+    }
+
+    fn valid_inode(&self, fs: &mut BlockCacheDevice, id: usize) -> Result<bool, ErrorCode> {
+        Ok(id != 0 && id < self.reached.len() && fs.inode_unchecked(id)?.exist())
+    }
+
+    /// 广度优先遍历 start 及其下属的全部文件
+    /// 校验和不符的 inode 与块只报告,仍按原内容遍历,避免修复位图时释放其中的块
+    fn walk(&mut self, fs: &mut BlockCacheDevice, start: usize) -> Result<(), ErrorCode> {
+        let mut queue = vec![start];
+        self.reached[start] = true;
+        while let Some(id) = queue.pop() {
+            let inode = fs.inode_unchecked(id)?;
+            let blocks = self.claim_inode(fs, id, &inode)?;
+            if !inode.is_dir() {
+                continue;
+            }
+            for blk in blocks {
+                let entries = fs.block_cache(fs.data_block(blk))?.lock().unwrap().read(0, |dirs: &DirBlock| *dirs);
+                if self.csum && !dir_block_ok(&entries) {
+                    self.problems.push(Problem::BlockChecksum { inode: id, block: blk });
+                }
+                for entry in entries.iter().filter(|v| v.valid()) {
+                    let target = entry.inode as usize;
+                    if !self.valid_inode(fs, target)? {
+                        self.problems.push(Problem::DanglingEntry {
+                            dir: id,
+                            name: String::from(entry.name),
+                            inode: target,
+                        });
+                        self.dangling.entry(id).or_default().push(entry.name);
+                        continue;
+                    }
+                    self.refs[target] += 1;
+                    if !self.reached[target] {
+                        self.reached[target] = true;
+                        queue.push(target);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 标记 inode 占用的索引块与数据块,返回数据块列表
+    fn claim_inode(&mut self, fs: &mut BlockCacheDevice, id: usize, inode: &Inode) -> Result<Vec<usize>, ErrorCode> {
+        let level = inode.index_level;
+        if level > MAX_INDEX_LEVEL || (level == 0) == inode.index_node.is_valid() {
+            if level != 0 || inode.index_node.is_valid() {
+                self.problems.push(Problem::InvalidIndexLevel { inode: id, level });
+            }
+            return Ok(Vec::new());
+        }
+        let mut blocks = Vec::new();
+        self.claim(fs, id, &inode.index_node, level, &mut blocks)?;
+        Ok(blocks)
+    }
+
+    fn claim(
+        &mut self,
+        fs: &mut BlockCacheDevice,
+        id: usize,
+        node: &IndexNode,
+        level: u8,
+        blocks: &mut Vec<usize>,
+    ) -> Result<(), ErrorCode> {
+        let end = node.start_blk.checked_add(node.len);
+        if end.is_none_or(|end| end > self.data_blocks) {
+            // 整段丢弃,避免读取损坏的索引
+            self.problems.push(Problem::BlockOutOfRange { inode: id, block: node.start_blk });
+            return Ok(());
+        }
+        for blk in node.start_blk..node.start_blk + node.len {
+            if self.owner[blk] == RESERVED {
+                self.problems.push(Problem::ReservedBlock { inode: id, block: blk });
+                continue;
+            }
+            if self.owner[blk] != 0 {
+                self.problems.push(Problem::DuplicateBlock { block: blk, inode: id, other: self.owner[blk] });
+                continue;
+            }
+            self.owner[blk] = id;
+            if level > 1 {
+                let nodes = fs.index_block_cache(blk)?.lock().unwrap().read(0, |data: &IndexBlock| *data);
+                if self.csum && !index_block_ok(&nodes) {
+                    self.problems.push(Problem::BlockChecksum { inode: id, block: blk });
+                }
+                for v in nodes.iter().filter(|v| v.is_valid()) {
+                    self.claim(fs, id, v, level - 1, blocks)?;
+                }
+            } else {
+                blocks.push(blk);
+            }
+        }
+        Ok(())
+    }
+
+    /// 对比位图与 link_count,返回孤儿 inode
+    fn compare(&mut self, fs: &mut BlockCacheDevice) -> Result<Vec<usize>, ErrorCode> {
+        let mut orphans = Vec::new();
+        for id in 1..self.reached.len() {
+            let inode = fs.inode_unchecked(id)?;
+            if self.csum && !inode.checksum_ok(id) {
+                self.problems.push(Problem::InodeChecksum(id));
+            }
+            let marked = fs.used(id - 1, true)?;
+            if self.reached[id] {
+                if !marked {
+                    self.problems.push(Problem::InodeNotMarked(id));
+                }
+                let actual = if id == ROOT_INODE { 1 } else { self.refs[id] };
+                if inode.link_count != actual {
+                    self.problems.push(Problem::LinkCount { inode: id, stored: inode.link_count, actual });
+                }
+            } else if inode.exist() {
+                self.problems.push(Problem::Orphan(id));
+                orphans.push(id);
+            } else if marked {
+                self.problems.push(Problem::InodeLeaked(id));
+            }
+        }
+        for blk in 0..self.data_blocks {
+            match (self.owner[blk] != 0, fs.used(blk, false)?) {
+                (true, false) => self.problems.push(Problem::BlockNotMarked(blk)),
+                (false, true) => self.problems.push(Problem::BlockLeaked(blk)),
+                _ => {}
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// 按遍历结果重写两张位图
+    fn fix_bitmaps(&self, fs: &mut BlockCacheDevice) -> Result<(), ErrorCode> {
+        for id in 1..self.reached.len() {
+            if fs.used(id - 1, true)? != self.reached[id] {
+                fs.set(id - 1, true, self.reached[id])?;
+            }
+        }
+        for blk in 0..self.data_blocks {
+            let used = self.owner[blk] != 0;
+            if fs.used(blk, false)? != used {
+                fs.set(blk, false, used)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 一致性检查与修复
+impl BlockCacheDevice {
+    /// 检查文件系统,repair 为 true 时修复位图、link_count、孤儿、无效目录项与组描述符
+    /// 重复引用、越界块、损坏的索引与校验和错误只报告不修复
+    /// repair 时重写超级块备份并记录检查时间,没有遗留问题时将状态置为 clean
+    pub fn fsck(&mut self, repair: bool) -> Result<FsckReport, ErrorCode> {
+        if repair && self.super_block().unknown_ro_compat() != 0 {
+            return Err(EROFS);
+        }
+        let (walker, orphans) = self.fsck_pass()?;
+        let mut problems = walker.problems.clone();
+        if self.super_block().state() == FsState::Dirty {
+            problems.push(Problem::NotClean);
+        }
+        problems.extend(self.stale_backups()?.into_iter().map(Problem::StaleBackup));
+        if !repair || problems.is_empty() {
+            if repair {
+                self.checked(true)?;
+            }
+            return Ok(FsckReport { remaining: problems.clone(), problems });
+        }
+        // 修复涉及的块可能超过日志容量,与 e2fsck 一样直接写回,中途中断时重新运行 fsck 即可
+        let mode = self.journal_mode();
+        self.set_journal_mode(JournalMode::None)?;
+        let report = self.repair(walker, orphans, problems);
+        self.set_journal_mode(mode)?;
+        report
+    }
+
+    fn repair(&mut self, mut walker: Walker, orphans: Vec<usize>, problems: Vec<Problem>) -> Result<FsckReport, ErrorCode> {
+        // 先把孤儿树也标记为在用,使修复位图后分配 lost+found 时不会覆盖它们
+        let roots = self.orphan_roots(&orphans)?;
+        for id in roots.iter() {
+            if !walker.reached[*id] {
+                walker.walk(self, *id)?;
+            }
+        }
+        for (dir, names) in walker.dangling.iter() {
+            let dir = self.inode(*dir)?.with_id(*dir);
+            let mut entries = self.ls_internal(dir.inode())?;
+            entries.retain(|v| !names.contains(&v.name));
+            self.write_system(0, &dir, &DirEntry::pack(&entries), true)?;
+        }
+        walker.fix_bitmaps(self)?;
+        // 修复位图不经过 free_block,被释放的 inode 号可能仍留在缓存中
+        self.inodes.clear();
+        self.dentries.clear();
+        if !roots.is_empty() || problems.contains(&Problem::NoLostFound) {
+            let lost_found = self.lost_found()?;
+            let inode = self.inode(lost_found)?;
+            let mut entries = self.ls_internal(&inode)?;
+            for id in roots {
+                entries.push(DirEntry { name: format!("#{}", id).as_str().into(), inode: id as u64 });
+            }
+            let dir = self.inode(lost_found)?.with_id(lost_found);
+            self.write_system(0, &dir, &DirEntry::pack(&entries), true)?;
+        }
+        // 重新遍历,修正 link_count 与位图
+        let (walker, _) = self.fsck_pass()?;
+        for problem in walker.problems.iter() {
+            if let Problem::LinkCount { inode, actual, .. } = problem {
+                self.modify_inode(*inode, |ino| ino.link_count = *actual)?;
+            }
+        }
+        walker.fix_bitmaps(self)?;
+        self.fix_groups()?;
+        self.sync()?;
+        let (walker, _) = self.fsck_pass()?;
+        self.write_backups()?;
+        self.checked(walker.problems.is_empty())?;
+        Ok(FsckReport { problems, remaining: walker.problems })
+    }
+
+    fn fsck_pass(&mut self) -> Result<(Walker, Vec<usize>), ErrorCode> {
+        let mut walker = Walker::new(self);
+        let root = self.inode_unchecked(ROOT_INODE)?;
+        if !root.is_dir() {
+            walker.problems.push(Problem::BadRoot);
+            return Ok((walker, Vec::new()));
+        }
+        walker.walk(self, ROOT_INODE)?;
+        let orphans = walker.compare(self)?;
+        walker.problems.extend(self.bad_groups()?.into_iter().map(Problem::BadGroup));
+        if let Err(ENOENT) = self.lookup_internal(&root.with_id(ROOT_INODE), LOST_FOUND.into()) {
+            walker.problems.push(Problem::NoLostFound);
+        }
+        Ok((walker, orphans))
+    }
+
+    /// 不被其他孤儿目录引用的孤儿,只需将它们挂到 lost+found 下
+    fn orphan_roots(&mut self, orphans: &[usize]) -> Result<Vec<usize>, ErrorCode> {
+        let mut referenced = Vec::new();
+        for id in orphans {
+            let inode = self.inode(*id)?;
+            if inode.is_dir() && inode.index_level <= MAX_INDEX_LEVEL {
+                if let Ok(entries) = self.ls_internal(&inode) {
+                    referenced.extend(entries.iter().map(|v| v.inode as usize).filter(|v| v != id));
+                }
+            }
+        }
+        let mut roots: Vec<usize> = orphans.iter().filter(|v| !referenced.contains(v)).cloned().collect();
+        if roots.is_empty() && !orphans.is_empty() {
+            // 孤儿目录之间成环
+            roots.push(orphans[0]);
+        }
+        Ok(roots)
+    }
+
+    /// 返回 lost+found 的 inode 号,不存在时创建
+    pub(crate) fn lost_found(&mut self) -> Result<usize, ErrorCode> {
+        let root = self.inode(ROOT_INODE)?;
+        match self.lookup_internal(&root.with_id(ROOT_INODE), LOST_FOUND.into()) {
+            Ok(v) => Ok(v.inode),
+            Err(ENOENT) => self.make_node_internal(
+                LOST_FOUND,
+                &root.with_id(ROOT_INODE),
+                FileType::Dir << 12 | 0o700,
+                0,
+                0,
+            ),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[test]
+fn repair_orphans_and_bitmaps() {
+    use crate::config::BLOCK_SIZE;
+    use crate::layout::data_block::DIR_ENTRIES_PER_BLOCK;
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (_, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let root = fs.inode(ROOT_INODE).unwrap().with_id(ROOT_INODE);
+    let dir = fs.make_node_internal("dir", &root, FileType::Dir << 12 | 0o755, 0, 0).unwrap();
+    let file = fs.create_file("file", &[1u8; BLOCK_SIZE]).unwrap();
+    // 在 dir 中插入其他文件,使目录的数据块不连续,索引升为两级
+    for i in 0..DIR_ENTRIES_PER_BLOCK + 1 {
+        let parent = fs.inode(dir).unwrap().with_id(dir);
+        fs.make_node_internal(&format!("f{}", i), &parent, FileType::File << 12 | 0o644, 0, 0).unwrap();
+        if i == 0 {
+            fs.create_file("other", &[2u8; 10]).unwrap();
+        }
+    }
+    let inode = fs.inode(dir).unwrap();
+    assert_eq!(inode.index_level, 2);
+    assert_eq!(fs.ls_internal(&inode).unwrap().len(), DIR_ENTRIES_PER_BLOCK + 1);
+    let report = fs.fsck(false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    // 从根目录摘除 dir,并弄乱 link_count 与位图
+    let root = fs.inode(ROOT_INODE).unwrap();
+    let entries: Vec<DirEntry> = fs.ls_internal(&root).unwrap().into_iter()
+        .filter(|v| String::from(v.name) != "dir")
+        .collect();
+    fs.write_system(0, &root.with_id(ROOT_INODE), &DirEntry::pack(&entries), true).unwrap();
+    fs.modify_inode(file, |ino| ino.link_count = 3).unwrap();
+    fs.set(fs.super_block().data_blocks - 1, false, true).unwrap();
+
+    let report = fs.fsck(true).unwrap();
+    assert!(report.problems.contains(&Problem::Orphan(dir)));
+    assert!(report.problems.contains(&Problem::LinkCount { inode: file, stored: 3, actual: 1 }));
+    assert!(report.problems.contains(&Problem::BlockLeaked(fs.super_block().data_blocks - 1)));
+    assert!(report.remaining.is_empty(), "{:?}", report.remaining);
+    let lost_found = fs.lost_found().unwrap();
+    let lost_found = fs.inode(lost_found).unwrap().with_id(lost_found);
+    let entry = fs.lookup_internal(&lost_found, format!("#{}", dir).as_str().into()).unwrap();
+    assert_eq!(entry.inode, dir);
+    assert!(fs.fsck(false).unwrap().is_clean());
+}
@@ -1,27 +1,38 @@
 use log::debug;
 
-use crate::config::BLOCK_SIZE;
-use crate::layout::data_block::{DIR_ENTRY_SIZE, DirEntry};
+use crate::layout::data_block::DirEntry;
 use crate::layout::inode::{Inode, InodeWithId};
 use crate::manager::block_cache_manager::BlockCacheDevice;
-use crate::manager::DirEntryDetail;
+use crate::manager::{DirEntryDetail, StatFs};
 use crate::manager::error_code::*;
 use crate::typ::file_name::FileName;
-use crate::utils::slice::{align, vec2slice};
+use crate::typ::file_type::FileType;
 
 /// 功能接口
 /// 无权限管理
 impl BlockCacheDevice {
+    /// 先查目录项缓存,未命中时读取整个目录并缓存其中所有目录项,不存在的文件名也会缓存
     pub fn lookup_internal(
         &mut self,
-        parent: &Inode,
+        parent: &InodeWithId,
         name: FileName,
     ) -> Result<InodeWithId, ErrorCode> {
-        self.ls_internal(&parent)
-            .and_then(|v| match v.iter().find(|entry| name == entry.name) {
-                None => Err(ENOENT),
-                Some(e) => Ok(self.inode(e.inode as usize).with_id(e.inode as usize)),
-            })
+        let found = match self.dentries.get(parent.inode, &name) {
+            Some(found) => found,
+            None => {
+                let entries = self.ls_internal(parent.inode())?;
+                for entry in entries.iter() {
+                    self.dentries.insert(parent.inode, entry.name, Some(entry.inode as usize));
+                }
+                let found = entries.iter().find(|entry| name == entry.name).map(|e| e.inode as usize);
+                if found.is_none() {
+                    self.dentries.insert(parent.inode, name, None);
+                }
+                found
+            }
+        };
+        let id = found.ok_or(ENOENT)?;
+        Ok(self.inode(id)?.with_id(id))
     }
     pub fn make_node_internal(
         &mut self,
@@ -36,24 +47,25 @@ impl BlockCacheDevice {
                     if dirs.iter().any(|v| v.name.as_slice() == name) {
                         return Err(EEXIST);
                     }
-                    let inode_opt = self.alloc_block(true);
-                    return match inode_opt {
-                        None => Err(ENOSPC),
-                        Some(inode_id) => {
-                            self.print();
-                            self.modify_inode(inode_id, |inode| *inode = Inode::new(mode, uid, gid));
-                            dirs.push(DirEntry {
-                                name: name.into(),
-                                inode: inode_id as u64,
-                            });
-                            let buf: Vec<u8> = vec2slice(dirs);
-                            if let Err(e) = self.write_system(0, parent, &buf, true) {
-                                debug!("mk_file:339 error: {}", e);
-                                return Err(e);
-                            }
-                            Ok(inode_id)
-                        }
-                    };
+                    // 新目录分散到各块组,其他文件的 inode 与数据放在父目录附近
+                    let is_dir = mode >> 12 == FileType::Dir as u16;
+                    let hint = if is_dir { self.dir_inode_hint()? } else { None };
+                    let inode_id = self.alloc_inode(hint.or(Some(parent.inode)))?;
+                    self.modify_inode(inode_id, |inode| *inode = Inode::new(mode, uid, gid))?;
+                    if let Some(&last) = self.inode_data_blk_list(parent.inode())?.last().filter(|_| !is_dir) {
+                        self.allocator.set_goal(inode_id, last + 1);
+                    }
+                    dirs.push(DirEntry {
+                        name: name.into(),
+                        inode: inode_id as u64,
+                    });
+                    let buf = DirEntry::pack(&dirs);
+                    if let Err(e) = self.write_system(0, parent, &buf, true) {
+                        debug!("mk_file:339 error: {}", e);
+                        return Err(e);
+                    }
+                    self.dentries.insert(parent.inode, name.into(), Some(inode_id));
+                    Ok(inode_id)
                 })
             } else {
                 Err(ENOTDIR)
@@ -67,22 +79,13 @@ impl BlockCacheDevice {
             if inode.is_dir() {
                 let mut entries = Vec::new();
                 // debug!("dir list: {:?},{}", inode.index_node, inode.index_level);
-                inode
-                    .index_node
-                    .list(self, inode.index_level)
-                    .iter()
-                    .for_each(|v| {
-                        // debug!("data blocks: {}", v);
-                        self.block_cache(self.data_block(*v)).lock().unwrap().read(
-                            0,
-                            |dirs: &[DirEntry; BLOCK_SIZE / DIR_ENTRY_SIZE]| {
-                                // debug!("dir entries: {:?}", dirs);
-                                dirs.iter()
-                                    .filter(|v| !v.name.is_empty() && v.inode != 0)
-                                    .for_each(|dir| entries.push(*dir))
-                            },
-                        );
-                    });
+                for v in inode.index_node.list(self, inode.index_level)? {
+                    // debug!("data blocks: {}", v);
+                    self.dir_block(v)?
+                        .iter()
+                        .filter(|v| v.valid())
+                        .for_each(|dir| entries.push(*dir));
+                }
                 Ok(entries)
             } else {
                 Err(ENOTDIR)
@@ -98,7 +101,7 @@ impl BlockCacheDevice {
             Ok(entries) => {
                 for entry in entries {
                     let ino = entry.inode as usize;
-                    let inode = self.inode(ino);
+                    let inode = self.inode(ino)?;
                     if inode.is_dir() {
                         if let Err(e) = self.remove_dir_internal(&inode.with_id(ino)) {
                             return Err(e);
@@ -127,14 +130,14 @@ impl BlockCacheDevice {
                     let inode = self.modify_inode(ino_id, |inode| {
                         inode.link_count -= 1;
                         inode.clone()
-                    });
+                    })?;
                     if inode.link_count == 0 {
-                        inode.index_node.delete(self, inode.index_level, true);
-                        self.free_block(ino_id, true, true);
+                        inode.index_node.delete(self, inode.index_level, true)?;
+                        self.free_block(ino_id, true, true)?;
                     }
-                    let mut buf = vec2slice(v);
-                    align(&mut buf, BLOCK_SIZE);
-                    return self.write_system(0, parent, &buf, true).map(|_| ());
+                    self.write_system(0, parent, &DirEntry::pack(&v), true)?;
+                    self.dentries.insert(parent.inode, name, None);
+                    return Ok(());
                 }
             }
             Err(ENOENT)
@@ -150,7 +153,7 @@ impl BlockCacheDevice {
         if parent.inode == new_parent.inode && name == new_name {
             return Ok(());
         }
-        self.lookup_internal(parent.inode(), name)
+        self.lookup_internal(parent, name)
             .and_then(|entry| match self.unlink_internal(new_parent, new_name) {
                 Ok(_) | Err(ENOENT) => {
                     self.ls_internal(new_parent.inode()).and_then(|mut new_dirs| {
@@ -160,19 +163,29 @@ impl BlockCacheDevice {
                         });
                         self.modify_inode(entry.inode, |ino| {
                             ino.link_count += 1
-                        });
-                        let mut buf = vec2slice(new_dirs);
-                        align(&mut buf, BLOCK_SIZE);
-                        self.write_system(0, new_parent, &buf, true)
+                        })?;
+                        self.write_system(0, new_parent, &DirEntry::pack(&new_dirs), true)
                             .and_then(|_| self.unlink_internal(parent, name))
+                            .map(|_| self.dentries.insert(new_parent.inode, new_name, Some(entry.inode)))
                     })
                 }
                 Err(e) => Err(e),
             })
     }
+    pub fn statfs_internal(&mut self) -> Result<StatFs, ErrorCode> {
+        let super_block = self.super_block();
+        let free_blocks = super_block.data_blocks - self.used_count(false)?;
+        Ok(StatFs {
+            blocks: super_block.data_blocks,
+            free_blocks,
+            available_blocks: free_blocks.saturating_sub(super_block.reserved_blocks),
+            inodes: super_block.inode_size(),
+            free_inodes: super_block.inode_size() - self.used_count(true)?,
+        })
+    }
     pub fn ls(&mut self, path: &str) -> Result<Vec<DirEntryDetail>, ErrorCode> {
         let path_split = path.split("/").filter(|p| !p.is_empty());
-        let mut parent_inode = self.inode(0);
+        let mut parent_inode = self.inode(0)?;
         for p in path_split {
             let dirs_result = self
                 .ls_internal(&parent_inode);
@@ -181,7 +194,7 @@ impl BlockCacheDevice {
                     match dirs.iter().find(|v| String::from(v.name) == p.to_string())
                     {
                         None => return Err(ENOENT),
-                        Some(v) => parent_inode = self.inode(v.inode as usize),
+                        Some(v) => parent_inode = self.inode(v.inode as usize)?,
                     }
                 }
                 Err(e) => {
@@ -189,14 +202,14 @@ impl BlockCacheDevice {
                 }
             }
         }
-        self.ls_internal(&parent_inode).map(|vec| {
+        self.ls_internal(&parent_inode).and_then(|vec| {
             vec.iter()
-                .map(|v| DirEntryDetail {
+                .map(|v| Ok(DirEntryDetail {
                     name: String::from(v.name),
                     inode_id: v.inode as usize,
                     offset: 0,
-                    inode: self.inode(v.inode as usize),
-                })
+                    inode: self.inode(v.inode as usize)?,
+                }))
                 .collect()
         })
     }
@@ -0,0 +1,384 @@
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+use crate::cache::block_cache::CacheBlock;
+use crate::config::BLOCK_SIZE;
+use crate::layout::journal::*;
+use crate::layout::super_block::SuperBlock;
+use crate::manager::block_cache_manager::BlockCacheDevice;
+use crate::manager::error_code::{EINVAL, EIO, ENOSPC, ErrorCode, io_error};
+use crate::typ::request::Req;
+use crate::utils::slice::{from_slice, slice};
+
+/// 挂载期间的日志状态
+#[derive(Copy, Clone, Debug)]
+pub struct Journal {
+    /// 日志区域起始物理块
+    pub start: usize,
+    pub blocks: usize,
+    pub mode: JournalMode,
+    /// 下一个事务的序号
+    pub sequence: u64,
+}
+
+/// 元数据日志
+/// 两次提交之间修改的块组成一个事务,提交前脏块不会写回原位置
+impl BlockCacheDevice {
+    pub fn journal_mode(&self) -> JournalMode {
+        self.journal.map_or(JournalMode::None, |j| j.mode)
+    }
+
+    /// 修改本次挂载的日志模式,不写入超级块
+    /// 镜像没有日志区域时只能使用 JournalMode::None
+    pub fn set_journal_mode(&mut self, mode: JournalMode) -> Result<(), ErrorCode> {
+        self.sync()?;
+        match self.journal.as_mut() {
+            Some(journal) => journal.mode = mode,
+            None if mode == JournalMode::None => {}
+            None => return Err(EINVAL),
+        }
+        self.write_back.set_journaling(mode != JournalMode::None);
+        Ok(())
+    }
+
+    /// 执行一次文件系统操作并提交其修改
+    /// 之前未提交的修改先单独提交,事务中只有本次操作
+    pub fn transaction<V>(&mut self, f: impl FnOnce(&mut Self) -> Result<V, ErrorCode>) -> Result<V, ErrorCode> {
+        if self.journal_mode() != JournalMode::None {
+            self.journal_commit()?;
+        }
+        let result = f(self);
+        if self.journal_mode() != JournalMode::None {
+            self.journal_commit()?;
+        }
+        result
+    }
+
+    /// 写入文件,data 模式下按日志容量拆分为多次写入,每次写入作为一个完整的事务提交
+    /// 每个事务最多写入容量一半的数据块,其余留给 inode、位图与索引块
+    /// 写入成功时返回 data 的长度,后面的事务失败时返回已写入的长度
+    pub fn write_transactions(&mut self, req: &Req, fh: u32, offset: u64, data: &[u8]) -> Result<usize, ErrorCode> {
+        let batch = match self.journal {
+            Some(journal) if journal.mode == JournalMode::Data => (journal_capacity(journal.blocks) / 2).max(1) * BLOCK_SIZE,
+            _ => data.len(),
+        };
+        let mut written = 0;
+        loop {
+            let chunk = &data[written..data.len().min(written + batch)];
+            match self.transaction(|fs| fs.write_guard(req, fh, SeekFrom::Start(offset + written as u64), chunk)) {
+                Ok(_) => {
+                    written += chunk.len();
+                    if written == data.len() {
+                        return Ok(written);
+                    }
+                }
+                Err(e) if written == 0 => return Err(e),
+                Err(_) => return Ok(written),
+            }
+        }
+    }
+
+    /// 普通文件的数据块,metadata 模式下不写入日志
+    pub(crate) fn mark_file_data(&mut self, id: usize) -> Result<(), ErrorCode> {
+        let blk_id = self.data_block(id);
+        self.block_cache(blk_id)?.lock().unwrap().set_file_data();
+        Ok(())
+    }
+
+    /// mkfs 时初始化日志区域,序号从当前时间开始以免与旧日志混淆
+    pub(crate) fn init_journal(&mut self, super_block: &SuperBlock) -> Result<(), ErrorCode> {
+        self.journal = None;
+        if super_block.journal_blocks == 0 {
+            return Ok(());
+        }
+        let sequence = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |v| v.as_secs());
+        let journal = Journal {
+            start: super_block.journal().start,
+            blocks: super_block.journal_blocks,
+            mode: super_block.journal_mode.into(),
+            sequence,
+        };
+        self.device.write(journal.start + 1, &[0u8; BLOCK_SIZE]).map_err(io_error)?;
+        self.journal = Some(journal);
+        self.write_back.set_journaling(journal.mode != JournalMode::None);
+        self.write_journal_header()
+    }
+
+    /// 挂载时读取日志头并重放已提交但未写回的事务
+    pub(crate) fn load_journal(&mut self) -> Result<(), ErrorCode> {
+        let super_block = self.super_block();
+        if super_block.journal_blocks == 0 {
+            self.journal = None;
+            return Ok(());
+        }
+        let start = super_block.journal().start;
+        let mut buf = [0u8; BLOCK_SIZE];
+        self.device.read(start, &mut buf).map_err(io_error)?;
+        let header: JournalBlock = from_slice(&buf);
+        if header.magic != JOURNAL_MAGIC || header.kind != JOURNAL_HEADER {
+            warn!("Journal header at block {} is corrupted", start);
+            return Err(EIO);
+        }
+        self.journal = Some(Journal {
+            start,
+            blocks: super_block.journal_blocks,
+            mode: super_block.journal_mode.into(),
+            sequence: header.sequence,
+        });
+        self.write_back.set_journaling(self.journal_mode() != JournalMode::None);
+        if self.replay()? {
+            // 超级块可能也在事务中
+            self.super_block = self.write_back.cache(self.device.clone(), 0).map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    /// 重放日志中序号与日志头一致且完整的事务,返回是否重放
+    /// 没有 Commit 或校验失败的事务直接丢弃
+    fn replay(&mut self) -> Result<bool, ErrorCode> {
+        let Some(mut journal) = self.journal else { return Ok(false) };
+        let end = journal.start + journal.blocks;
+        let mut pos = journal.start + 1;
+        let mut writes: Vec<(usize, Vec<u8>)> = Vec::new();
+        let mut checksum = JOURNAL_CHECKSUM_SEED;
+        let mut buf = [0u8; BLOCK_SIZE];
+        while pos < end {
+            self.device.read(pos, &mut buf).map_err(io_error)?;
+            let head: JournalBlock = from_slice(&buf);
+            if head.is(JOURNAL_DESCRIPTOR, journal.sequence) {
+                let descriptor: JournalDescriptor = from_slice(&buf);
+                let count = head.count as usize;
+                if count > JOURNAL_TAGS || pos + 1 + count > end {
+                    break;
+                }
+                for (i, tag) in descriptor.tags[..count].iter().enumerate() {
+                    let target = *tag as usize;
+                    if target >= journal.start {
+                        warn!("Journal transaction {} writes to block {}, discarded", journal.sequence, target);
+                        return Ok(false);
+                    }
+                    let mut data = vec![0u8; BLOCK_SIZE];
+                    self.device.read(pos + 1 + i, &mut data).map_err(io_error)?;
+                    checksum = journal_checksum(checksum, target, &data);
+                    writes.push((target, data));
+                }
+                pos += 1 + count;
+            } else if head.is(JOURNAL_COMMIT, journal.sequence) {
+                let commit: JournalCommit = from_slice(&buf);
+                if head.count as usize != writes.len() || commit.checksum != checksum {
+                    break;
+                }
+                for (target, data) in writes.iter() {
+                    self.device.write(*target, data).map_err(io_error)?;
+                }
+                self.device.sync().map_err(io_error)?;
+                journal.sequence += 1;
+                self.journal = Some(journal);
+                self.write_journal_header()?;
+                info!("Replayed journal transaction {} ({} blocks)", journal.sequence - 1, writes.len());
+                return Ok(true);
+            } else {
+                break;
+            }
+        }
+        if !writes.is_empty() {
+            info!("Discarded incomplete journal transaction {}", journal.sequence);
+        }
+        Ok(false)
+    }
+
+    /// 将所有脏块作为一个事务写入日志,再写回原位置
+    /// 事务不会拆分,超过日志容量时放弃所有未提交的修改并返回 ENOSPC,磁盘上不会留下只完成一半的操作
+    pub fn journal_commit(&mut self) -> Result<(), ErrorCode> {
+        let Some(journal) = self.journal else { return Ok(()) };
+        let mut dirty = self.dirty_blocks();
+        let capacity = journal_capacity(journal.blocks);
+        let logged = dirty
+            .iter()
+            .filter(|c| journal.mode == JournalMode::Data || !c.lock().unwrap().is_file_data())
+            .count();
+        if logged > capacity {
+            warn!("Transaction of {} blocks exceeds journal capacity {}, aborted", logged, capacity);
+            self.journal_abort()?;
+            return Err(ENOSPC);
+        }
+        if journal.mode == JournalMode::Metadata {
+            // ordered: 文件数据先于引用它的元数据落盘
+            let mut ordered = false;
+            for c in dirty.iter() {
+                let mut c = c.lock().unwrap();
+                if c.is_file_data() {
+                    c.sync().map_err(io_error)?;
+                    ordered = true;
+                }
+            }
+            if ordered {
+                self.device.sync().map_err(io_error)?;
+            }
+            dirty.retain(|c| c.lock().unwrap().is_dirty());
+        }
+        if !dirty.is_empty() {
+            self.write_transaction(&dirty)?;
+            // checkpoint
+            for c in dirty.iter() {
+                c.lock().unwrap().sync().map_err(io_error)?;
+            }
+            self.device.sync().map_err(io_error)?;
+            if let Some(journal) = self.journal.as_mut() {
+                journal.sequence += 1;
+            }
+            self.write_journal_header()?;
+        }
+        self.pinned.clear();
+        self.release_freed()
+    }
+
+    /// 放弃未提交的修改,脏块恢复为磁盘上的内容
+    /// 直接写入的新数据块恢复后不再被引用
+    fn journal_abort(&mut self) -> Result<(), ErrorCode> {
+        let mut buf = [0u8; BLOCK_SIZE];
+        for c in self.dirty_blocks() {
+            let mut c = c.lock().unwrap();
+            self.device.read(c.block(), &mut buf).map_err(io_error)?;
+            c.load(&buf);
+        }
+        self.pinned.clear();
+        self.inodes.clear();
+        self.dentries.clear();
+        self.allocator.reset();
+        Ok(())
+    }
+
+    fn dirty_blocks(&self) -> Vec<Arc<Mutex<CacheBlock>>> {
+        self.write_back.blocks()
+    }
+
+    /// 依次写入 Descriptor 与块内容,落盘后再写入 Commit
+    fn write_transaction(&mut self, blocks: &[Arc<Mutex<CacheBlock>>]) -> Result<(), ErrorCode> {
+        let journal = self.journal.ok_or(EINVAL)?;
+        let mut pos = journal.start + 1;
+        let mut checksum = JOURNAL_CHECKSUM_SEED;
+        for group in blocks.chunks(JOURNAL_TAGS) {
+            let mut descriptor = JournalDescriptor {
+                head: JournalBlock::new(JOURNAL_DESCRIPTOR, journal.sequence, group.len() as u64),
+                tags: [0u64; JOURNAL_TAGS],
+            };
+            for (i, c) in group.iter().enumerate() {
+                let c = c.lock().unwrap();
+                descriptor.tags[i] = c.block() as u64;
+                checksum = journal_checksum(checksum, c.block(), c.data());
+                self.device.write(pos + 1 + i, c.data()).map_err(io_error)?;
+            }
+            self.write_journal_block(pos, slice(&descriptor))?;
+            pos += 1 + group.len();
+        }
+        self.device.sync().map_err(io_error)?;
+        let commit = JournalCommit {
+            head: JournalBlock::new(JOURNAL_COMMIT, journal.sequence, blocks.len() as u64),
+            checksum,
+        };
+        self.write_journal_block(pos, slice(&commit))?;
+        self.device.sync().map_err(io_error)
+    }
+
+    fn write_journal_header(&mut self) -> Result<(), ErrorCode> {
+        let journal = self.journal.ok_or(EINVAL)?;
+        let header = JournalBlock::new(JOURNAL_HEADER, journal.sequence, 0);
+        self.write_journal_block(journal.start, slice(&header))?;
+        self.device.sync().map_err(io_error)
+    }
+
+    fn write_journal_block(&mut self, block: usize, data: &[u8]) -> Result<(), ErrorCode> {
+        let mut buf = [0u8; BLOCK_SIZE];
+        buf[..data.len()].copy_from_slice(data);
+        self.device.write(block, &buf).map_err(io_error)
+    }
+}
+
+#[test]
+fn replay_or_discard_after_crash() {
+    use crate::block_device::ram_device::RamDevice;
+    use crate::manager::error_code::ENOENT;
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new().journal(JournalMode::Data));
+    fs.create_file("file", &[]).unwrap();
+    // 事务已写入日志,但还没有写回原位置
+    let dirty = fs.dirty_blocks();
+    fs.write_transaction(&dirty).unwrap();
+    let crashed = ram.snapshot();
+    let commit_block = fs.journal.unwrap().start + 2 + dirty.len();
+
+    let lookup = |image: Vec<u8>| {
+        let mut fs = BlockCacheDevice::open(Arc::new(RamDevice::from_snapshot(image).unwrap())).unwrap();
+        let root = fs.inode(1).unwrap().with_id(1);
+        let found = fs.lookup_internal(&root, "file".into()).map(|_| ());
+        assert!(fs.fsck(false).unwrap().is_clean());
+        found
+    };
+    assert_eq!(lookup(crashed.clone()), Ok(()));
+    // 只读打开时在内存中重放,镜像保持不变
+    let image = Arc::new(RamDevice::from_snapshot(crashed.clone()).unwrap());
+    let mut fs = BlockCacheDevice::open_read_only(image.clone()).unwrap();
+    let root = fs.inode(1).unwrap().with_id(1);
+    assert!(fs.lookup_internal(&root, "file".into()).is_ok());
+    assert!(fs.fsck(false).unwrap().is_clean());
+    drop(fs);
+    assert!(image.snapshot() == crashed);
+    // Commit 没有落盘的事务被丢弃
+    let mut torn = crashed;
+    torn[commit_block * BLOCK_SIZE..(commit_block + 1) * BLOCK_SIZE].fill(0);
+    assert_eq!(lookup(torn), Err(ENOENT));
+}
+
+#[test]
+fn transaction_fits_journal() {
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new().journal(JournalMode::Data));
+    let capacity = journal_capacity(fs.journal.unwrap().blocks);
+    let ino = fs.transaction(|fs| fs.create_file("file", &[])).unwrap();
+    // 超过日志容量的操作被整体放弃
+    let data: Vec<u8> = (0..2 * capacity * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    let inode = fs.inode(ino).unwrap().with_id(ino);
+    assert_eq!(fs.transaction(|fs| fs.write_system(0, &inode, &data, false)), Err(ENOSPC));
+    assert_eq!(fs.inode(ino).unwrap().size, 0);
+    assert!(fs.fsck(false).unwrap().is_clean());
+    // 大块写入拆分为多个完整的事务
+    let sequence = fs.journal.unwrap().sequence;
+    let req = Req { uid: 0, gid: 0, pid: 1 };
+    let fh = fs.open_internal(ino, 0, libc::O_RDWR, req.pid).unwrap();
+    assert_eq!(fs.write_transactions(&req, fh, 0, &data), Ok(data.len()));
+    assert!(fs.journal.unwrap().sequence >= sequence + 4);
+    fs.close_internal(fh, req.pid, true).unwrap();
+    fs.sync().unwrap();
+    drop(fs);
+    let mut fs = BlockCacheDevice::open(ram).unwrap();
+    assert_eq!(fs.read_all(ino).unwrap(), data);
+    assert!(fs.fsck(false).unwrap().is_clean());
+}
+
+#[test]
+fn reuse_freed_after_commit() {
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new().journal(JournalMode::Data));
+    let a = fs.transaction(|fs| fs.create_file("a", &[1u8; 4 * BLOCK_SIZE])).unwrap();
+    let inode = fs.inode(a).unwrap();
+    let old = fs.inode_data_blk_list(&inode).unwrap();
+    let image = ram.snapshot();
+    fs.transaction(|fs| {
+        let inode = fs.inode(a)?.with_id(a);
+        fs.write_system(0, &inode, &[], true)?;
+        // 同一事务中释放的块不会分配给新数据,新数据在 data 模式下也经过日志
+        let (node, level) = fs.write_data(&[2u8; 4 * BLOCK_SIZE], 0)?;
+        assert!(node.list(fs, level)?.iter().all(|id| !old.contains(id)));
+        assert!(ram.snapshot() == image);
+        Ok(())
+    }).unwrap();
+    let free = fs.free_extents(false).unwrap();
+    assert!(old.iter().all(|id| free.iter().any(|range| range.contains(id))));
+}
@@ -0,0 +1,153 @@
+#[cfg(test)]
+use std::sync::Arc;
+
+#[cfg(test)]
+use crate::block_device::ram_device::RamDevice;
+use crate::config::BLOCK_SIZE;
+use crate::layout::journal::JournalMode;
+#[cfg(test)]
+use crate::manager::block_cache_manager::BlockCacheDevice;
+#[cfg(test)]
+use crate::manager::error_code::ErrorCode;
+#[cfg(test)]
+use crate::typ::file_type::FileType;
+
+/// 格式化参数
+/// 未设置的项使用默认值: 整个设备、每 4KB 一个 inode、无卷标、无保留块、完整清零、
+/// metadata 日志,日志大小为文件系统的 1/32 (16 ~ 8192 块),启用元数据校验和,不划分块组
+#[derive(Clone, Debug)]
+pub struct MkfsOptions {
+    pub(crate) blocks: Option<usize>,
+    pub(crate) bytes_per_inode: usize,
+    pub(crate) label: [u8; 16],
+    pub(crate) reserved_percent: u8,
+    pub(crate) fast: bool,
+    pub(crate) journal: JournalMode,
+    pub(crate) journal_blocks: Option<usize>,
+    pub(crate) checksums: bool,
+    pub(crate) blocks_per_group: Option<usize>,
+}
+
+impl Default for MkfsOptions {
+    fn default() -> Self {
+        Self {
+            blocks: None,
+            bytes_per_inode: BLOCK_SIZE,
+            label: [0u8; 16],
+            reserved_percent: 0,
+            fast: false,
+            journal: JournalMode::Metadata,
+            journal_blocks: None,
+            checksums: true,
+            blocks_per_group: None,
+        }
+    }
+}
+
+impl MkfsOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 文件系统块数,不设置时使用整个设备
+    pub fn blocks(mut self, blocks: usize) -> Self {
+        self.blocks = Some(blocks);
+        self
+    }
+
+    /// 文件系统字节数,向下取整到整块
+    pub fn size(self, bytes: u64) -> Self {
+        self.blocks(bytes as usize / BLOCK_SIZE)
+    }
+
+    /// 每多少字节的空间分配一个 inode
+    pub fn bytes_per_inode(mut self, bytes: usize) -> Self {
+        self.bytes_per_inode = bytes;
+        self
+    }
+
+    /// 卷标,超过 16 字节的部分会被截断
+    pub fn label(mut self, label: &str) -> Self {
+        let len = label.len().min(self.label.len());
+        self.label = [0u8; 16];
+        self.label[..len].copy_from_slice(&label.as_bytes()[..len]);
+        self
+    }
+
+    /// 为 root 保留的数据块百分比
+    pub fn reserved_percent(mut self, percent: u8) -> Self {
+        self.reserved_percent = percent;
+        self
+    }
+
+    /// 只初始化元数据区域,不清零数据块
+    pub fn fast(mut self, fast: bool) -> Self {
+        self.fast = fast;
+        self
+    }
+
+    /// 挂载时默认的日志模式,None 表示不创建日志区域
+    pub fn journal(mut self, mode: JournalMode) -> Self {
+        self.journal = mode;
+        self
+    }
+
+    /// 日志区域的块数,不少于 MKFS_MIN_JOURNAL_BLOCKS
+    pub fn journal_blocks(mut self, blocks: usize) -> Self {
+        self.journal_blocks = Some(blocks);
+        self
+    }
+
+    /// 为超级块、inode、索引块与目录块记录校验和
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// 按块组划分,每组 blocks_per_group 块,不超过 BLOCK_SIZE * 8
+    pub fn block_groups(mut self, blocks_per_group: usize) -> Self {
+        self.blocks_per_group = Some(blocks_per_group);
+        self
+    }
+}
+
+/// 测试用的文件系统
+#[cfg(test)]
+impl BlockCacheDevice {
+    /// 在内存设备上按 options 格式化,设备大小取 options 的块数,未设置时为 1024 块
+    pub(crate) fn test(options: &MkfsOptions) -> (Arc<RamDevice>, Self) {
+        let ram = Arc::new(RamDevice::new(options.blocks.unwrap_or(1024)));
+        let mut fs = Self::new(ram.clone()).unwrap();
+        fs.mkfs_with(options).unwrap();
+        (ram, fs)
+    }
+
+    /// 在根目录下创建普通文件并写入 data,返回 inode 号
+    pub(crate) fn create_file(&mut self, name: &str, data: &[u8]) -> Result<usize, ErrorCode> {
+        let root = self.inode(1)?.with_id(1);
+        let file = self.make_node_internal(name, &root, FileType::File << 12 | 0o644, 0, 0)?;
+        if !data.is_empty() {
+            let inode = self.inode(file)?.with_id(file);
+            self.write_system(0, &inode, data, true)?;
+        }
+        Ok(file)
+    }
+}
+
+#[test]
+fn fast_mkfs_over_garbage() {
+    let ram = Arc::new(RamDevice::from_snapshot(vec![0xa5u8; 1024 * BLOCK_SIZE]).unwrap());
+    let mut fs = BlockCacheDevice::new(ram).unwrap();
+    let sb = fs.mkfs_with(&MkfsOptions::new().bytes_per_inode(4 * BLOCK_SIZE).label("scratch").fast(true)).unwrap();
+    assert_eq!(sb.label(), "scratch");
+    assert_eq!(sb.inode_size(), 256);
+
+    let root = fs.inode(1).unwrap().with_id(1);
+    let dir = fs.make_node_internal("dir", &root, 0o040755, 0, 0).unwrap();
+    let parent = fs.inode(dir).unwrap().with_id(dir);
+    fs.make_node_internal("file", &parent, 0o100644, 0, 0).unwrap();
+    let parent = fs.inode(dir).unwrap();
+    let entries = fs.ls_internal(&parent).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(String::from(entries[0].name), "file");
+}
@@ -3,10 +3,19 @@
 
 use crate::layout::inode::Inode;
 
+pub mod allocator;
+pub mod backup;
 pub mod block_cache_manager;
+pub mod checksum;
+pub mod dump;
 pub(crate) mod error_code;
 pub mod file_system;
+pub mod fsck;
 pub mod interface;
+pub mod journal;
+pub mod mkfs;
+pub mod mount;
+pub mod stats;
 
 pub struct DirEntryDetail {
     pub name: String,
@@ -14,3 +23,13 @@ pub struct DirEntryDetail {
     pub offset: usize,
     pub inode: Inode,
 }
+
+/// 空间与 inode 使用情况,单位为块
+pub struct StatFs {
+    pub blocks: usize,
+    pub free_blocks: usize,
+    /// 扣除保留块后普通用户可用的块数
+    pub available_blocks: usize,
+    pub inodes: usize,
+    pub free_inodes: usize,
+}
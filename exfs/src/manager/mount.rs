@@ -0,0 +1,105 @@
+use std::time::SystemTime;
+
+use crate::layout::super_block::{FsState, SuperBlock};
+use crate::manager::block_cache_manager::BlockCacheDevice;
+use crate::manager::error_code::ErrorCode;
+use crate::utils::time::time_sys;
+
+/// 挂载状态与超级块中的时间记录
+impl BlockCacheDevice {
+    /// 修改超级块并更新校验和,与其他元数据一样在 sync 或事务提交时写回
+    pub fn modify_super_block<V>(&mut self, f: impl FnOnce(&mut SuperBlock) -> V) -> V {
+        self.super_block.lock().unwrap().modify(0, |sb: &mut SuperBlock| {
+            let v = f(sb);
+            sb.seal();
+            v
+        })
+    }
+
+    /// 只读挂载时 mount 与 unmount 不写入超级块
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// 挂载时调用,状态置为 Dirty、挂载次数加一并立即写入磁盘
+    /// 返回挂载前的状态,为 Dirty 时说明上次没有正常卸载
+    pub fn mount(&mut self) -> Result<FsState, ErrorCode> {
+        if self.read_only {
+            return Ok(self.super_block().state());
+        }
+        let now = time_sys(SystemTime::now());
+        let state = self.modify_super_block(|sb| {
+            let state = sb.state();
+            sb.state = FsState::Dirty as u8;
+            sb.mount_count += 1;
+            sb.mount_time = now;
+            sb.write_time = now;
+            state
+        });
+        self.sync()?;
+        Ok(state)
+    }
+
+    /// 正常卸载时调用,写回所有修改后状态置为 Clean
+    pub fn unmount(&mut self) -> Result<(), ErrorCode> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.sync()?;
+        self.set_state(FsState::Clean, false)
+    }
+
+    /// fsck 修复后调用,没有遗留问题时状态置为 Clean
+    pub(crate) fn checked(&mut self, clean: bool) -> Result<(), ErrorCode> {
+        let state = if clean { FsState::Clean } else { self.super_block().state() };
+        self.set_state(state, true)
+    }
+
+    fn set_state(&mut self, state: FsState, fsck: bool) -> Result<(), ErrorCode> {
+        let now = time_sys(SystemTime::now());
+        self.modify_super_block(|sb| {
+            sb.state = state as u8;
+            sb.write_time = now;
+            if fsck {
+                sb.fsck_time = now;
+            }
+        });
+        self.sync()
+    }
+}
+
+#[test]
+fn mount_state() {
+    use std::sync::Arc;
+
+    use crate::block_device::ram_device::RamDevice;
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (ram, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let uuid = fs.super_block().uuid;
+    assert_ne!(uuid, [0u8; 16]);
+    assert_eq!(fs.mount().unwrap(), FsState::Clean);
+    let st = fs.statfs_internal().unwrap();
+    // 根目录与 lost+found
+    assert_eq!(st.inodes - st.free_inodes, 2);
+    assert!(st.free_blocks < st.blocks);
+    fs.unmount().unwrap();
+    drop(fs);
+
+    // 挂载后未卸载即崩溃
+    let mut fs = BlockCacheDevice::open(ram.clone()).unwrap();
+    assert_eq!(fs.mount().unwrap(), FsState::Clean);
+    let image = ram.snapshot();
+    drop(fs);
+    let mut fs = BlockCacheDevice::open(Arc::new(RamDevice::from_snapshot(image).unwrap())).unwrap();
+    let sb = fs.super_block();
+    assert_eq!((sb.state(), sb.mount_count, sb.uuid), (FsState::Dirty, 2, uuid));
+    assert!(sb.mount_time > 0 && sb.fsck_time == 0);
+    assert!(!fs.fsck(false).unwrap().is_clean());
+    let report = fs.fsck(true).unwrap();
+    assert!(report.remaining.is_empty(), "{:?}", report.remaining);
+    let sb = fs.super_block();
+    assert_eq!(sb.state(), FsState::Clean);
+    assert!(sb.fsck_time > 0);
+    assert_eq!(fs.mount().unwrap(), FsState::Clean);
+}
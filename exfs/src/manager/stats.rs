@@ -0,0 +1,237 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+use crate::block_device::block_device::BlockDevice;
+use crate::cache::write_back::WriteBack;
+use crate::manager::block_cache_manager::BlockCacheDevice;
+
+/// 缓存与 I/O 计数,可在其他线程读取
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) cache_hits: AtomicU64,
+    pub(crate) cache_misses: AtomicU64,
+    pub(crate) readahead_blocks: AtomicU64,
+    pub(crate) evictions: AtomicU64,
+    pub(crate) write_backs: AtomicU64,
+    pub(crate) device_reads: AtomicU64,
+    pub(crate) device_writes: AtomicU64,
+    pub(crate) bytes_read: AtomicU64,
+    pub(crate) bytes_written: AtomicU64,
+    pub(crate) inode_allocs: AtomicU64,
+    pub(crate) inode_frees: AtomicU64,
+    pub(crate) data_allocs: AtomicU64,
+    pub(crate) data_frees: AtomicU64,
+    pub(crate) checksum_errors: AtomicU64,
+    // 当前缓存的块数,不随 reset 清零
+    pub(crate) cached_blocks: AtomicU64,
+}
+
+pub(crate) fn count(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// 统计信息快照
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Stats {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// 预读读入的块数
+    pub readahead_blocks: u64,
+    pub evictions: u64,
+    /// 写回设备的脏块数
+    pub write_backs: u64,
+    /// 设备请求数,批量读写算一次
+    pub device_reads: u64,
+    pub device_writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub inode_allocs: u64,
+    pub inode_frees: u64,
+    pub data_allocs: u64,
+    pub data_frees: u64,
+    /// 校验和不符的元数据
+    pub checksum_errors: u64,
+    /// 块设备校验失败的次数,见 ChecksumDevice
+    pub device_checksum_errors: u64,
+    pub cached_blocks: u64,
+    pub dirty_blocks: u64,
+}
+
+impl Stats {
+    /// 命中率,没有访问时为 0
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.cache_hits + self.cache_misses;
+        if total == 0 { 0.0 } else { self.cache_hits as f64 / total as f64 }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "cache:  {} hits, {} misses ({:.1}%), {} readahead, {} evictions",
+                 self.cache_hits, self.cache_misses, self.hit_ratio() * 100.0, self.readahead_blocks, self.evictions)?;
+        writeln!(f, "        {} cached, {} dirty, {} written back", self.cached_blocks, self.dirty_blocks, self.write_backs)?;
+        writeln!(f, "device: {} reads ({} bytes), {} writes ({} bytes)",
+                 self.device_reads, self.bytes_read, self.device_writes, self.bytes_written)?;
+        writeln!(f, "bitmap: inode {} alloc / {} free, data {} alloc / {} free",
+                 self.inode_allocs, self.inode_frees, self.data_allocs, self.data_frees)?;
+        write!(f, "errors: {} checksum, {} device checksum", self.checksum_errors, self.device_checksum_errors)
+    }
+}
+
+/// 挂载后 BlockCacheDevice 交给 fuser,通过该句柄在其他线程读取统计
+#[derive(Clone)]
+pub struct StatsHandle {
+    counters: Arc<Counters>,
+    write_back: Arc<WriteBack>,
+    device: Arc<dyn BlockDevice>,
+}
+
+impl StatsHandle {
+    pub fn get(&self) -> Stats {
+        let c = &self.counters;
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        Stats {
+            cache_hits: load(&c.cache_hits),
+            cache_misses: load(&c.cache_misses),
+            readahead_blocks: load(&c.readahead_blocks),
+            evictions: load(&c.evictions),
+            write_backs: load(&c.write_backs),
+            device_reads: load(&c.device_reads),
+            device_writes: load(&c.device_writes),
+            bytes_read: load(&c.bytes_read),
+            bytes_written: load(&c.bytes_written),
+            inode_allocs: load(&c.inode_allocs),
+            inode_frees: load(&c.inode_frees),
+            data_allocs: load(&c.data_allocs),
+            data_frees: load(&c.data_frees),
+            checksum_errors: load(&c.checksum_errors),
+            device_checksum_errors: self.device.checksum_errors(),
+            cached_blocks: load(&c.cached_blocks),
+            dirty_blocks: self.write_back.len() as u64,
+        }
+    }
+
+    /// 清零累计计数
+    pub fn reset(&self) {
+        let c = &self.counters;
+        for v in [
+            &c.cache_hits, &c.cache_misses, &c.readahead_blocks, &c.evictions, &c.write_backs,
+            &c.device_reads, &c.device_writes, &c.bytes_read, &c.bytes_written,
+            &c.inode_allocs, &c.inode_frees, &c.data_allocs, &c.data_frees, &c.checksum_errors,
+        ] {
+            v.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 统计设备请求数与字节数
+pub(crate) struct CountedDevice {
+    pub(crate) inner: Arc<dyn BlockDevice>,
+    pub(crate) counters: Arc<Counters>,
+}
+
+impl CountedDevice {
+    fn read_done(&self, len: usize) {
+        count(&self.counters.device_reads, 1);
+        count(&self.counters.bytes_read, len as u64);
+    }
+
+    fn write_done(&self, len: usize) {
+        count(&self.counters.device_writes, 1);
+        count(&self.counters.bytes_written, len as u64);
+    }
+}
+
+impl BlockDevice for CountedDevice {
+    fn id(&self) -> usize {
+        self.inner.id()
+    }
+
+    fn read(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read(block, buf)?;
+        self.read_done(buf.len());
+        Ok(())
+    }
+
+    fn write(&self, block: usize, data: &[u8]) -> io::Result<()> {
+        self.inner.write(block, data)?;
+        self.write_done(data.len());
+        Ok(())
+    }
+
+    fn read_blocks(&self, block: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_blocks(block, buf)?;
+        self.read_done(buf.len());
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: usize, data: &[u8]) -> io::Result<()> {
+        self.inner.write_blocks(block, data)?;
+        self.write_done(data.len());
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn blocks(&self) -> io::Result<usize> {
+        self.inner.blocks()
+    }
+
+    fn checksum_errors(&self) -> u64 {
+        self.inner.checksum_errors()
+    }
+}
+
+/// 统计接口
+impl BlockCacheDevice {
+    pub fn stats(&self) -> Stats {
+        self.stats_handle().get()
+    }
+
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            counters: self.counters.clone(),
+            write_back: self.write_back.clone(),
+            device: self.device.clone(),
+        }
+    }
+}
+
+#[test]
+fn count_cache_and_io() {
+    use crate::config::BLOCK_SIZE;
+    use crate::manager::mkfs::MkfsOptions;
+
+    let (_, mut fs) = BlockCacheDevice::test(&MkfsOptions::new());
+    let handle = fs.stats_handle();
+    handle.reset();
+    let file = fs.create_file("file", &[1u8; 3 * BLOCK_SIZE]).unwrap();
+    fs.sync().unwrap();
+
+    let stats = handle.get();
+    assert_eq!(stats.inode_allocs, 1);
+    assert_eq!(stats.data_allocs, 3);
+    assert!(stats.cache_hits > 0 && stats.cache_misses > 0);
+    assert!(stats.write_backs >= 4);
+    assert!(stats.device_writes > 0 && stats.bytes_written >= stats.write_backs * BLOCK_SIZE as u64);
+    assert_eq!(stats.dirty_blocks, 0);
+    assert_eq!(stats.cached_blocks as usize, fs.caches.len());
+
+    fs.free_block(file, true, true).unwrap();
+    let before = fs.stats();
+    fs.inode(file).unwrap();
+    let after = fs.stats();
+    assert_eq!(after.inode_frees, 1);
+    assert_eq!(after.cache_hits, before.cache_hits + 1);
+    assert_eq!(after.device_reads, before.device_reads);
+}
@@ -3,9 +3,19 @@ use std::ffi::{OsStr, OsString};
 use std::ops::Deref;
 use std::os::unix::ffi::OsStringExt;
 
-#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+/// 文件名最大字节数
+pub const FILE_NAME_LEN: usize = 56;
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
 pub struct FileName([u8; 56]);
 
+impl FileName {
+    /// 全为 0 的文件名,目录块末尾的校验和项使用空文件名
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|v| *v == 0)
+    }
+}
+
 impl Deref for FileName {
     type Target = [u8; 56];
 
@@ -42,6 +52,12 @@ impl Into<FileName> for &OsStr {
     }
 }
 
+impl From<&str> for FileName {
+    fn from(value: &str) -> Self {
+        OsStr::new(value).into()
+    }
+}
+
 
 fn trim_zero(data: Vec<u8>) -> Vec<u8> {
     let mut trimmed_data = data.clone();
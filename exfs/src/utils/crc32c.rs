@@ -0,0 +1,34 @@
+/// CRC32C (Castagnoli),用于元数据校验
+const POLY: u32 = 0x82f6_3b78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// 以 crc 为初值继续计算,可分段计算同一份数据
+pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, b| TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_append(0, data)
+}
+
+#[test]
+fn check_value() {
+    assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    assert_eq!(crc32c_append(crc32c(b"1234"), b"56789"), 0xe306_9283);
+    assert_eq!(crc32c(&[]), 0);
+}
@@ -1,2 +1,4 @@
+pub(crate) mod crc32c;
 pub mod slice;
 pub(crate) mod time;
+pub(crate) mod uuid;
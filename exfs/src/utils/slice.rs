@@ -12,6 +12,12 @@ pub fn slice<T: Sized>(data: &T) -> &'static [u8] {
     unsafe { std::slice::from_raw_parts(data as *const T as *const u8, size_of::<T>()) }
 }
 
+/// 从字节中按原样读出 T,data 不能短于 T
+pub fn from_slice<T: Copy>(data: &[u8]) -> T {
+    assert!(data.len() >= size_of::<T>());
+    unsafe { std::ptr::read_unaligned(data.as_ptr() as *const T) }
+}
+
 
 pub trait SliceExt {
     fn trim(&self) -> &Self;
@@ -0,0 +1,16 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::SystemTime;
+
+/// 随机生成 v4 UUID,随机数取自标准库 HashMap 的随机种子
+pub fn random_uuid() -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    for half in uuid.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos());
+        half.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    uuid[6] = uuid[6] & 0x0f | 0x40;
+    uuid[8] = uuid[8] & 0x3f | 0x80;
+    uuid
+}